@@ -0,0 +1,92 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use log::{error, info};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc::{Receiver, Sender},
+};
+
+use super::{flush_worker, try_send_all, writer_channel, WorkerMessage, Writer};
+use crate::events::{AllEvents, RawEvent};
+
+/// Records every event along with when the scraper received it into a jsonl capture file, which
+/// can be fed back through the writers with `tl2 replay`. Like any writer it sees events after
+/// processing and routing, so dropped events are missing and redacted text stays redacted.
+pub struct CaptureWriter {
+    tx: Sender<WorkerMessage<RawEvent>>,
+}
+
+impl CaptureWriter {
+    pub async fn new(path: PathBuf) -> Result<CaptureWriter> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("Opening capture file {:?} failed", path))?;
+        info!("Recording events to {:?}", path);
+
+        let (tx, rx) = writer_channel();
+        let worker = CaptureWorker {
+            rx,
+            file: BufWriter::new(file),
+        };
+        tokio::spawn(worker.run());
+        Ok(CaptureWriter { tx })
+    }
+}
+
+#[async_trait]
+impl Writer for CaptureWriter {
+    fn write(&self, event: AllEvents) -> Result<()> {
+        try_send_all(&self.tx, vec![event.to_raw()])
+    }
+
+    async fn flush(&self) -> Result<()> {
+        flush_worker(&self.tx).await
+    }
+}
+
+struct CaptureWorker {
+    rx: Receiver<WorkerMessage<RawEvent>>,
+    file: BufWriter<File>,
+}
+
+impl CaptureWorker {
+    async fn run(mut self) {
+        while let Some(message) = self.rx.recv().await {
+            // Flush once we've caught up, so a crash loses as little of the capture as possible
+            // without flushing every single line during bursts.
+            let mut messages = vec![message];
+            while let Ok(message) = self.rx.try_recv() {
+                messages.push(message);
+            }
+            if let Err(error) = self.process(messages).await {
+                error!("[CaptureWriter] Error writing capture: {:?}", error);
+            }
+        }
+    }
+
+    async fn process(&mut self, messages: Vec<WorkerMessage<RawEvent>>) -> Result<()> {
+        for message in messages {
+            match message {
+                WorkerMessage::Item(record) => {
+                    let line = serde_json::to_string(&record)? + "\n";
+                    self.file.write_all(line.as_bytes()).await?;
+                }
+                WorkerMessage::Flush(done) => {
+                    self.file.flush().await?;
+                    let _ = done.send(());
+                }
+            }
+        }
+        self.file.flush().await?;
+        Ok(())
+    }
+}
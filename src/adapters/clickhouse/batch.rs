@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use clickhouse::{inserter::Inserter, Client, Row};
+use serde::Serialize;
+
+use crate::settings::ClickhouseSettings;
+
+/// The rows of one table that haven't been sent yet. An INSERT is sent once it holds
+/// `batch_size` rows or is `batch_period_seconds` old, whichever comes first.
+pub struct TableBatch<T: Row> {
+    table: &'static str,
+    inserter: Inserter<T>,
+    batch_size: u64,
+    /// Rows written since the last commit check
+    rows: u64,
+    /// Sequence number of the first row that hasn't been sent, everything before it has
+    pending: Option<u64>,
+}
+
+impl<T: Row + Serialize> TableBatch<T> {
+    pub fn new(client: &Client, table: &'static str, config: &ClickhouseSettings) -> Result<Self> {
+        Ok(TableBatch {
+            table,
+            inserter: create_inserter(client, table, config)?,
+            batch_size: config.batch_size.max(1),
+            rows: 0,
+            pending: None,
+        })
+    }
+
+    pub fn pending(&self) -> Option<u64> {
+        self.pending
+    }
+
+    pub async fn write(&mut self, row: &T, seq: u64) -> Result<()> {
+        self.inserter.write(row).await?;
+        self.pending.get_or_insert(seq);
+        self.rows += 1;
+        if self.rows >= self.batch_size {
+            self.commit().await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the INSERT if it's full or old enough
+    pub async fn commit(&mut self) -> Result<()> {
+        self.rows = 0;
+        if self.inserter.commit().await?.entries > 0 {
+            self.pending = None;
+        }
+        Ok(())
+    }
+
+    /// Sends whatever is buffered right away
+    pub async fn end(&mut self, client: &Client, config: &ClickhouseSettings) -> Result<()> {
+        let ended = std::mem::replace(
+            &mut self.inserter,
+            create_inserter(client, self.table, config)?,
+        );
+        ended.end().await?;
+        self.rows = 0;
+        self.pending = None;
+        Ok(())
+    }
+}
+
+fn create_inserter<T: Row>(
+    client: &Client,
+    table: &str,
+    config: &ClickhouseSettings,
+) -> Result<Inserter<T>> {
+    Ok(client
+        .inserter::<T>(table)?
+        .with_max_entries(config.batch_size)
+        .with_period(Some(Duration::from_secs(config.batch_period_seconds))))
+}
@@ -7,6 +7,8 @@ use serde::Deserialize;
 use serde::Serialize;
 use twitch_irc::message::PrivmsgMessage;
 
+use crate::events::{Event, EventKind};
+
 #[derive(Clone, Debug, Serialize, Deserialize, Row)]
 pub struct ClickhouseMessage {
     pub ts: i64,
@@ -78,6 +80,57 @@ pub async fn create_messages(client: &Client) -> Result<()> {
     Ok(())
 }
 
+/// A dgg chat message, dgg has no user or channel ids and flairs instead of badges
+#[derive(Clone, Debug, Serialize, Deserialize, Row)]
+pub struct ClickhouseDggMessage {
+    pub ts: i64,
+    pub channel: String,
+    pub username: String,
+    pub text: String,
+    pub flairs: Vec<String>,
+    pub subscribed: u8,
+}
+
+impl ClickhouseDggMessage {
+    pub fn from_event(event: &Event) -> Option<Self> {
+        match &event.kind {
+            EventKind::Message(msg) => Some(ClickhouseDggMessage {
+                ts: event.timestamp.timestamp_millis(),
+                channel: event.channel.clone(),
+                username: msg.user.login.clone(),
+                text: msg.text.clone(),
+                flairs: msg.badges.clone(),
+                subscribed: msg.is_subscriber as u8,
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub async fn create_dgg_messages(client: &Client) -> Result<()> {
+    client
+        .query(
+            "
+          CREATE TABLE IF NOT EXISTS dgg_messages (
+              ts DateTime64(3) CODEC(T64, ZSTD(12)),
+              channel LowCardinality(String),
+              username String CODEC(ZSTD(12)),
+              text String CODEC(ZSTD(14)),
+              flairs Array(LowCardinality(String)),
+              subscribed UInt8
+          )
+          ENGINE = ReplacingMergeTree
+          PARTITION BY toYYYYMM(ts)
+          ORDER BY (channel, username, ts, text);",
+        )
+        .execute()
+        .await?;
+
+    debug!("Created clickhouse dgg_messages table");
+
+    Ok(())
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, Row)]
 pub struct ClickhouseOrlMessage {
     pub ts: i64,
@@ -1,30 +1,49 @@
 use std::{convert::TryInto, sync::Arc, time::Duration};
 
 use anyhow::{Context, Result};
-use clickhouse::{inserter::Inserter, Client};
-use log::{error, info};
-use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use async_trait::async_trait;
+use clickhouse::Client;
+use log::{error, info, warn};
+use tokio::sync::mpsc::Sender;
 
-use self::{messages_table::ClickhouseMessage, user_notices_table::ClickhouseUserNotice};
-use super::Writer;
+use self::{
+    batch::TableBatch,
+    messages_table::{ClickhouseDggMessage, ClickhouseMessage},
+    moderation_table::ClickhouseModeration,
+    user_notices_table::ClickhouseUserNotice,
+};
+use super::{flush_worker, try_send_all, WorkerMessage, Writer};
 use crate::{
-    alerts::DiscordAlerting, events::AllEvents, scrapers::twitch::events::TwitchEvent,
+    alerts::DiscordAlerting,
+    events::{AllEvents, EventCategory, EventGroup},
+    scrapers::twitch::events::{TwitchEvent, TwitchMessage},
     settings::ClickhouseSettings,
+    wal::WorkerInput,
 };
 
+pub mod batch;
 pub mod messages_table;
+pub mod moderation_table;
+pub mod presence_table;
 pub mod user_notices_table;
 
+/// How often batches are checked against the batch period
+const COMMIT_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
 pub struct ClickhouseWriter {
-    tx: UnboundedSender<AllEvents>,
+    tx: Sender<WorkerMessage<AllEvents>>,
 }
 
 impl ClickhouseWriter {
-    pub fn new(config: ClickhouseSettings, alerting: Arc<DiscordAlerting>) -> ClickhouseWriter {
-        let (tx, rx) = mpsc::unbounded_channel();
+    pub fn new(
+        config: ClickhouseSettings,
+        tx: Sender<WorkerMessage<AllEvents>>,
+        input: WorkerInput,
+        alerting: Arc<DiscordAlerting>,
+    ) -> ClickhouseWriter {
         let alerting = alerting.clone();
         let mut worker = ClickhouseWorker {
-            rx,
+            input,
             alerting,
             config: config.clone(),
         };
@@ -32,96 +51,187 @@ impl ClickhouseWriter {
         Self { tx }
     }
 }
+#[async_trait]
 impl Writer for ClickhouseWriter {
     fn write(&self, msg: AllEvents) -> Result<()> {
-        self.tx
-            .send(msg)
-            .with_context(|| "Sending message to Clickhouse worker failed, rx probably dropped")?;
-        Ok(())
+        try_send_all(&self.tx, vec![msg])
+            .with_context(|| "Sending message to Clickhouse worker failed")
+    }
+
+    async fn flush(&self) -> Result<()> {
+        flush_worker(&self.tx)
+            .await
+            .with_context(|| "Flushing Clickhouse worker failed")
     }
 }
 
 pub struct ClickhouseWorker {
     pub config: ClickhouseSettings,
-    pub rx: UnboundedReceiver<AllEvents>,
+    pub input: WorkerInput,
     pub alerting: Arc<DiscordAlerting>,
 }
 
 impl ClickhouseWorker {
     pub async fn work(&mut self) {
         info!("Pogchamp");
-        let client = self.create_client();
+        let client = create_client(&self.config);
 
         loop {
-            if let Err(e) = self.run_writer(&client).await {
-                error!("Clickhouse worker failed: {:?}", e);
+            match self.run_writer(&client).await {
+                // The writer is gone and everything it sent was inserted
+                Ok(()) => return,
+                Err(e) => error!("Clickhouse worker failed: {:?}", e),
             }
+            // Anything that wasn't committed is inserted again, if there's a write-ahead log
+            self.input.rewind();
             tokio::time::sleep(Duration::from_secs(5)).await;
         }
     }
     async fn run_writer(&mut self, client: &Client) -> Result<()> {
         ClickhouseWorker::init_tables(client).await?;
         info!("Starting Clickhouse ingestion loop");
-        let mut message_inserter = client
-            .inserter::<ClickhouseMessage>("messages")?
-            .with_max_entries(100)
-            .with_period(Some(Duration::from_secs(5)));
-        let mut user_notice_inserter = client
-            .inserter::<ClickhouseUserNotice>("usernotices")?
-            .with_max_entries(100)
-            .with_period(Some(Duration::from_secs(5)));
-        while let Some(event) = self.rx.recv().await {
-            ClickhouseWorker::write_message(&mut message_inserter, event.clone())
-                .await
-                .with_context(|| "Write message failed")?;
-            ClickhouseWorker::write_user_notice(&mut user_notice_inserter, event.clone())
-                .await
-                .with_context(|| "Write user notice failed")?;
-        }
-        Ok(())
-    }
-
-    fn create_client(&self) -> Client {
-        let mut client = Client::default().with_url(&self.config.url);
-        if let Some(db_user) = &self.config.db_user {
-            client = client.with_user(db_user);
-        }
-        if let Some(db_pass) = &self.config.db_pass {
-            client = client.with_password(db_pass);
-        }
-        if let Some(db_name) = &self.config.db_name {
-            client = client.with_database(db_name);
+        let mut tables = Tables::new(client, &self.config)?;
+        let mut last_seq = 0;
+        // Sends batches that are old enough while no events are coming in
+        let mut commit_check = tokio::time::interval(COMMIT_CHECK_PERIOD);
+        loop {
+            let message = tokio::select! {
+                message = self.input.next() => message?,
+                _ = commit_check.tick() => {
+                    tables.commit().await?;
+                    self.input.commit(tables.committed(last_seq)).await?;
+                    continue;
+                }
+            };
+            match message {
+                Some(WorkerMessage::Item((seq, event))) => {
+                    last_seq = seq;
+                    tables.write(event, seq).await?;
+                    self.input.commit(tables.committed(seq)).await?;
+                }
+                Some(WorkerMessage::Flush(done)) => {
+                    tables.end(client, &self.config).await?;
+                    self.input.commit(last_seq).await?;
+                    self.input.checkpoint().await?;
+                    let _ = done.send(());
+                }
+                None => break,
+            }
         }
-        return client;
+        // The writer is gone, insert what's left
+        tables.end(client, &self.config).await?;
+        self.input.commit(last_seq).await?;
+        self.input.checkpoint().await
     }
 
     async fn init_tables(client: &Client) -> Result<()> {
         messages_table::create_messages(client).await?;
+        messages_table::create_dgg_messages(client).await?;
         user_notices_table::create_user_notices(client).await?;
+        moderation_table::create_moderation(client).await?;
         Ok(())
     }
+}
 
-    async fn write_message(
-        inserter: &mut Inserter<ClickhouseMessage>,
-        event: AllEvents,
-    ) -> Result<()> {
-        if let AllEvents::Twitch(TwitchEvent::Privmsg(msg)) = event {
-            let ch_message: ClickhouseMessage = msg.try_into()?;
-            inserter.write(&ch_message).await?;
-            inserter.commit().await?;
-        }
-        Ok(())
+pub fn create_client(config: &ClickhouseSettings) -> Client {
+    let mut client = Client::default().with_url(&config.url);
+    if let Some(db_user) = &config.db_user {
+        client = client.with_user(db_user);
+    }
+    if let Some(db_pass) = &config.db_pass {
+        client = client.with_password(db_pass);
+    }
+    if let Some(db_name) = &config.db_name {
+        client = client.with_database(db_name);
+    }
+    client
+}
+
+/// A batch per table, every event is written to the tables it has rows for
+struct Tables {
+    messages: TableBatch<ClickhouseMessage>,
+    user_notices: TableBatch<ClickhouseUserNotice>,
+    dgg_messages: TableBatch<ClickhouseDggMessage>,
+    moderation: TableBatch<ClickhouseModeration>,
+}
+
+impl Tables {
+    fn new(client: &Client, config: &ClickhouseSettings) -> Result<Self> {
+        Ok(Tables {
+            messages: TableBatch::new(client, "messages", config)?,
+            user_notices: TableBatch::new(client, "usernotices", config)?,
+            dgg_messages: TableBatch::new(client, "dgg_messages", config)?,
+            moderation: TableBatch::new(client, "moderation", config)?,
+        })
     }
 
-    async fn write_user_notice(
-        inserter: &mut Inserter<ClickhouseUserNotice>,
-        event: AllEvents,
-    ) -> Result<()> {
-        if let AllEvents::Twitch(TwitchEvent::UserNotice(msg)) = event {
-            let ch_user_notice: ClickhouseUserNotice = msg.try_into()?;
-            inserter.write(&ch_user_notice).await?;
-            inserter.commit().await?;
+    /// Events that can't be turned into rows are logged and skipped, a single odd message
+    /// shouldn't stop the writer.
+    async fn write(&mut self, event: AllEvents, seq: u64) -> Result<()> {
+        let is_moderation = event.category() == EventCategory::Moderation;
+        match event {
+            AllEvents::Twitch(TwitchEvent {
+                message: TwitchMessage::Privmsg(msg),
+                ..
+            }) => match msg.try_into() {
+                Ok(row) => self.messages.write(&row, seq).await?,
+                Err(e) => warn!("Skipping message Clickhouse can't store: {:?}", e),
+            },
+            AllEvents::Twitch(TwitchEvent {
+                message: TwitchMessage::UserNotice(msg),
+                ..
+            }) => match msg.try_into() {
+                Ok(row) => self.user_notices.write(&row, seq).await?,
+                Err(e) => warn!("Skipping user notice Clickhouse can't store: {:?}", e),
+            },
+            event @ AllEvents::Dgg(_) => {
+                for event in EventGroup::from(event).0 {
+                    if let Some(row) = ClickhouseDggMessage::from_event(&event) {
+                        self.dgg_messages.write(&row, seq).await?;
+                    }
+                    if let Some(row) = ClickhouseModeration::from_event(&event) {
+                        self.moderation.write(&row, seq).await?;
+                    }
+                }
+            }
+            event if is_moderation => {
+                for event in EventGroup::from(event).0 {
+                    if let Some(row) = ClickhouseModeration::from_event(&event) {
+                        self.moderation.write(&row, seq).await?;
+                    }
+                }
+            }
+            _ => {}
         }
         Ok(())
     }
+
+    async fn commit(&mut self) -> Result<()> {
+        self.messages.commit().await?;
+        self.user_notices.commit().await?;
+        self.dgg_messages.commit().await?;
+        self.moderation.commit().await
+    }
+
+    async fn end(&mut self, client: &Client, config: &ClickhouseSettings) -> Result<()> {
+        self.messages.end(client, config).await?;
+        self.user_notices.end(client, config).await?;
+        self.dgg_messages.end(client, config).await?;
+        self.moderation.end(client, config).await
+    }
+
+    /// Everything before the oldest unsent row is in Clickhouse, or everything up to `seq` if
+    /// nothing is waiting.
+    fn committed(&self, seq: u64) -> u64 {
+        [
+            self.messages.pending(),
+            self.user_notices.pending(),
+            self.dgg_messages.pending(),
+            self.moderation.pending(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .map_or(seq, |first_pending| first_pending - 1)
+    }
 }
@@ -0,0 +1,112 @@
+use anyhow::Result;
+use clickhouse::{Client, Row};
+use log::debug;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::{events::Event, formats::moderation::ModerationLog};
+
+/// Bans, timeouts and their reversals from every platform. Fields a platform doesn't have are
+/// left empty, e.g. the moderator on twitch or ids and durations on dgg.
+#[derive(Clone, Debug, Serialize, Deserialize, Row)]
+pub struct ClickhouseModeration {
+    pub ts: i64,
+    pub platform: String,
+    pub channel: String,
+    pub channel_id: String,
+    pub action: String,
+    pub target_username: String,
+    pub target_user_id: String,
+    pub moderator: String,
+    pub duration_seconds: u64,
+}
+
+impl ClickhouseModeration {
+    pub fn from_event(event: &Event) -> Option<Self> {
+        ModerationLog::from_event(event).map(ClickhouseModeration::from)
+    }
+}
+
+impl From<ModerationLog> for ClickhouseModeration {
+    fn from(log: ModerationLog) -> Self {
+        ClickhouseModeration {
+            ts: log.timestamp.timestamp_millis(),
+            platform: log.platform.as_ref().to_string(),
+            channel: log.channel,
+            channel_id: log.channel_id.unwrap_or_default(),
+            action: log.action.as_ref().to_string(),
+            target_username: log.target.unwrap_or_default(),
+            target_user_id: log.target_id.unwrap_or_default(),
+            moderator: log.moderator.unwrap_or_default(),
+            duration_seconds: log.duration_seconds.unwrap_or(0),
+        }
+    }
+}
+
+pub async fn create_moderation(client: &Client) -> Result<()> {
+    client
+        .query(
+            "
+          CREATE TABLE IF NOT EXISTS moderation (
+              ts DateTime64(3) CODEC(T64, ZSTD(12)),
+              platform LowCardinality(String),
+              channel LowCardinality(String),
+              channel_id String CODEC(ZSTD(12)),
+              action LowCardinality(String),
+              target_username String CODEC(ZSTD(12)),
+              target_user_id String CODEC(ZSTD(12)),
+              moderator String CODEC(ZSTD(12)),
+              duration_seconds UInt64 CODEC(T64, ZSTD(12))
+          )
+          ENGINE = ReplacingMergeTree
+          PARTITION BY toYYYYMM(ts)
+          ORDER BY (platform, channel, target_username, ts, action);",
+        )
+        .execute()
+        .await?;
+
+    debug!("Created clickhouse moderation table");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        adapters::clickhouse::messages_table::ClickhouseDggMessage,
+        events::{AllEvents, EventGroup},
+        scrapers::dgg::parse_test_frame,
+    };
+
+    fn dgg_events(raw: &str) -> Vec<Event> {
+        let event = parse_test_frame(raw);
+        EventGroup::from(AllEvents::from(event)).0
+    }
+
+    #[test]
+    fn test_dgg_rows() {
+        let events =
+            dgg_events(r#"MUTE {"data":"spammer","nick":"Bot","timestamp":1628035200095}"#);
+        let row = ClickhouseModeration::from_event(&events[0]).unwrap();
+        assert_eq!(
+            (row.platform.as_str(), row.action.as_str()),
+            ("dgg", "timeout")
+        );
+        assert_eq!(
+            (row.target_username.as_str(), row.moderator.as_str()),
+            ("spammer", "Bot")
+        );
+        assert!(ClickhouseDggMessage::from_event(&events[0]).is_none());
+
+        let events = dgg_events(
+            r#"MSG {"nick":"bob","features":["flair3"],"timestamp":1628035200095,"data":"hi"}"#,
+        );
+        let row = ClickhouseDggMessage::from_event(&events[0]).unwrap();
+        assert_eq!((row.username.as_str(), row.text.as_str()), ("bob", "hi"));
+        assert_eq!(row.flairs, vec!["flair3"]);
+        assert_eq!(row.subscribed, 1);
+        assert_eq!(row.ts, 1628035200095);
+        assert!(ClickhouseModeration::from_event(&events[0]).is_none());
+    }
+}
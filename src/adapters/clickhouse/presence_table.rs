@@ -0,0 +1,72 @@
+use anyhow::Result;
+use clickhouse::{Client, Row};
+use log::debug;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::userlist_scraper::PresenceSnapshot;
+
+/// Chatter, message and viewer counts of every chat over time. Chats without a userlist have 0
+/// chatters, `has_userlist` tells them apart from empty chats, and `has_viewers` does the same
+/// for chats whose viewers weren't polled.
+#[derive(Clone, Debug, Serialize, Deserialize, Row)]
+pub struct ClickhousePresence {
+    pub ts: i64,
+    pub platform: String,
+    pub channel: String,
+    pub has_userlist: u8,
+    pub chatters: u64,
+    pub connection_count: u64,
+    pub joins: u64,
+    pub leaves: u64,
+    pub messages: u64,
+    pub has_viewers: u8,
+    pub viewers: u64,
+}
+
+impl From<&PresenceSnapshot> for ClickhousePresence {
+    fn from(snapshot: &PresenceSnapshot) -> Self {
+        ClickhousePresence {
+            ts: snapshot.timestamp.timestamp_millis(),
+            platform: snapshot.platform.as_ref().to_string(),
+            channel: snapshot.channel.clone(),
+            has_userlist: snapshot.chatters.is_some() as u8,
+            chatters: snapshot.chatters.unwrap_or(0),
+            connection_count: snapshot.connection_count.unwrap_or(0),
+            joins: snapshot.joins,
+            leaves: snapshot.leaves,
+            messages: snapshot.messages,
+            has_viewers: snapshot.viewers.is_some() as u8,
+            viewers: snapshot.viewers.unwrap_or(0),
+        }
+    }
+}
+
+pub async fn create_presence(client: &Client) -> Result<()> {
+    client
+        .query(
+            "
+          CREATE TABLE IF NOT EXISTS presence (
+              ts DateTime64(3) CODEC(T64, ZSTD(12)),
+              platform LowCardinality(String),
+              channel LowCardinality(String),
+              has_userlist UInt8,
+              chatters UInt64 CODEC(T64, ZSTD(12)),
+              connection_count UInt64 CODEC(T64, ZSTD(12)),
+              joins UInt64 CODEC(T64, ZSTD(12)),
+              leaves UInt64 CODEC(T64, ZSTD(12)),
+              messages UInt64 CODEC(T64, ZSTD(12)),
+              has_viewers UInt8,
+              viewers UInt64 CODEC(T64, ZSTD(12))
+          )
+          ENGINE = ReplacingMergeTree
+          PARTITION BY toYYYYMM(ts)
+          ORDER BY (platform, channel, ts);",
+        )
+        .execute()
+        .await?;
+
+    debug!("Created clickhouse presence table");
+
+    Ok(())
+}
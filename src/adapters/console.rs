@@ -1,4 +1,7 @@
+use std::io::Write as _;
+
 use anyhow::Result;
+use async_trait::async_trait;
 use colored::{ColoredString, Colorize};
 
 use super::Writer;
@@ -21,6 +24,7 @@ impl ConsoleWriter {
     }
 }
 
+#[async_trait]
 impl Writer for ConsoleWriter {
     fn write(&self, event: AllEvents) -> Result<()> {
         let smg = SimpleMessageGroup::from(event);
@@ -28,4 +32,9 @@ impl Writer for ConsoleWriter {
         smg.0.iter().for_each(ConsoleWriter::print_message);
         Ok(())
     }
+
+    async fn flush(&self) -> Result<()> {
+        std::io::stdout().flush()?;
+        Ok(())
+    }
 }
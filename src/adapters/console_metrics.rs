@@ -1,43 +1,45 @@
 use std::time::{Duration, Instant};
 
 use anyhow::Result;
+use async_trait::async_trait;
 use log::info;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::{Receiver, Sender};
 
-use super::Writer;
+use super::{flush_worker, try_send_all, writer_channel, WorkerMessage, Writer};
 use crate::events::{AllEvents, SimpleMessage, SimpleMessageGroup};
 
 pub struct ConsoleMetricsWriter {
-    tx: UnboundedSender<SimpleMessage>,
+    tx: Sender<WorkerMessage<SimpleMessage>>,
 }
 
 impl ConsoleMetricsWriter {
     pub fn new() -> Self {
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (tx, rx) = writer_channel();
         let mut worker = ConsoleMetricsWorker::new(rx);
         tokio::spawn(async move { worker.run().await });
         Self { tx }
     }
 }
 
+#[async_trait]
 impl Writer for ConsoleMetricsWriter {
     fn write(&self, event: AllEvents) -> Result<()> {
         let msgs = SimpleMessageGroup::from(event);
+        try_send_all(&self.tx, msgs.0)
+    }
 
-        for msg in msgs.0 {
-            self.tx.send(msg)?;
-        }
-        Ok(())
+    async fn flush(&self) -> Result<()> {
+        flush_worker(&self.tx).await
     }
 }
 
 struct ConsoleMetricsWorker {
-    rx: UnboundedReceiver<SimpleMessage>,
+    rx: Receiver<WorkerMessage<SimpleMessage>>,
     count: u64,
     last_time: Instant,
 }
 impl ConsoleMetricsWorker {
-    pub fn new(rx: UnboundedReceiver<SimpleMessage>) -> Self {
+    pub fn new(rx: Receiver<WorkerMessage<SimpleMessage>>) -> Self {
         Self {
             rx,
             count: 0,
@@ -46,7 +48,11 @@ impl ConsoleMetricsWorker {
     }
 
     pub async fn run(&mut self) {
-        while self.rx.recv().await.is_some() {
+        while let Some(message) = self.rx.recv().await {
+            if let WorkerMessage::Flush(done) = message {
+                let _ = done.send(());
+                continue;
+            }
             self.count += 1;
             let seconds = 30;
             if Instant::now().duration_since(self.last_time) > Duration::from_secs(seconds) {
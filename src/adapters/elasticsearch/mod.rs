@@ -0,0 +1,660 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use chrono::{TimeZone, Utc};
+use elasticsearch::{
+    auth::Credentials,
+    cert::{Certificate, CertificateValidation},
+    http::{
+        request::JsonBody,
+        transport::{SingleNodeConnectionPool, Transport, TransportBuilder},
+        Url,
+    },
+    BulkParts, Elasticsearch,
+};
+use log::{debug, error, info};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::mpsc::Sender};
+use tokio_compat_02::FutureExt;
+
+use self::setup::{initialize, IndexLayout};
+use super::{flush_worker, try_send_all, WorkerMessage, Writer};
+use crate::{
+    alerts::DiscordAlerting,
+    events::{AllEvents, Event, EventGroup, EventKind},
+    formats::unified::OrlLog1_0,
+    settings::ElasticsearchSettings,
+    wal::WorkerInput,
+};
+
+pub mod setup;
+
+pub struct ElasticsearchWriter {
+    tx: Sender<WorkerMessage<AllEvents>>,
+    pub config: Arc<ElasticsearchSettings>,
+}
+
+impl ElasticsearchWriter {
+    pub fn new(
+        config: ElasticsearchSettings,
+        tx: Sender<WorkerMessage<AllEvents>>,
+        input: WorkerInput,
+        alerting: Arc<DiscordAlerting>,
+    ) -> Result<ElasticsearchWriter> {
+        let config = Arc::new(config);
+        let mut worker = ElasticsearchWorker {
+            client: create_elasticsearch_client(&config)?,
+            input,
+            layout: IndexLayout {
+                name: config.index.clone(),
+                mode: config.index_mode,
+            },
+            config: config.clone(),
+            batch: Vec::new(),
+            batch_seq: 0,
+            oldest: None,
+            batch_size: config.batch_size as usize,
+            batch_period: Duration::from_secs(config.batch_period_seconds),
+            dead_letter_path: PathBuf::from(&config.dead_letter_path),
+            retries: 0,
+            max_retry_seconds: config.max_retry_seconds,
+        };
+
+        tokio::spawn(async move { worker.work(&alerting).compat().await });
+        Ok(ElasticsearchWriter { config, tx })
+    }
+}
+#[async_trait]
+impl Writer for ElasticsearchWriter {
+    fn write(&self, msg: AllEvents) -> Result<()> {
+        try_send_all(&self.tx, vec![msg])
+            .with_context(|| "Sending message to Elasticsearch worker failed")
+    }
+
+    async fn flush(&self) -> Result<()> {
+        flush_worker(&self.tx)
+            .await
+            .with_context(|| "Flushing Elasticsearch worker failed")
+    }
+}
+
+const BASE_RETRY_SECONDS: u64 = 5;
+
+/// How often the batch is checked against the batch period while no events are coming in
+const FLUSH_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+/// A document waiting to be indexed, kept until elasticsearch accepted it
+#[derive(Clone, Debug)]
+pub struct BulkDocument {
+    pub op_type: &'static str,
+    pub index: String,
+    pub id: String,
+    pub source: Value,
+}
+
+impl BulkDocument {
+    /// The document for a log in the layout's index, with its `CommonKey` id as `_id`
+    pub fn from_log(layout: &IndexLayout, log: OrlLog1_0) -> Self {
+        let date_time = Utc.timestamp_millis_opt(log.key.timestamp).unwrap();
+        let timestamp_string = date_time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+
+        BulkDocument {
+            op_type: layout.op_type(),
+            index: layout.target(date_time),
+            id: log.key.id,
+            source: json!({
+                "channel": log.channel_name,
+                "username": log.username,
+                "text": log.text,
+                "ts": timestamp_string,
+                "@timestamp": timestamp_string,
+                // System events are logged as pseudo-users like `@subscriber`
+                "system": log.username.starts_with('@'),
+            }),
+        }
+    }
+
+    /// The live document for an event, the archive fields plus whatever the platform told us
+    /// about it. Events without an ORL representation aren't indexed.
+    pub fn from_event(layout: &IndexLayout, event: &Event) -> Option<Self> {
+        let msg = event.to_simple_message()?;
+        // Same id as the archive importers. Only monthly indices are the same too, so re-importing
+        // overwrites live documents there. An alias or data stream writes to whichever index is
+        // current, the same message can end up in two of them.
+        let mut doc = BulkDocument::from_log(layout, OrlLog1_0::from(msg.to_orl_log()));
+        if let (Value::Object(source), Ok(Value::Object(metadata))) = (
+            &mut doc.source,
+            serde_json::to_value(EventMetadata::from(event)),
+        ) {
+            source.extend(metadata);
+        }
+        Some(doc)
+    }
+
+    /// Appends the action and the document to a bulk request body
+    pub fn push_to(&self, body: &mut Vec<JsonBody<Value>>) {
+        let mut action = serde_json::Map::new();
+        action.insert(
+            self.op_type.to_string(),
+            json!({ "_index": self.index, "_id": self.id }),
+        );
+        body.push(Value::Object(action).into());
+        body.push(self.source.clone().into());
+    }
+}
+
+/// Fields of a live document that the archives don't have. The user is the one the event is
+/// about, e.g. the subscriber of a `@subscriber` line or the target of a ban.
+#[derive(Serialize)]
+struct EventMetadata<'a> {
+    platform: &'a str,
+    kind: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    display_name: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    badges: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subscriber: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subscriber_months: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bits: Option<u64>,
+}
+
+impl<'a> From<&'a Event> for EventMetadata<'a> {
+    fn from(event: &'a Event) -> Self {
+        let user = event.users().first().copied();
+        let mut metadata = EventMetadata {
+            platform: event.platform.as_ref(),
+            kind: event.kind.as_ref(),
+            message_id: event.id.as_deref(),
+            user_id: user.and_then(|user| user.id.as_deref()),
+            display_name: user.and_then(|user| user.display_name.as_deref()),
+            badges: &[],
+            subscriber: None,
+            subscriber_months: None,
+            bits: None,
+        };
+        match &event.kind {
+            EventKind::Message(msg) => {
+                metadata.badges = &msg.badges;
+                metadata.subscriber = Some(msg.is_subscriber);
+                metadata.subscriber_months = msg.subscriber_months;
+            }
+            EventKind::Subscription(sub) => {
+                metadata.subscriber = Some(true);
+                metadata.subscriber_months = Some(sub.cumulative_months);
+            }
+            EventKind::Bits(bits) => metadata.bits = Some(bits.amount),
+            _ => {}
+        }
+        metadata
+    }
+}
+
+/// A document of a bulk request that elasticsearch didn't index
+#[derive(Debug)]
+struct Rejection {
+    /// Position of the document in the request
+    position: usize,
+    status: u64,
+    error: Value,
+}
+
+impl Rejection {
+    /// Overloaded or unavailable shards, the document is fine and is sent again later. Anything
+    /// else, like a mapping error, would be rejected again.
+    fn is_temporary(&self) -> bool {
+        self.status == 429 || self.status >= 500
+    }
+
+    /// Data streams refuse to `create` a document whose id exists, it was indexed before
+    fn is_duplicate(&self) -> bool {
+        self.status == 409
+    }
+}
+
+struct ElasticsearchWorker {
+    pub client: Elasticsearch,
+    pub input: WorkerInput,
+    pub config: Arc<ElasticsearchSettings>,
+    pub layout: IndexLayout,
+    /// Documents that weren't indexed yet, a failed request leaves them here to be retried
+    batch: Vec<BulkDocument>,
+    /// Sequence number of the last event that was added to the batch
+    batch_seq: u64,
+    /// When the first event since the last send was read
+    oldest: Option<Instant>,
+    batch_size: usize,
+    batch_period: Duration,
+    dead_letter_path: PathBuf,
+    pub retries: u64,
+    pub max_retry_seconds: u64,
+}
+
+impl ElasticsearchWorker {
+    async fn work(&mut self, alerting: &DiscordAlerting) {
+        let mut has_sent_failed = false;
+        loop {
+            match self.run_writer().await {
+                // The writer is gone and everything it sent was written
+                Ok(()) => return,
+                Err(e) => {
+                    error!("Elasticsearch adapter failed: {:?}", e);
+                    self.retries += 1;
+                }
+            }
+            // The batch is kept and sent first once elasticsearch is back, nothing is dropped
+            if self.retries > 5 && !has_sent_failed {
+                alerting.error(&format!(
+                    "Elasticsearch is failing, 5 retries in with {} documents waiting...",
+                    self.batch.len()
+                ));
+                has_sent_failed = true;
+            }
+            let retry_seconds = (BASE_RETRY_SECONDS * self.retries)
+                .max(BASE_RETRY_SECONDS)
+                .min(self.max_retry_seconds);
+            info!(
+                "Reinitializing elasticsearch writer in {} seconds with {} documents waiting...",
+                retry_seconds,
+                self.batch.len()
+            );
+            tokio::time::sleep(Duration::from_secs(retry_seconds as u64)).await;
+        }
+    }
+    async fn run_writer(&mut self) -> Result<()> {
+        self.inititalize().await?;
+        // Whatever failed last time goes out before anything new is read
+        self.send_batch().await?;
+
+        info!("Starting ES ingestion loop");
+        // Sends the batch once it's old enough, even if no events are coming in
+        let mut flush_check = tokio::time::interval(FLUSH_CHECK_PERIOD);
+        loop {
+            let message = tokio::select! {
+                message = self.input.next() => message?,
+                _ = flush_check.tick() => {
+                    let expired = self
+                        .oldest
+                        .map_or(false, |oldest| oldest.elapsed() >= self.batch_period);
+                    if expired {
+                        debug!("Hit period, size: {}", self.batch.len());
+                        self.send_batch().await?;
+                    }
+                    continue;
+                }
+            };
+            match message {
+                Some(WorkerMessage::Item((seq, event))) => {
+                    self.push(seq, event);
+                    if self.batch.len() >= self.batch_size {
+                        debug!("Hit max batch, size: {}", self.batch.len());
+                        self.send_batch().await?;
+                    }
+                }
+                Some(WorkerMessage::Flush(done)) => {
+                    self.send_batch().await?;
+                    self.input.checkpoint().await?;
+                    let _ = done.send(());
+                }
+                None => break,
+            }
+        }
+        // The writer is gone, send what's left
+        self.send_batch().await?;
+        self.input.checkpoint().await
+    }
+
+    fn push(&mut self, seq: u64, event: AllEvents) {
+        for event in EventGroup::from(event).0 {
+            self.batch
+                .extend(BulkDocument::from_event(&self.layout, &event));
+        }
+        self.batch_seq = seq;
+        self.oldest.get_or_insert_with(Instant::now);
+    }
+
+    /// Sends the batch and commits everything up to the last event in it. Documents rejected
+    /// for good are written to the dead letter file, ones rejected with a temporary error stay in
+    /// the batch and fail the attempt, so only they are sent again after the backoff.
+    async fn send_batch(&mut self) -> Result<()> {
+        if !self.batch.is_empty() {
+            let rejections = self
+                .process(&self.batch)
+                .await
+                .with_context(|| "Processing batch of messages failed")?;
+            let batch = std::mem::take(&mut self.batch);
+            let (retry, dead) = split_rejected(batch, rejections);
+            self.batch = retry;
+            if !dead.is_empty() {
+                error!(
+                    "Elasticsearch rejected {} documents, first error: {}",
+                    dead.len(),
+                    dead[0].1.error
+                );
+                if let Err(e) = write_dead_letters(&self.dead_letter_path, &dead).await {
+                    self.batch.extend(dead.into_iter().map(|(doc, _)| doc));
+                    return Err(e);
+                }
+            }
+            if !self.batch.is_empty() {
+                bail!(
+                    "{} documents were rejected with a temporary error",
+                    self.batch.len()
+                );
+            }
+        }
+        self.input.commit(self.batch_seq).await?;
+        self.retries = 0;
+        self.oldest = None;
+        Ok(())
+    }
+
+    async fn inititalize(&mut self) -> Result<()> {
+        initialize(&self.client, &self.config).await
+    }
+
+    /// Sends the documents in one bulk request, returns the ones that weren't indexed
+    async fn process(&self, docs: &[BulkDocument]) -> Result<Vec<Rejection>> {
+        let mut body: Vec<JsonBody<_>> = Vec::with_capacity(docs.len() * 2);
+        for doc in docs {
+            doc.push_to(&mut body);
+        }
+
+        let mut req = self.client.bulk(BulkParts::None);
+        if let Some(pipeline) = &self.config.pipeline {
+            req = req.pipeline(pipeline)
+        }
+        let response = req.body(body).send().await?.error_for_status_code()?;
+
+        let response_body = response.json::<Value>().await?;
+
+        if !response_body["errors"].as_bool().unwrap_or(false) {
+            return Ok(Vec::new());
+        }
+        let items = response_body["items"]
+            .as_array()
+            .with_context(|| "Bulk response with errors is missing its items")?;
+        Ok(rejections(items))
+    }
+}
+
+fn rejections(items: &[Value]) -> Vec<Rejection> {
+    items
+        .iter()
+        .enumerate()
+        .filter_map(|(position, item)| {
+            // Keyed by the action, `index` or `create`
+            let result = item.as_object()?.values().next()?;
+            Some(Rejection {
+                position,
+                status: result["status"].as_u64().unwrap_or(0),
+                error: result.get("error")?.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Splits the rejected documents of a batch into ones to retry and ones that can't be indexed
+fn split_rejected(
+    batch: Vec<BulkDocument>,
+    rejections: Vec<Rejection>,
+) -> (Vec<BulkDocument>, Vec<(BulkDocument, Rejection)>) {
+    let mut docs: Vec<Option<BulkDocument>> = batch.into_iter().map(Some).collect();
+    let mut retry = Vec::new();
+    let mut dead = Vec::new();
+    for rejection in rejections {
+        let doc = match docs.get_mut(rejection.position).and_then(Option::take) {
+            Some(doc) => doc,
+            None => continue,
+        };
+        if rejection.is_duplicate() {
+            continue;
+        } else if rejection.is_temporary() {
+            retry.push(doc);
+        } else {
+            dead.push((doc, rejection));
+        }
+    }
+    (retry, dead)
+}
+
+/// Appends documents that elasticsearch won't take to a jsonl file, with the reason they were
+/// rejected, so they can be fixed up and indexed by hand.
+async fn write_dead_letters(path: &Path, dead: &[(BulkDocument, Rejection)]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut lines = String::new();
+    for (doc, rejection) in dead {
+        let line = json!({
+            "rejected_at": Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "status": rejection.status,
+            "error": rejection.error,
+            "index": doc.index,
+            "id": doc.id,
+            "document": doc.source,
+        });
+        lines += &(line.to_string() + "\n");
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Opening dead letter file {:?} failed", path))?;
+    file.write_all(lines.as_bytes()).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+pub fn create_elasticsearch_client(config: &ElasticsearchSettings) -> Result<Elasticsearch> {
+    let url = format!("{}:{}", config.host, config.port);
+    let url = Url::parse(&url).with_context(|| "Building elasticsearch url failed")?;
+    let mut transport = TransportBuilder::new(SingleNodeConnectionPool::new(url));
+    if let Some(credentials) = credentials(config)? {
+        transport = transport.auth(credentials);
+    }
+    if let Some(path) = &config.ca_cert_path {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("Reading elasticsearch CA certificate {} failed", path))?;
+        let cert = Certificate::from_pem(&pem)
+            .with_context(|| format!("Parsing elasticsearch CA certificate {} failed", path))?;
+        transport = transport.cert_validation(CertificateValidation::Full(cert));
+    }
+    let transport = transport
+        .build()
+        .with_context(|| "Building elasticsearch transport failed")?;
+    Ok(Elasticsearch::new(transport))
+}
+
+fn credentials(config: &ElasticsearchSettings) -> Result<Option<Credentials>> {
+    match (
+        &config.username,
+        &config.password,
+        &config.api_key_id,
+        &config.api_key,
+    ) {
+        (None, None, None, None) => Ok(None),
+        (Some(username), Some(password), None, None) => {
+            Ok(Some(Credentials::Basic(username.clone(), password.clone())))
+        }
+        (None, None, Some(id), Some(key)) => Ok(Some(Credentials::ApiKey(id.clone(), key.clone()))),
+        _ => bail!(
+            "Elasticsearch needs either both username and password or both api_key_id and \
+             api_key"
+        ),
+    }
+}
+
+pub fn create_elasticsearch_client_from_url(url: String) -> Result<Elasticsearch> {
+    let transport =
+        Transport::single_node(&url).with_context(|| "Building elasticsearch url failed")?;
+    let client = Elasticsearch::new(transport);
+    Ok(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::unified::ChannelType;
+
+    fn doc(id: &str) -> BulkDocument {
+        BulkDocument {
+            op_type: "index",
+            index: "rustlesearch-2021-08-01".to_string(),
+            id: id.to_string(),
+            source: json!({ "text": id }),
+        }
+    }
+
+    fn settings(auth: &str) -> ElasticsearchSettings {
+        let yaml = format!(
+            "host: https://localhost\nport: 9200\nindex: rustlesearch\nbatch_size: 1000\n\
+             batch_period_seconds: 10\nmax_retry_seconds: 360\n{}",
+            auth
+        );
+        let mut config = config::Config::default();
+        config
+            .merge(config::File::from_str(&yaml, config::FileFormat::Yaml))
+            .unwrap();
+        config.try_into().unwrap()
+    }
+
+    #[test]
+    fn test_picks_credentials() {
+        assert!(credentials(&settings("")).unwrap().is_none());
+        assert!(matches!(
+            credentials(&settings("username: elastic\npassword: changeme")).unwrap(),
+            Some(Credentials::Basic(user, _)) if user == "elastic"
+        ));
+        assert!(matches!(
+            credentials(&settings("api_key_id: id\napi_key: key")).unwrap(),
+            Some(Credentials::ApiKey(id, _)) if id == "id"
+        ));
+        assert!(credentials(&settings("username: elastic")).is_err());
+        assert!(credentials(&settings("password: changeme\napi_key: key")).is_err());
+        assert!(create_elasticsearch_client(&settings("username: elastic\npassword: x")).is_ok());
+    }
+
+    #[test]
+    fn test_splits_rejected_documents() {
+        let response = json!({
+            "errors": true,
+            "items": [
+                { "index": { "_id": "a", "status": 201 } },
+                { "index": { "_id": "b", "status": 429, "error": { "type": "es_rejected" } } },
+                { "index": { "_id": "c", "status": 400, "error": { "type": "mapper_parsing" } } },
+                { "index": { "_id": "d", "status": 503, "error": { "type": "unavailable" } } },
+                { "create": { "_id": "e", "status": 409, "error": { "type": "conflict" } } },
+            ]
+        });
+        let items = response["items"].as_array().unwrap();
+        let batch = vec![doc("a"), doc("b"), doc("c"), doc("d"), doc("e")];
+
+        let (retry, dead) = split_rejected(batch, rejections(items));
+        let retry_ids: Vec<_> = retry.iter().map(|doc| doc.id.as_str()).collect();
+        assert_eq!(retry_ids, vec!["b", "d"]);
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].0.id, "c");
+        assert_eq!(dead[0].1.status, 400);
+    }
+
+    #[test]
+    fn test_event_documents_carry_metadata() {
+        use crate::events::{Bits, ChatMessage, EventUser};
+
+        let user = EventUser {
+            id: Some("123".to_string()),
+            login: "bob".to_string(),
+            display_name: Some("Bob".to_string()),
+        };
+        let event = |id: Option<&str>, kind| Event {
+            platform: ChannelType::Twitch,
+            channel: "xqc".to_string(),
+            channel_id: Some("1".to_string()),
+            id: id.map(str::to_string),
+            timestamp: Utc.timestamp_millis_opt(1628035200095).unwrap(),
+            kind,
+        };
+        let layout = IndexLayout::monthly("rustlesearch");
+
+        let message = event(
+            Some("abc"),
+            EventKind::Message(ChatMessage {
+                user: user.clone(),
+                text: "Cheer100 hi".to_string(),
+                is_action: false,
+                badges: vec!["subscriber".to_string()],
+                is_subscriber: true,
+                subscriber_months: Some(7),
+                color: None,
+            }),
+        );
+        let doc = BulkDocument::from_event(&layout, &message).unwrap();
+        assert_eq!(doc.index, "rustlesearch-2021-08-01");
+        assert_eq!(doc.source["username"], "bob");
+        assert_eq!(doc.source["system"], false);
+        assert_eq!(doc.source["platform"], "twitch");
+        assert_eq!(doc.source["kind"], "message");
+        assert_eq!(doc.source["message_id"], "abc");
+        assert_eq!(doc.source["user_id"], "123");
+        assert_eq!(doc.source["display_name"], "Bob");
+        assert_eq!(doc.source["badges"], json!(["subscriber"]));
+        assert_eq!(doc.source["subscriber"], true);
+        assert_eq!(doc.source["subscriber_months"], 7);
+        assert!(doc.source.get("bits").is_none());
+
+        let bits = event(None, EventKind::Bits(Bits { user, amount: 100 }));
+        let doc = BulkDocument::from_event(&layout, &bits).unwrap();
+        assert_eq!(doc.source["username"], "@bits");
+        assert_eq!(doc.source["system"], true);
+        assert_eq!(doc.source["kind"], "bits");
+        assert_eq!(doc.source["user_id"], "123");
+        assert_eq!(doc.source["bits"], 100);
+        assert!(doc.source.get("message_id").is_none());
+        assert!(doc.source.get("badges").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_appends_dead_letters() {
+        let path = std::env::temp_dir()
+            .join(format!("tl2-dead-letter-{}", std::process::id()))
+            .join("elasticsearch.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let rejection = || Rejection {
+            position: 0,
+            status: 400,
+            error: json!({ "type": "mapper_parsing" }),
+        };
+        write_dead_letters(&path, &[(doc("a"), rejection())])
+            .await
+            .unwrap();
+        write_dead_letters(&path, &[(doc("b"), rejection())])
+            .await
+            .unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["id"], "b");
+        assert_eq!(lines[1]["document"]["text"], "b");
+        assert_eq!(lines[1]["error"]["type"], "mapper_parsing");
+
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,484 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use elasticsearch::{
+    http::{headers::HeaderMap, request::JsonBody, Method},
+    ilm::IlmPutLifecycleParts,
+    indices::{
+        IndicesCreateDataStreamParts, IndicesCreateParts, IndicesExistsAliasParts,
+        IndicesPutIndexTemplateParts,
+    },
+    ingest::IngestPutPipelineParts,
+    Elasticsearch,
+};
+use log::info;
+use serde_json::{json, Value};
+
+use crate::settings::{ElasticsearchSettings, IndexMode, RolloverSettings, SearchDistribution};
+
+/// Composable templates of the same priority can't overlap, this is above the built-in
+/// `logs-*-*` and `metrics-*-*` templates of Elasticsearch 8
+const TEMPLATE_PRIORITY: u64 = 200;
+
+/// Where a writer's documents go
+#[derive(Clone, Debug)]
+pub struct IndexLayout {
+    pub name: String,
+    pub mode: IndexMode,
+}
+
+impl IndexLayout {
+    pub fn monthly(name: &str) -> Self {
+        IndexLayout {
+            name: name.to_string(),
+            mode: IndexMode::Monthly,
+        }
+    }
+
+    /// The index, alias or data stream that a document from `ts` is written to
+    pub fn target(&self, ts: DateTime<Utc>) -> String {
+        match self.mode {
+            IndexMode::Monthly => monthly_index(&self.name, ts),
+            IndexMode::Rollover | IndexMode::DataStream => self.name.clone(),
+        }
+    }
+
+    /// The bulk action, data streams only accept `create`
+    pub fn op_type(&self) -> &'static str {
+        match self.mode {
+            IndexMode::Monthly | IndexMode::Rollover => "index",
+            IndexMode::DataStream => "create",
+        }
+    }
+}
+
+/// Index for a document, `<index>-<YYYY-MM>-01`, the same as the pipeline's monthly rounding
+pub fn monthly_index(index: &str, ts: DateTime<Utc>) -> String {
+    format!("{}-{}", index, ts.format("%Y-%m-01"))
+}
+
+/// The mapping of every document tl2 writes, for the live writer and the archive importers.
+/// Archives only fill the fields up to `system`, the rest is only known for live events.
+pub fn document_mapping() -> Value {
+    json!({
+      "properties": {
+        // Required by data streams, the same as `ts`
+        "@timestamp": { "type": "date" },
+        "channel": { "type": "keyword" },
+        "text": { "type": "text" },
+        "ts": { "type": "date" },
+        "username": { "type": "keyword" },
+        "system": { "type": "boolean" },
+        "platform": { "type": "keyword" },
+        "kind": { "type": "keyword" },
+        "message_id": { "type": "keyword" },
+        "user_id": { "type": "keyword" },
+        "display_name": { "type": "keyword" },
+        // Twitch badge names or dgg flairs
+        "badges": { "type": "keyword" },
+        "subscriber": { "type": "boolean" },
+        "subscriber_months": { "type": "integer" },
+        "bits": { "type": "long" },
+      },
+    })
+}
+
+fn index_settings() -> Value {
+    json!({
+      "number_of_replicas": 0,
+      "number_of_shards": 1,
+      "refresh_interval": "10s",
+      "sort.field": ["ts"],
+      "sort.order": ["desc"],
+      "codec": "best_compression",
+    })
+}
+
+/// Creates everything a writer needs before it can index: the lifecycle policy, index template,
+/// the first index or data stream and the ingest pipeline. Everything that already exists is
+/// updated in place, so this is run every time a writer starts.
+pub async fn initialize(client: &Elasticsearch, config: &ElasticsearchSettings) -> Result<()> {
+    let layout = IndexLayout {
+        name: config.index.clone(),
+        mode: config.index_mode,
+    };
+    info!("Initializing ES templates");
+    match layout.mode {
+        IndexMode::Monthly => initialize_template(client, &layout.name).await?,
+        IndexMode::Rollover => {
+            initialize_policy(client, config, &format!("{}-*", layout.name)).await?;
+            let mut settings = index_settings();
+            match config.distribution {
+                SearchDistribution::Elasticsearch => {
+                    settings["index.lifecycle.name"] = json!(policy_name(&layout.name));
+                    settings["index.lifecycle.rollover_alias"] = json!(layout.name);
+                }
+                SearchDistribution::Opensearch => {
+                    settings["plugins.index_state_management.rollover_alias"] = json!(layout.name);
+                }
+            }
+            put_index_template(
+                client,
+                &layout.name,
+                format!("{}-*", layout.name),
+                settings,
+                None,
+            )
+            .await?;
+            bootstrap_rollover_index(client, &layout.name).await?;
+        }
+        IndexMode::DataStream => {
+            initialize_policy(client, config, &format!(".ds-{}-*", layout.name)).await?;
+            let mut settings = index_settings();
+            if config.distribution == SearchDistribution::Elasticsearch {
+                settings["index.lifecycle.name"] = json!(policy_name(&layout.name));
+            }
+            let data_stream = json!({});
+            put_index_template(
+                client,
+                &layout.name,
+                layout.name.clone(),
+                settings,
+                Some(data_stream),
+            )
+            .await?;
+            create_data_stream(client, &layout.name).await?;
+        }
+    }
+    if let Some(pipeline) = &config.pipeline {
+        info!("Initializing ES pipelines");
+        initialize_pipeline(client, &layout, pipeline)
+            .await
+            .with_context(|| "Error initializing elasticsearch pipelines")?;
+    }
+    Ok(())
+}
+
+/// The template for the monthly indices, which the archive importers always write to
+pub async fn initialize_template(client: &Elasticsearch, index: &str) -> Result<()> {
+    put_index_template(
+        client,
+        index,
+        format!("{}-*", index),
+        index_settings(),
+        None,
+    )
+    .await
+    .with_context(|| "Error initializing elasticsearch templates")
+}
+
+async fn put_index_template(
+    client: &Elasticsearch,
+    index: &str,
+    pattern: String,
+    settings: Value,
+    data_stream: Option<Value>,
+) -> Result<()> {
+    let mut body = json!({
+      "index_patterns": [pattern],
+      "priority": TEMPLATE_PRIORITY,
+      "template": {
+        "mappings": document_mapping(),
+        "settings": settings,
+      },
+    });
+    if let Some(data_stream) = data_stream {
+        body["data_stream"] = data_stream;
+    }
+    let exception = client
+        .indices()
+        .put_index_template(IndicesPutIndexTemplateParts::Name(&format!(
+            "{}-template",
+            index
+        )))
+        .body(body)
+        .send()
+        .await?
+        .exception()
+        .await?;
+    if let Some(exception) = exception {
+        bail!(
+            "Initializing templates failed with Exception: {:?}",
+            exception
+        );
+    }
+
+    Ok(())
+}
+
+fn policy_name(index: &str) -> String {
+    format!("{}-policy", index)
+}
+
+/// Creates the ILM or ISM policy that rolls the indices matching `pattern` over
+async fn initialize_policy(
+    client: &Elasticsearch,
+    config: &ElasticsearchSettings,
+    pattern: &str,
+) -> Result<()> {
+    let name = policy_name(&config.index);
+    info!("Initializing lifecycle policy {}", name);
+    match config.distribution {
+        SearchDistribution::Elasticsearch => {
+            let exception = client
+                .ilm()
+                .put_lifecycle(IlmPutLifecycleParts::Policy(&name))
+                .body(ilm_policy(&config.rollover))
+                .send()
+                .await?
+                .exception()
+                .await?;
+            if let Some(exception) = exception {
+                bail!(
+                    "Initializing lifecycle policy failed with Exception: {:?}",
+                    exception
+                );
+            }
+        }
+        SearchDistribution::Opensearch => {
+            let path = format!("/_plugins/_ism/policies/{}", name);
+            let response = client
+                .send(
+                    Method::Put,
+                    &path,
+                    HeaderMap::new(),
+                    Option::<&()>::None,
+                    Some(JsonBody::from(ism_policy(&config.rollover, pattern))),
+                    None,
+                )
+                .await?;
+            let status = response.status_code().as_u16();
+            // Updating an ISM policy needs its current version, an existing one is left alone
+            if status == 409 {
+                info!("ISM policy {} already exists, it isn't updated", name);
+            } else if !(200..300).contains(&status) {
+                bail!(
+                    "Initializing ISM policy failed with status {}: {}",
+                    status,
+                    response.text().await?
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn ilm_policy(rollover: &RolloverSettings) -> Value {
+    let mut phases = json!({
+      "hot": {
+        "actions": {
+          "rollover": {
+            "max_age": rollover.max_age,
+            "max_primary_shard_size": rollover.max_primary_size,
+          },
+        },
+      },
+    });
+    if let Some(delete_after) = &rollover.delete_after {
+        phases["delete"] = json!({
+          "min_age": delete_after,
+          "actions": { "delete": {} },
+        });
+    }
+    json!({ "policy": { "phases": phases } })
+}
+
+fn ism_policy(rollover: &RolloverSettings, pattern: &str) -> Value {
+    let mut hot = json!({
+      "name": "hot",
+      "actions": [{
+        "rollover": {
+          "min_index_age": rollover.max_age,
+          "min_primary_shard_size": rollover.max_primary_size,
+        },
+      }],
+      "transitions": [],
+    });
+    let mut states = Vec::new();
+    if let Some(delete_after) = &rollover.delete_after {
+        hot["transitions"] = json!([{
+          "state_name": "delete",
+          "conditions": { "min_index_age": delete_after },
+        }]);
+        states.push(json!({
+          "name": "delete",
+          "actions": [{ "delete": {} }],
+          "transitions": [],
+        }));
+    }
+    states.insert(0, hot);
+    json!({
+      "policy": {
+        "description": "tl2 rollover",
+        "default_state": "hot",
+        "states": states,
+        // Attaches the policy to new indices, OpenSearch has no setting for it
+        "ism_template": [{
+          "index_patterns": [pattern],
+          "priority": TEMPLATE_PRIORITY,
+        }],
+      },
+    })
+}
+
+/// Creates `<index>-000001` with `<index>` as its write alias, unless the alias exists already
+async fn bootstrap_rollover_index(client: &Elasticsearch, index: &str) -> Result<()> {
+    let exists = client
+        .indices()
+        .exists_alias(IndicesExistsAliasParts::Name(&[index]))
+        .send()
+        .await?
+        .status_code()
+        .is_success();
+    if exists {
+        return Ok(());
+    }
+    info!("Creating the first rollover index of {}", index);
+    let exception = client
+        .indices()
+        .create(IndicesCreateParts::Index(&format!("{}-000001", index)))
+        .body(json!({
+          "aliases": {
+            (index): { "is_write_index": true },
+          },
+        }))
+        .send()
+        .await?
+        .exception()
+        .await?;
+    if let Some(exception) = exception {
+        bail!(
+            "Creating the first rollover index failed with Exception: {:?}",
+            exception
+        );
+    }
+    Ok(())
+}
+
+async fn create_data_stream(client: &Elasticsearch, name: &str) -> Result<()> {
+    let response = client
+        .indices()
+        .create_data_stream(IndicesCreateDataStreamParts::Name(name))
+        .send()
+        .await?;
+    if response.status_code().is_success() {
+        info!("Created data stream {}", name);
+        return Ok(());
+    }
+    let status = response.status_code().as_u16();
+    let text = response.text().await?;
+    if !text.contains("resource_already_exists_exception") {
+        bail!(
+            "Creating data stream failed with status {}: {}",
+            status,
+            text
+        );
+    }
+    Ok(())
+}
+
+pub async fn initialize_pipeline(
+    client: &Elasticsearch,
+    layout: &IndexLayout,
+    pipeline: &str,
+) -> Result<()> {
+    let mut processors = Vec::new();
+    // Rollover aliases and data streams name their own indices
+    if layout.mode == IndexMode::Monthly {
+        processors.push(json!({
+          "date_index_name": {
+            "date_rounding": "M",
+            "field": "ts",
+            "index_name_prefix": layout.name.to_string() + "-",
+          },
+        }));
+    }
+    processors.push(json!({
+      // Only for documents indexed without an id, the writers set their own
+      "set": {
+        "field": "_id",
+        "value": "{{channel}}-{{username}}-{{ts}}",
+        "override": false,
+      },
+    }));
+    let exception = client
+        .ingest()
+        .put_pipeline(IngestPutPipelineParts::Id(pipeline))
+        .body(json!({
+          "description": "tl2 document ids and monthly date-time index naming",
+          "processors": processors,
+        }))
+        .send()
+        .await?
+        .exception()
+        .await?;
+    if let Some(exception) = exception {
+        bail!(
+            "Initializing pipeline failed with Exception: {:?}",
+            exception
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_monthly_index() {
+        let ts = Utc.timestamp_millis_opt(1630454399999).unwrap();
+        assert_eq!(ts.to_rfc3339(), "2021-08-31T23:59:59.999+00:00");
+        assert_eq!(monthly_index("rustlesearch", ts), "rustlesearch-2021-08-01");
+        let next_month = ts + chrono::Duration::milliseconds(1);
+        assert_eq!(
+            monthly_index("rustlesearch", next_month),
+            "rustlesearch-2021-09-01"
+        );
+    }
+
+    #[test]
+    fn test_layout_targets() {
+        let ts = Utc.timestamp_millis_opt(1628035200095).unwrap();
+        let monthly = IndexLayout::monthly("rustlesearch");
+        assert_eq!(monthly.target(ts), "rustlesearch-2021-08-01");
+        assert_eq!(monthly.op_type(), "index");
+
+        let stream = IndexLayout {
+            name: "rustlesearch".to_string(),
+            mode: IndexMode::DataStream,
+        };
+        assert_eq!(stream.target(ts), "rustlesearch");
+        assert_eq!(stream.op_type(), "create");
+    }
+
+    #[test]
+    fn test_policies_delete_only_when_configured() {
+        let mut rollover = RolloverSettings::default();
+        assert!(ilm_policy(&rollover)["policy"]["phases"]
+            .get("delete")
+            .is_none());
+        assert_eq!(
+            ism_policy(&rollover, "tl2-*")["policy"]["states"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+
+        rollover.delete_after = Some("365d".to_string());
+        let ilm = ilm_policy(&rollover);
+        assert_eq!(ilm["policy"]["phases"]["delete"]["min_age"], "365d");
+        let ism = ism_policy(&rollover, "tl2-*");
+        assert_eq!(ism["policy"]["states"][0]["name"], "hot");
+        assert_eq!(
+            ism["policy"]["states"][0]["transitions"][0]["state_name"],
+            "delete"
+        );
+        assert_eq!(
+            ism["policy"]["ism_template"][0]["index_patterns"][0],
+            "tl2-*"
+        );
+    }
+}
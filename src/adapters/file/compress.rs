@@ -0,0 +1,399 @@
+use std::{
+    collections::{BTreeSet, VecDeque},
+    ffi::OsString,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use async_compression::tokio::{
+    bufread::{BrotliDecoder, GzipDecoder, ZstdDecoder},
+    write::{BrotliEncoder, GzipEncoder, ZstdEncoder},
+};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use log::{debug, info};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+};
+
+use crate::settings::Compression;
+
+/// How often the log directory is scanned for finished days
+const SCAN_PERIOD: Duration = Duration::from_secs(300);
+
+/// Every codec a day file might already be compressed with, from an earlier run or config
+const CODECS: [Compression; 3] = [Compression::Gzip, Compression::Zstd, Compression::Brotli];
+
+/// Suffix a plain day file is renamed to while it's being compressed
+const STAGED_SUFFIX: &str = "compressing";
+
+/// Suffix of a compressed file that's being written, before it replaces the day's file
+const TMP_SUFFIX: &str = "tmp";
+
+impl Compression {
+    fn extension(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gz"),
+            Compression::Zstd => Some("zst"),
+            Compression::Brotli => Some("br"),
+        }
+    }
+}
+
+/// Finds plain `<channel>/<YYYY-MM-DD>.<extension>` files of days that ended more than the grace
+/// period ago, which won't be written to anymore. Days whose compression was cut short by a crash
+/// are found too.
+pub struct DayCompressor {
+    root: PathBuf,
+    extension: &'static str,
+    grace: chrono::Duration,
+    pending: VecDeque<PathBuf>,
+    last_scan: Option<Instant>,
+}
+
+impl DayCompressor {
+    pub fn new(root: PathBuf, extension: &'static str, grace_seconds: u64) -> Self {
+        DayCompressor {
+            root,
+            extension,
+            grace: chrono::Duration::seconds(grace_seconds as i64),
+            pending: VecDeque::new(),
+            last_scan: None,
+        }
+    }
+
+    /// The next day file that's ready to be compressed. The directory is only scanned every
+    /// `SCAN_PERIOD`, files found are handed out one at a time so the writer isn't held up.
+    pub async fn next_ready(&mut self, now: DateTime<Utc>) -> Result<Option<PathBuf>> {
+        let due = self
+            .last_scan
+            .map_or(true, |last_scan| last_scan.elapsed() >= SCAN_PERIOD);
+        if self.pending.is_empty() && due {
+            self.last_scan = Some(Instant::now());
+            self.pending = self.scan(now).await?.into();
+        }
+        Ok(self.pending.pop_front())
+    }
+
+    async fn scan(&self, now: DateTime<Utc>) -> Result<Vec<PathBuf>> {
+        let mut ready = BTreeSet::new();
+        let mut channels = match tokio::fs::read_dir(&self.root).await {
+            Ok(channels) => channels,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(channel) = channels.next_entry().await? {
+            if !channel.file_type().await?.is_dir() {
+                continue;
+            }
+            let mut files = tokio::fs::read_dir(channel.path()).await?;
+            while let Some(file) = files.next_entry().await? {
+                if let Some(plain) = self.day_file(&file.path()) {
+                    if self.is_finished_day(&plain, now) {
+                        ready.insert(plain);
+                    }
+                }
+            }
+        }
+        debug!(
+            "Found {} finished day files to compress in {:?}",
+            ready.len(),
+            self.root
+        );
+        Ok(ready.into_iter().collect())
+    }
+
+    /// The plain day file that `path` is, or is being compressed from
+    fn day_file(&self, path: &Path) -> Option<PathBuf> {
+        let name = path.file_name()?.to_str()?;
+        let mut plain = name
+            .strip_suffix(STAGED_SUFFIX)
+            .and_then(|n| n.strip_suffix('.'));
+        for codec in CODECS {
+            let suffix = format!(".{}.{}", codec.extension()?, TMP_SUFFIX);
+            plain = plain.or_else(|| name.strip_suffix(suffix.as_str()));
+        }
+        let plain = plain.unwrap_or(name);
+        plain
+            .ends_with(self.extension)
+            .then(|| path.with_file_name(plain))
+    }
+
+    pub fn is_finished_day(&self, path: &Path, now: DateTime<Utc>) -> bool {
+        let date = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(self.extension))
+            .and_then(|name| name.strip_suffix('.'))
+            .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+        let day_end = date
+            .and_then(|date| date.succ_opt())
+            .and_then(|next_day| next_day.and_hms_opt(0, 0, 0))
+            .map(|dt| Utc.from_utc_datetime(&dt));
+        match day_end {
+            Some(day_end) => day_end + self.grace <= now,
+            None => false,
+        }
+    }
+}
+
+/// Compresses a plain day file into `<file>.<codec extension>` and removes it. If the day was
+/// already compressed, e.g. lines came in after it was, they're merged into the compressed file so
+/// a day never ends up with both a plain and a compressed file.
+///
+/// The plain file is renamed to `<file>.compressing` first and the compressed file is written to
+/// a temporary file, which replaces the old one once the staged lines are removed. A run that
+/// died with the staged file still there starts over from it, one that died after removing it
+/// only has to move the temporary file in place, so lines are never lost or written twice.
+pub async fn compress_day_file(plain: &Path, compression: Compression) -> Result<()> {
+    let target = match compression.extension() {
+        Some(extension) => with_suffix(plain, extension),
+        None => return Ok(()),
+    };
+    let staged = with_suffix(plain, STAGED_SUFFIX);
+    let tmp = with_suffix(&target, TMP_SUFFIX);
+
+    loop {
+        if !exists(&staged).await {
+            for codec in CODECS {
+                if let Some(extension) = codec.extension() {
+                    let tmp = with_suffix(&with_suffix(plain, extension), TMP_SUFFIX);
+                    if exists(&tmp).await {
+                        replace_compressed(plain, &tmp, codec).await?;
+                    }
+                }
+            }
+            if !exists(plain).await {
+                return Ok(());
+            }
+            tokio::fs::rename(plain, &staged).await?;
+        }
+
+        let existing = compressed_files(plain).await;
+        if existing.is_empty() {
+            let mut input = File::open(&staged)
+                .await
+                .with_context(|| format!("Opening {:?} failed", staged))?;
+            write_compressed(&tmp, compression, &mut input).await?;
+        } else {
+            let mut contents = Vec::new();
+            for (path, codec) in &existing {
+                let mut decoder = open_decoded(path, *codec).await?;
+                decoder
+                    .read_to_end(&mut contents)
+                    .await
+                    .with_context(|| format!("Decompressing {:?} failed", path))?;
+            }
+            contents.extend_from_slice(&tokio::fs::read(&staged).await?);
+            write_compressed(&tmp, compression, &mut contents.as_slice()).await?;
+        }
+
+        // Everything staged is in the temporary file now
+        tokio::fs::remove_file(&staged).await?;
+        replace_compressed(plain, &tmp, compression).await?;
+        info!("Compressed {:?} to {:?}", plain, target);
+    }
+}
+
+/// Moves a finished temporary file in place of the day's compressed files
+async fn replace_compressed(plain: &Path, tmp: &Path, compression: Compression) -> Result<()> {
+    let target = match compression.extension() {
+        Some(extension) => with_suffix(plain, extension),
+        None => return Ok(()),
+    };
+    for (path, _) in compressed_files(plain).await {
+        if path != target {
+            tokio::fs::remove_file(&path).await?;
+        }
+    }
+    tokio::fs::rename(tmp, &target).await?;
+    Ok(())
+}
+
+/// The compressed files of the day that's written to `plain`
+pub async fn compressed_files(plain: &Path) -> Vec<(PathBuf, Compression)> {
+    let mut existing = Vec::new();
+    for codec in CODECS {
+        if let Some(extension) = codec.extension() {
+            let path = with_suffix(plain, extension);
+            if exists(&path).await {
+                existing.push((path, codec));
+            }
+        }
+    }
+    existing
+}
+
+async fn exists(path: &Path) -> bool {
+    tokio::fs::metadata(path).await.is_ok()
+}
+
+async fn write_compressed(
+    path: &Path,
+    compression: Compression,
+    input: &mut (impl AsyncRead + Unpin),
+) -> Result<()> {
+    let writer = BufWriter::new(File::create(path).await?);
+    let mut encoder: Box<dyn AsyncWrite + Unpin + Send> = match compression {
+        Compression::None => Box::new(writer),
+        Compression::Gzip => Box::new(GzipEncoder::new(writer)),
+        Compression::Zstd => Box::new(ZstdEncoder::new(writer)),
+        Compression::Brotli => Box::new(BrotliEncoder::new(writer)),
+    };
+    tokio::io::copy(input, &mut encoder)
+        .await
+        .with_context(|| format!("Writing {:?} failed", path))?;
+    encoder.shutdown().await?;
+    drop(encoder);
+    // Make sure the data is on disk before it replaces anything
+    File::open(path).await?.sync_all().await?;
+    Ok(())
+}
+
+async fn open_decoded(
+    path: &Path,
+    compression: Compression,
+) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+    let reader = BufReader::new(File::open(path).await?);
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => {
+            let mut decoder = GzipDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+        Compression::Zstd => {
+            let mut decoder = ZstdDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+        Compression::Brotli => Box::new(BrotliDecoder::new(reader)),
+    })
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("tl2-compress-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("chan")).unwrap();
+        dir
+    }
+
+    async fn read_decoded(path: &Path, compression: Compression) -> String {
+        let mut contents = String::new();
+        let mut decoder = open_decoded(path, compression).await.unwrap();
+        decoder.read_to_string(&mut contents).await.unwrap();
+        contents
+    }
+
+    #[tokio::test]
+    async fn test_finds_days_after_grace_period() {
+        let dir = temp_dir("scan");
+        for name in [
+            "2021-08-03.txt",
+            "2021-08-04.txt",
+            "2021-08-03.txt.gz",
+            "notes.txt",
+        ] {
+            std::fs::write(dir.join("chan").join(name), "").unwrap();
+        }
+        let mut compressor = DayCompressor::new(dir.clone(), "txt", 3600);
+
+        let now = NaiveDate::from_ymd_opt(2021, 8, 5)
+            .and_then(|d| d.and_hms_opt(0, 30, 0))
+            .map(|dt| Utc.from_utc_datetime(&dt))
+            .unwrap();
+        assert_eq!(
+            compressor.next_ready(now).await.unwrap(),
+            Some(dir.join("chan").join("2021-08-03.txt"))
+        );
+        // The 4th is still within its grace period
+        assert_eq!(compressor.next_ready(now).await.unwrap(), None);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_merges_late_lines_into_compressed_day() {
+        let dir = temp_dir("merge");
+        let plain = dir.join("chan").join("2021-08-03.txt");
+        std::fs::write(&plain, "one\n").unwrap();
+        compress_day_file(&plain, Compression::Gzip).await.unwrap();
+        assert!(!plain.exists());
+
+        // A late line reopens the plain file, the day is recompressed with a different codec
+        std::fs::write(&plain, "two\n").unwrap();
+        compress_day_file(&plain, Compression::Zstd).await.unwrap();
+        let zst = with_suffix(&plain, "zst");
+        assert_eq!(read_decoded(&zst, Compression::Zstd).await, "one\ntwo\n");
+        assert!(!plain.exists());
+        assert!(!with_suffix(&plain, "gz").exists());
+
+        // The same line again is kept, it's a different message
+        std::fs::write(&plain, "two\n").unwrap();
+        compress_day_file(&plain, Compression::Zstd).await.unwrap();
+        assert_eq!(
+            read_decoded(&zst, Compression::Zstd).await,
+            "one\ntwo\ntwo\n"
+        );
+        assert!(!plain.exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_recovers_interrupted_compression() {
+        let dir = temp_dir("recover");
+        let plain = dir.join("chan").join("2021-08-03.txt");
+        let gz = with_suffix(&plain, "gz");
+        let staged = with_suffix(&plain, STAGED_SUFFIX);
+        let tmp = with_suffix(&gz, TMP_SUFFIX);
+        std::fs::write(&plain, "one\n").unwrap();
+        compress_day_file(&plain, Compression::Gzip).await.unwrap();
+
+        // Died while writing the temporary file, the staged lines are compressed again
+        std::fs::write(&staged, "two\n").unwrap();
+        std::fs::write(&tmp, "partial").unwrap();
+        // A late line came in after that
+        std::fs::write(&plain, "three\n").unwrap();
+        let compressor = DayCompressor::new(dir.clone(), "txt", 0);
+        let now = NaiveDate::from_ymd_opt(2021, 8, 5)
+            .and_then(|d| d.and_hms_opt(0, 0, 0))
+            .map(|dt| Utc.from_utc_datetime(&dt))
+            .unwrap();
+        assert_eq!(compressor.scan(now).await.unwrap(), vec![plain.clone()]);
+        compress_day_file(&plain, Compression::Gzip).await.unwrap();
+        assert_eq!(
+            read_decoded(&gz, Compression::Gzip).await,
+            "one\ntwo\nthree\n"
+        );
+
+        // Died after the staged lines were removed, the temporary file only has to be moved
+        let mut lines: &[u8] = b"one\ntwo\nthree\nfour\n";
+        write_compressed(&tmp, Compression::Gzip, &mut lines)
+            .await
+            .unwrap();
+        assert_eq!(compressor.scan(now).await.unwrap(), vec![plain.clone()]);
+        compress_day_file(&plain, Compression::Gzip).await.unwrap();
+        assert_eq!(
+            read_decoded(&gz, Compression::Gzip).await,
+            "one\ntwo\nthree\nfour\n"
+        );
+        assert!(!tmp.exists() && !staged.exists() && !plain.exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,612 @@
+use std::{
+    collections::{hash_map::Entry, BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_compression::tokio::write::GzipEncoder;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{error, trace};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::mpsc::{Receiver, Sender},
+};
+use twitch_irc::message::AsRawIRC;
+
+use self::compress::{compress_day_file, compressed_files, DayCompressor};
+use super::{flush_worker, try_send_all, writer_channel, WorkerMessage, Writer};
+use crate::{
+    events::{AllEvents, EventGroup, SimpleMessageGroup},
+    formats::{
+        moderation::ModerationLog,
+        unified::{ChannelType, UnifiedMessageLog},
+    },
+    settings::{Compression, FileSettings},
+};
+
+pub mod compress;
+
+/// Value of the `source` field in records written by the live jsonl writer
+const JSONL_SOURCE: &str = "tl2/live";
+
+/// How often buffered lines are checked against the max age, and old days' files are closed
+const FLUSH_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Directory for raw twitch messages that aren't sent to a channel, like `RECONNECT`
+const RAW_SERVER_DIRECTORY: &str = "_server";
+
+/// The on-disk format of the daily log files, all use the `<channel>/<YYYY-MM-DD>.<ext>` layout.
+#[derive(Clone, Copy, Debug)]
+pub enum FileFormat {
+    /// `[timestamp] username: text` lines, the same as the ORL archives
+    Orl,
+    /// `simple-log/1.0` records, one per line, the same as `JsonFileSink`
+    Jsonl,
+    /// Moderation log entries, one per line. Only moderation events are written.
+    ModerationJsonl,
+    /// Every twitch message received over IRC, gzip compressed. twitch-irc doesn't keep the line
+    /// it parsed, so it's serialized again and tags can come in a different order than twitch sent
+    /// them. Each flush appends a new gzip member, so the files can be read back with any gzip
+    /// reader.
+    TwitchIrc,
+}
+
+impl FileFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            FileFormat::Orl => "txt",
+            FileFormat::Jsonl => "jsonl",
+            FileFormat::ModerationJsonl => "moderation.jsonl",
+            FileFormat::TwitchIrc => "irc.gz",
+        }
+    }
+
+    fn is_compressed(&self) -> bool {
+        matches!(self, FileFormat::TwitchIrc)
+    }
+
+    /// Whether finished days are compressed afterwards, raw twitch files already are
+    fn is_compressed_later(&self, compression: Compression) -> bool {
+        !self.is_compressed() && compression != Compression::None
+    }
+}
+
+struct FileLine {
+    timestamp: DateTime<Utc>,
+    channel: String,
+    line: String,
+}
+
+pub struct FileWriter {
+    tx: Sender<WorkerMessage<Vec<FileLine>>>,
+    format: FileFormat,
+    pub config: Arc<FileSettings>,
+}
+
+impl FileWriter {
+    pub fn new(config: FileSettings, format: FileFormat) -> FileWriter {
+        let config = Arc::new(config);
+        let (tx, rx) = writer_channel();
+        FileWorker::spawn(config.clone(), format, rx);
+        FileWriter { tx, format, config }
+    }
+
+    fn format_lines(&self, event: AllEvents) -> Result<Vec<FileLine>> {
+        match self.format {
+            FileFormat::Orl => Ok(SimpleMessageGroup::from(event)
+                .0
+                .into_iter()
+                .map(|msg| {
+                    let msg = msg.normalize();
+                    let d = msg.timestamp.format("%Y-%m-%d %H:%M:%S%.3f %Z");
+                    FileLine {
+                        line: format!("[{}] {}: {}", d, msg.username, msg.text),
+                        timestamp: msg.timestamp,
+                        channel: msg.channel,
+                    }
+                })
+                .collect()),
+            FileFormat::Jsonl => EventGroup::from(event)
+                .0
+                .iter()
+                .filter_map(|event| {
+                    let log = event.to_simple_log(JSONL_SOURCE)?;
+                    Some((event.timestamp, log))
+                })
+                .map(|(timestamp, log)| {
+                    Ok(FileLine {
+                        timestamp,
+                        channel: log.channel_name.clone(),
+                        line: serde_json::to_string(&UnifiedMessageLog::SimpleLog1_0(log))?,
+                    })
+                })
+                .collect(),
+            FileFormat::ModerationJsonl => ModerationLog::from_events(event)
+                .into_iter()
+                .map(|log| {
+                    Ok(FileLine {
+                        timestamp: log.timestamp,
+                        line: serde_json::to_string(&log)?,
+                        channel: log.channel,
+                    })
+                })
+                .collect(),
+            FileFormat::TwitchIrc => Ok(match event {
+                // Files are picked by when the line arrived, not when it's written
+                AllEvents::Twitch(event) => vec![FileLine {
+                    timestamp: event.received_at,
+                    channel: event.channel_login().to_string(),
+                    line: event.source().as_raw_irc(),
+                }],
+                AllEvents::Raw(raw) if raw.platform == ChannelType::Twitch => vec![FileLine {
+                    timestamp: raw.received_at,
+                    channel: raw
+                        .channel
+                        .unwrap_or_else(|| RAW_SERVER_DIRECTORY.to_string()),
+                    line: raw.data,
+                }],
+                _ => Vec::new(),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl Writer for FileWriter {
+    fn write(&self, event: AllEvents) -> Result<()> {
+        let lines = self.format_lines(event)?;
+        if !lines.is_empty() {
+            try_send_all(&self.tx, vec![lines])?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        flush_worker(&self.tx).await
+    }
+}
+
+struct FileWorker {
+    config: Arc<FileSettings>,
+    format: FileFormat,
+    rx: Receiver<WorkerMessage<Vec<FileLine>>>,
+    file_queues: HashMap<String, QueuedAppender>,
+    handles: FileHandles,
+    compressor: Option<DayCompressor>,
+    /// Days that were already compressed when lines for them came in, those lines are merged
+    /// into the compressed file on the next flush check
+    late_days: BTreeSet<PathBuf>,
+}
+impl FileWorker {
+    fn spawn(
+        config: Arc<FileSettings>,
+        format: FileFormat,
+        rx: Receiver<WorkerMessage<Vec<FileLine>>>,
+    ) {
+        let compressor = format.is_compressed_later(config.compression).then(|| {
+            DayCompressor::new(
+                PathBuf::from(&config.path),
+                format.extension(),
+                config.compress_grace_seconds,
+            )
+        });
+        let worker = FileWorker {
+            handles: FileHandles::new(config.max_open_files, config.fsync),
+            compressor,
+            config,
+            format,
+            rx,
+            file_queues: HashMap::new(),
+            late_days: BTreeSet::new(),
+        };
+        tokio::spawn(worker.run());
+    }
+    async fn run(mut self) {
+        let mut flush_check = tokio::time::interval(FLUSH_CHECK_PERIOD);
+        loop {
+            tokio::select! {
+                message = self.rx.recv() => match message {
+                    Some(WorkerMessage::Item(lines)) => {
+                        if let Err(error) = self.process(lines).await {
+                            error!("[FileWriter] Error writing messages to disk: {:?}", error);
+                        }
+                    }
+                    Some(WorkerMessage::Flush(done)) => match self.flush().await {
+                        Ok(()) => {
+                            let _ = done.send(());
+                        }
+                        Err(error) => error!("[FileWriter] Error flushing files: {:?}", error),
+                    },
+                    None => break,
+                },
+                _ = flush_check.tick() => {
+                    if let Err(error) = self.flush_expired().await {
+                        error!("[FileWriter] Error flushing files: {:?}", error);
+                    }
+                    self.close_previous_days();
+                    if let Err(error) = self.merge_late_days().await {
+                        error!("[FileWriter] Error merging late lines: {:?}", error);
+                    }
+                    if let Err(error) = self.compress_next_day().await {
+                        error!("[FileWriter] Error compressing finished day: {:?}", error);
+                    }
+                }
+            }
+        }
+        // The writer is gone, write out whatever is still buffered
+        if let Err(error) = self.flush().await {
+            error!("[FileWriter] Error flushing files: {:?}", error);
+        }
+    }
+    async fn flush(&mut self) -> Result<()> {
+        for queue in self.file_queues.values_mut() {
+            queue.flush(&mut self.handles).await?;
+        }
+        Ok(())
+    }
+    /// Writes out the queues of channels that went quiet with lines still buffered
+    async fn flush_expired(&mut self) -> Result<()> {
+        for queue in self.file_queues.values_mut() {
+            if queue.is_expired() {
+                queue.flush(&mut self.handles).await?;
+            }
+        }
+        Ok(())
+    }
+    /// Closes the files of previous days after midnight, late lines for them reopen the file
+    fn close_previous_days(&mut self) {
+        let today = Utc::now().format("%Y-%m-%d").to_string() + "." + self.format.extension();
+        self.handles
+            .close_where(|path| path.file_name() != Some(today.as_ref()));
+    }
+    /// Compresses one finished day file per call, so a backlog doesn't hold up writing
+    async fn compress_next_day(&mut self) -> Result<()> {
+        let path = match &mut self.compressor {
+            Some(compressor) => match compressor.next_ready(Utc::now()).await? {
+                Some(path) => path,
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+        self.compress_day(path).await
+    }
+    /// Merges the lines that came in for already compressed days into their compressed files
+    async fn merge_late_days(&mut self) -> Result<()> {
+        while let Some(path) = self.late_days.iter().next().cloned() {
+            self.compress_day(path.clone()).await?;
+            self.late_days.remove(&path);
+        }
+        Ok(())
+    }
+    async fn compress_day(&mut self, path: PathBuf) -> Result<()> {
+        // Late lines for the day may still be buffered, or its file open
+        let channel = path
+            .parent()
+            .and_then(|dir| dir.file_name())
+            .and_then(|name| name.to_str());
+        if let Some(queue) = channel.and_then(|channel| self.file_queues.get_mut(channel)) {
+            queue.flush(&mut self.handles).await?;
+        }
+        self.handles.close_where(|open| open == path);
+        compress_day_file(&path, self.config.compression).await
+    }
+    async fn process(&mut self, lines: Vec<FileLine>) -> Result<()> {
+        for line in lines {
+            self.write_to_file(&line.timestamp, &line.channel, &line.line)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn write_to_file(
+        &mut self,
+        date: &DateTime<Utc>,
+        channel: &str,
+        line: &str,
+    ) -> Result<()> {
+        let filename = date.format("%Y-%m-%d").to_string() + "." + self.format.extension();
+        let path = Path::new(&self.config.path).join(&channel).join(&filename);
+        if !self.file_queues.contains_key(channel) {
+            tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        }
+        if let Some(compressor) = &self.compressor {
+            if !self.late_days.contains(&path)
+                && compressor.is_finished_day(&path, Utc::now())
+                && !compressed_files(&path).await.is_empty()
+            {
+                self.late_days.insert(path.clone());
+            }
+        }
+        let queue = self
+            .file_queues
+            .entry(channel.to_string())
+            .or_insert_with(|| {
+                QueuedAppender::new(
+                    channel.to_string(),
+                    self.config.max_buffered_lines,
+                    Duration::from_secs(self.config.max_buffered_seconds),
+                    self.format.is_compressed(),
+                )
+            });
+        queue
+            .write(path, line.to_string(), &mut self.handles)
+            .await?;
+
+        Ok(())
+    }
+}
+
+struct QueuedAppender {
+    channel: String,
+    max_age: Duration,
+    capacity: usize,
+    compress: bool,
+    queue: HashMap<PathBuf, Vec<String>>,
+    /// When the oldest line that's still buffered was queued
+    oldest: Option<Instant>,
+}
+
+impl QueuedAppender {
+    fn new(channel: String, capacity: usize, max_age: Duration, compress: bool) -> Self {
+        QueuedAppender {
+            channel,
+            max_age,
+            capacity,
+            compress,
+            queue: HashMap::new(),
+            oldest: None,
+        }
+    }
+
+    fn queue_len(&self) -> usize {
+        self.queue.iter().fold(0usize, |sum, (_, v)| sum + v.len())
+    }
+    async fn write(
+        &mut self,
+        path: PathBuf,
+        line: String,
+        handles: &mut FileHandles,
+    ) -> std::io::Result<()> {
+        let list = self.queue.entry(path).or_insert_with(Vec::new);
+        list.push(line);
+        self.oldest.get_or_insert_with(Instant::now);
+        let queue_len = self.queue_len();
+        let expired = self.is_expired();
+        if queue_len >= self.capacity || expired {
+            trace!(
+                "Flushing with channel: {}, queue_len: {}, capacity: {}, expired: {}",
+                self.channel,
+                queue_len,
+                self.capacity,
+                expired
+            );
+            self.flush(handles).await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self, handles: &mut FileHandles) -> std::io::Result<()> {
+        // Older days first, the file names sort by date
+        let mut paths: Vec<PathBuf> = self.queue.keys().cloned().collect();
+        paths.sort();
+        for path in paths {
+            let list = match self.queue.get(&path) {
+                Some(list) if !list.is_empty() => list,
+                _ => continue,
+            };
+            let mut to_write = (list.join("\n") + "\n").into_bytes();
+            if self.compress {
+                let mut encoder = GzipEncoder::new(Vec::new());
+                encoder.write_all(&to_write).await?;
+                encoder.shutdown().await?;
+                to_write = encoder.into_inner();
+            }
+            handles.append(&path, &to_write).await?;
+            // Only forget lines once they're written, so a failed flush is retried
+            self.queue.remove(&path);
+        }
+        self.oldest = None;
+        Ok(())
+    }
+
+    fn is_expired(&self) -> bool {
+        self.oldest
+            .map_or(false, |oldest| oldest.elapsed() >= self.max_age)
+    }
+}
+
+/// Append handles of recently written files, so they aren't reopened on every flush. The least
+/// recently written file is closed once more than `capacity` are open.
+struct FileHandles {
+    capacity: usize,
+    fsync: bool,
+    files: HashMap<PathBuf, (File, u64)>,
+    writes: u64,
+}
+
+impl FileHandles {
+    fn new(capacity: usize, fsync: bool) -> Self {
+        FileHandles {
+            capacity,
+            fsync,
+            files: HashMap::new(),
+            writes: 0,
+        }
+    }
+
+    /// Appends to the file, a handle that failed is closed so the next write reopens the file
+    async fn append(&mut self, path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+        let fsync = self.fsync;
+        let file = self.open(path).await?;
+        let mut result = file.write_all(bytes).await;
+        if result.is_ok() {
+            result = file.flush().await;
+        }
+        if result.is_ok() && fsync {
+            result = file.sync_data().await;
+        }
+        if result.is_err() {
+            self.files.remove(path);
+        }
+        result
+    }
+
+    async fn open(&mut self, path: &Path) -> std::io::Result<&mut File> {
+        self.writes += 1;
+        if !self.files.contains_key(path) && self.files.len() >= self.capacity {
+            let least_recent = self
+                .files
+                .iter()
+                .min_by_key(|(_, (_, last_write))| *last_write)
+                .map(|(path, _)| path.clone());
+            if let Some(least_recent) = least_recent {
+                self.files.remove(&least_recent);
+            }
+        }
+        let (file, last_write) = match self.files.entry(path.to_path_buf()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?;
+                entry.insert((file, 0))
+            }
+        };
+        *last_write = self.writes;
+        Ok(file)
+    }
+
+    /// Closes the files matching the predicate, everything written to them is already flushed
+    fn close_where(&mut self, predicate: impl Fn(&Path) -> bool) {
+        self.files.retain(|path, _| !predicate(path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_compression::tokio::bufread::GzipDecoder;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tl2-file-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_closes_least_recently_written_file() {
+        let dir = temp_dir("lru");
+        let mut handles = FileHandles::new(2, false);
+        for name in ["a", "b", "a", "c"] {
+            handles.append(&dir.join(name), b"line\n").await.unwrap();
+        }
+        let mut open: Vec<_> = handles.files.keys().cloned().collect();
+        open.sort();
+        assert_eq!(open, vec![dir.join("a"), dir.join("c")]);
+
+        handles.close_where(|path| path.ends_with("a"));
+        assert_eq!(handles.files.len(), 1);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("a")).unwrap(),
+            "line\nline\n"
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_flushes_quiet_channel_after_max_age() {
+        let dir = temp_dir("age");
+        let writer = FileWriter::new(
+            FileSettings {
+                path: dir.to_string_lossy().into(),
+                max_buffered_seconds: 1,
+                max_buffered_lines: 50,
+                max_open_files: 16,
+                fsync: false,
+                compression: Compression::None,
+                compress_grace_seconds: 3600,
+            },
+            FileFormat::Orl,
+        );
+        let timestamp = Utc::now();
+        let line = FileLine {
+            timestamp,
+            channel: "quiet".into(),
+            line: "[ts] bob: hello".into(),
+        };
+        try_send_all(&writer.tx, vec![vec![line]]).unwrap();
+
+        let path = dir
+            .join("quiet")
+            .join(timestamp.format("%Y-%m-%d.txt").to_string());
+        let mut contents = String::new();
+        for _ in 0..30 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            contents = std::fs::read_to_string(&path).unwrap_or_default();
+            if !contents.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(contents, "[ts] bob: hello\n");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_merges_late_lines_into_compressed_day() {
+        let dir = temp_dir("late");
+        let plain = dir.join("late").join("2021-08-03.txt");
+        std::fs::create_dir_all(plain.parent().unwrap()).unwrap();
+        std::fs::write(&plain, "[ts] bob: on time\n").unwrap();
+        compress_day_file(&plain, Compression::Gzip).await.unwrap();
+
+        let writer = FileWriter::new(
+            FileSettings {
+                path: dir.to_string_lossy().into(),
+                max_buffered_seconds: 5,
+                max_buffered_lines: 50,
+                max_open_files: 16,
+                fsync: false,
+                compression: Compression::Gzip,
+                compress_grace_seconds: 3600,
+            },
+            FileFormat::Orl,
+        );
+        let line = FileLine {
+            timestamp: "2021-08-03T23:59:59Z".parse().unwrap(),
+            channel: "late".into(),
+            line: "[ts] bob: late".into(),
+        };
+        try_send_all(&writer.tx, vec![vec![line]]).unwrap();
+        writer.flush().await.unwrap();
+
+        let gz = dir.join("late").join("2021-08-03.txt.gz");
+        let mut contents = String::new();
+        for _ in 0..30 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            contents.clear();
+            let file = tokio::io::BufReader::new(File::open(&gz).await.unwrap());
+            GzipDecoder::new(file)
+                .read_to_string(&mut contents)
+                .await
+                .unwrap();
+            if !plain.exists() && contents.contains("late") {
+                break;
+            }
+        }
+        assert_eq!(contents, "[ts] bob: on time\n[ts] bob: late\n");
+        assert!(!plain.exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
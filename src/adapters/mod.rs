@@ -1,17 +1,29 @@
-use anyhow::Result;
+use std::{fmt, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
 use enum_dispatch::enum_dispatch;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError, Receiver, Sender},
+    oneshot,
+};
 
 use self::{
-    clickhouse::ClickhouseWriter, console::ConsoleWriter, console_metrics::ConsoleMetricsWriter,
-    elasticsearch::ElasticsearchWriter, file::FileWriter, username_tracker::UsernameTracker,
+    capture::CaptureWriter, clickhouse::ClickhouseWriter, console::ConsoleWriter,
+    console_metrics::ConsoleMetricsWriter, elasticsearch::ElasticsearchWriter, file::FileWriter,
+    moderation_log::ModerationLogWriter, presence::PresenceWriter,
+    username_tracker::UsernameTracker,
 };
-use crate::events::AllEvents;
+use crate::{events::AllEvents, routing::Route, settings::RouteSettings};
 
+pub mod capture;
 pub mod clickhouse;
 pub mod console;
 pub mod console_metrics;
 pub mod elasticsearch;
 pub mod file;
+pub mod moderation_log;
+pub mod presence;
 pub mod username_tracker;
 
 #[enum_dispatch]
@@ -22,9 +34,130 @@ pub enum Writers {
     ConsoleMetrics(ConsoleMetricsWriter),
     Clickhouse(ClickhouseWriter),
     UsernameTracker(UsernameTracker),
+    ModerationLog(ModerationLogWriter),
+    Presence(PresenceWriter),
+    Capture(CaptureWriter),
+}
+
+/// A named writer together with the route deciding which events it receives
+pub struct RoutedWriter {
+    pub name: String,
+    pub route: Route,
+    pub writer: Writers,
 }
 
+impl RoutedWriter {
+    pub fn new(
+        name: impl Into<String>,
+        writer: impl Into<Writers>,
+        routes: &RouteSettings,
+    ) -> Self {
+        RoutedWriter {
+            name: name.into(),
+            route: Route::new(routes),
+            writer: writer.into(),
+        }
+    }
+
+    /// Writes the event if the route allows it
+    pub fn write(&self, event: &AllEvents) -> Result<()> {
+        if self.route.matches(event) {
+            self.writer.write(event.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Writes the event, waiting for the writer to catch up when its channel is full
+    pub async fn write_waiting(&self, event: &AllEvents) -> Result<()> {
+        loop {
+            match self.write(event) {
+                Err(e) if e.is::<WriterFull>() => tokio::time::sleep(FULL_RETRY_DELAY).await,
+                result => return result,
+            }
+        }
+    }
+
+    pub async fn shutdown(self) -> Result<()> {
+        let name = self.name;
+        self.writer
+            .shutdown()
+            .await
+            .with_context(|| format!("Shutting down writer '{}' failed", name))
+    }
+}
+
+/// Events buffered between a writer and its worker, past this the writer returns `WriterFull`
+/// and the event is kept in the writer's queue instead.
+pub const WRITER_CHANNEL_CAPACITY: usize = 1024;
+
+const FULL_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Returned by `Writer::write` when the writer's worker is behind, the event wasn't written and
+/// should be retried later.
+#[derive(Debug)]
+pub struct WriterFull;
+
+impl fmt::Display for WriterFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Writer channel is full")
+    }
+}
+
+impl std::error::Error for WriterFull {}
+
+/// What a writer sends to its worker task
+pub enum WorkerMessage<T> {
+    Item(T),
+    /// Write out everything received before this, then reply
+    Flush(oneshot::Sender<()>),
+}
+
+pub fn writer_channel<T>() -> (Sender<WorkerMessage<T>>, Receiver<WorkerMessage<T>>) {
+    mpsc::channel(WRITER_CHANNEL_CAPACITY)
+}
+
+/// Sends either all of the items or none of them, so an event is never partially written.
+pub fn try_send_all<T>(tx: &Sender<WorkerMessage<T>>, items: Vec<T>) -> Result<()> {
+    if tx.is_closed() {
+        bail!("Writer worker stopped, rx probably dropped");
+    }
+    if tx.capacity() < items.len() {
+        return Err(WriterFull.into());
+    }
+    for item in items {
+        match tx.try_send(WorkerMessage::Item(item)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => return Err(WriterFull.into()),
+            Err(TrySendError::Closed(_)) => bail!("Writer worker stopped, rx probably dropped"),
+        }
+    }
+    Ok(())
+}
+
+/// Waits until the worker wrote out everything that was sent to it before
+pub async fn flush_worker<T>(tx: &Sender<WorkerMessage<T>>) -> Result<()> {
+    let (done_tx, done_rx) = oneshot::channel();
+    tx.send(WorkerMessage::Flush(done_tx))
+        .await
+        .map_err(|_| anyhow!("Writer worker stopped, rx probably dropped"))?;
+    done_rx
+        .await
+        .with_context(|| "Writer worker failed before it flushed")
+}
+
+#[async_trait]
 #[enum_dispatch(Writers)]
 pub trait Writer {
     fn write(&self, event: AllEvents) -> Result<()>;
+
+    /// Waits until everything written so far is written out downstream
+    async fn flush(&self) -> Result<()>;
+
+    /// Flushes and stops the writer, its worker exits once the writer is dropped
+    async fn shutdown(self) -> Result<()>
+    where
+        Self: Sized + Sync,
+    {
+        self.flush().await
+    }
 }
@@ -0,0 +1,250 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use log::{debug, error};
+use sqlx::SqlitePool;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use super::{flush_worker, try_send_all, writer_channel, WorkerMessage, Writer};
+use crate::{
+    events::AllEvents,
+    formats::moderation::ModerationLog,
+    settings::ModerationLogSettings,
+    sinks::sqlite::moderation::{init_moderation_tables, submit_moderation_batch},
+};
+
+/// How often entries are checked against the batch period, so they're written while it's quiet
+const FLUSH_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Batches kept while sqlite fails, the oldest entries are dropped past that. The same as the
+/// twitch username tracker keeps.
+const MAX_QUEUED_BATCHES: usize = 10;
+
+/// Keeps bans, timeouts and their reversals from every platform in sqlite, for `tl2 moderation`.
+pub struct ModerationLogWriter {
+    tx: Sender<WorkerMessage<ModerationLog>>,
+    pub config: Arc<ModerationLogSettings>,
+}
+
+impl ModerationLogWriter {
+    pub fn new(config: ModerationLogSettings, sqlite: SqlitePool) -> ModerationLogWriter {
+        let config = Arc::new(config);
+        let (tx, rx) = writer_channel();
+        ModerationLogWorker::spawn(config.clone(), rx, sqlite);
+        ModerationLogWriter { tx, config }
+    }
+}
+
+#[async_trait]
+impl Writer for ModerationLogWriter {
+    fn write(&self, event: AllEvents) -> Result<()> {
+        let logs = ModerationLog::from_events(event);
+        if !logs.is_empty() {
+            try_send_all(&self.tx, logs)?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        flush_worker(&self.tx).await
+    }
+}
+
+struct ModerationLogWorker {
+    sqlite: SqlitePool,
+    config: Arc<ModerationLogSettings>,
+    rx: Receiver<WorkerMessage<ModerationLog>>,
+    queue: Vec<ModerationLog>,
+    /// When the oldest entry that's still queued came in
+    oldest: Option<Instant>,
+    /// Set after a failed write, until then only the flush check tries again
+    failing: bool,
+}
+
+impl ModerationLogWorker {
+    fn spawn(
+        config: Arc<ModerationLogSettings>,
+        rx: Receiver<WorkerMessage<ModerationLog>>,
+        sqlite: SqlitePool,
+    ) {
+        let worker = ModerationLogWorker {
+            config,
+            rx,
+            sqlite,
+            queue: Vec::new(),
+            oldest: None,
+            failing: false,
+        };
+        tokio::spawn(worker.run());
+    }
+
+    async fn run(mut self) {
+        if let Err(error) = init_moderation_tables(&self.sqlite).await {
+            error!(
+                "Couldn't initialize sqlite table for the moderation log: {:?}",
+                error
+            );
+            return;
+        }
+        let batch_size = self.config.batch_size as usize;
+        let batch_period = Duration::from_secs(self.config.batch_period_seconds);
+        let mut flush_check = tokio::time::interval(FLUSH_CHECK_PERIOD);
+        loop {
+            tokio::select! {
+                message = self.rx.recv() => match message {
+                    Some(WorkerMessage::Item(log)) => {
+                        self.queue.push(log);
+                        self.oldest.get_or_insert_with(Instant::now);
+                        if self.queue.len() >= batch_size && !self.failing {
+                            self.write_queue().await;
+                        }
+                    }
+                    Some(WorkerMessage::Flush(done)) => {
+                        if self.write_queue().await {
+                            let _ = done.send(());
+                        }
+                    }
+                    None => break,
+                },
+                _ = flush_check.tick() => {
+                    if matches!(self.oldest, Some(oldest) if oldest.elapsed() >= batch_period) {
+                        self.write_queue().await;
+                    }
+                }
+            }
+        }
+        // The writer is gone, write out what's left
+        self.write_queue().await;
+    }
+
+    /// Writes out the queue, returns whether that worked. What couldn't be written stays queued,
+    /// up to `MAX_QUEUED_BATCHES` batches.
+    async fn write_queue(&mut self) -> bool {
+        match self.process(&self.queue).await {
+            Ok(()) => {
+                self.queue.clear();
+                self.oldest = None;
+                self.failing = false;
+                return true;
+            }
+            Err(error) => error!("Error writing moderation log to disk: {:?}", error),
+        }
+        self.failing = true;
+        let max_queued = (self.config.batch_size as usize).max(1) * MAX_QUEUED_BATCHES;
+        if self.queue.len() > max_queued {
+            let dropped = self.queue.len() - max_queued;
+            self.queue.drain(..dropped);
+            error!(
+                "Dropped the {} oldest moderation log entries, sqlite is still failing",
+                dropped
+            );
+        }
+        false
+    }
+
+    async fn process(&self, logs: &[ModerationLog]) -> Result<()> {
+        if logs.is_empty() {
+            return Ok(());
+        }
+        debug!("Writing {} moderation log entries to db", logs.len());
+        submit_moderation_batch(&self.sqlite, logs).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::{
+        formats::{moderation::ModerationAction, unified::ChannelType},
+        sinks::sqlite::moderation::{query_moderation, ModerationQuery},
+        sqlite_pool::create_sqlite,
+    };
+
+    fn timeout(target: &str, seconds: i64) -> ModerationLog {
+        ModerationLog {
+            platform: ChannelType::Twitch,
+            channel: "xqc".to_string(),
+            channel_id: None,
+            action: ModerationAction::Timeout,
+            target: Some(target.to_string()),
+            target_id: None,
+            moderator: None,
+            duration_seconds: Some(600),
+            timestamp: Utc.timestamp_opt(seconds, 0).unwrap(),
+        }
+    }
+
+    async fn temp_pool(name: &str) -> (std::path::PathBuf, SqlitePool) {
+        let path = std::env::temp_dir().join(format!(
+            "tl2-moderation-log-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let pool = create_sqlite(path.to_str().unwrap()).await.unwrap();
+        (path, pool)
+    }
+
+    #[tokio::test]
+    async fn test_writes_partial_batch_after_period() {
+        let (path, pool) = temp_pool("period").await;
+        let config = ModerationLogSettings {
+            sqlite_path: path.to_string_lossy().into(),
+            batch_size: 100,
+            batch_period_seconds: 1,
+        };
+        let writer = ModerationLogWriter::new(config, pool.clone());
+        try_send_all(&writer.tx, vec![timeout("spammer", 1000)]).unwrap();
+
+        let query = ModerationQuery {
+            target: Some("spammer".to_string()),
+            limit: 10,
+            ..Default::default()
+        };
+        let mut found = Vec::new();
+        for _ in 0..30 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            found = query_moderation(&pool, &query).await.unwrap_or_default();
+            if !found.is_empty() {
+                break;
+            }
+        }
+        assert_eq!(found, vec![timeout("spammer", 1000)]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_caps_queue_while_sqlite_fails() {
+        let (path, pool) = temp_pool("cap").await;
+        pool.close().await;
+        let (_tx, rx) = writer_channel();
+        let mut worker = ModerationLogWorker {
+            sqlite: pool,
+            config: Arc::new(ModerationLogSettings {
+                sqlite_path: path.to_string_lossy().into(),
+                batch_size: 1,
+                batch_period_seconds: 1,
+            }),
+            rx,
+            queue: (0..MAX_QUEUED_BATCHES as i64 + 5)
+                .map(|i| timeout("spammer", i))
+                .collect(),
+            oldest: Some(Instant::now()),
+            failing: false,
+        };
+
+        assert!(!worker.write_queue().await);
+        assert!(worker.failing);
+        assert_eq!(worker.queue.len(), MAX_QUEUED_BATCHES);
+        assert_eq!(worker.queue[0], timeout("spammer", 5));
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,182 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use clickhouse::Client;
+use log::{debug, error};
+use sqlx::SqlitePool;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use super::{
+    clickhouse::{
+        create_client,
+        presence_table::{create_presence, ClickhousePresence},
+    },
+    flush_worker, try_send_all, writer_channel, WorkerMessage, Writer,
+};
+use crate::{
+    events::{AllEvents, EventCategory},
+    formats::unified::ChannelType,
+    scrapers::twitch::helix::HelixClient,
+    settings::PresenceSettings,
+    sinks::sqlite::presence::{init_presence_tables, submit_presence_snapshots},
+    sqlite_pool::create_sqlite,
+    userlist_scraper::{PresenceSnapshot, Userlists},
+};
+
+/// Keeps the userlist of every chat and writes snapshots of chatter and message counts on an
+/// interval, for charting them over time.
+pub struct PresenceWriter {
+    tx: Sender<WorkerMessage<AllEvents>>,
+}
+
+impl PresenceWriter {
+    pub async fn new(config: PresenceSettings) -> Result<PresenceWriter> {
+        let sqlite = match &config.sqlite_path {
+            Some(path) => Some(create_sqlite(path).await?),
+            None => None,
+        };
+        let clickhouse = config.clickhouse.as_ref().map(create_client);
+        if sqlite.is_none() && clickhouse.is_none() {
+            bail!("The presence writer needs a sqlite_path, clickhouse or both to write to");
+        }
+
+        let helix = match config.helix {
+            Some(helix) => Some(HelixClient::new(helix)?),
+            None => None,
+        };
+
+        let (tx, rx) = writer_channel();
+        let worker = PresenceWorker {
+            rx,
+            sqlite,
+            clickhouse,
+            helix,
+            period: Duration::from_secs(config.snapshot_interval_seconds.max(1)),
+            userlists: Userlists::default(),
+        };
+        tokio::spawn(worker.run());
+        Ok(PresenceWriter { tx })
+    }
+}
+
+#[async_trait]
+impl Writer for PresenceWriter {
+    fn write(&self, event: AllEvents) -> Result<()> {
+        // Messages are only counted
+        match event.category() {
+            EventCategory::Presence | EventCategory::Message => try_send_all(&self.tx, vec![event]),
+            _ => Ok(()),
+        }
+    }
+
+    async fn flush(&self) -> Result<()> {
+        flush_worker(&self.tx).await
+    }
+}
+
+struct PresenceWorker {
+    rx: Receiver<WorkerMessage<AllEvents>>,
+    sqlite: Option<SqlitePool>,
+    clickhouse: Option<Client>,
+    helix: Option<HelixClient>,
+    period: Duration,
+    userlists: Userlists,
+}
+
+impl PresenceWorker {
+    async fn run(mut self) {
+        if let Err(error) = self.init().await {
+            error!("Couldn't initialize presence tables: {:?}", error);
+            return;
+        }
+        let mut interval = tokio::time::interval(self.period);
+        // The first tick completes right away, there's nothing to snapshot yet
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                message = self.rx.recv() => match message {
+                    Some(WorkerMessage::Item(event)) => self.userlists.apply(&event),
+                    // Nothing is buffered between snapshots
+                    Some(WorkerMessage::Flush(done)) => {
+                        let _ = done.send(());
+                    }
+                    None => break,
+                },
+                _ = interval.tick() => self.snapshot().await,
+            }
+        }
+        // The writer is gone, write down where things stood
+        self.snapshot().await;
+    }
+
+    async fn init(&self) -> Result<()> {
+        if let Some(sqlite) = &self.sqlite {
+            init_presence_tables(sqlite).await?;
+        }
+        if let Some(clickhouse) = &self.clickhouse {
+            create_presence(clickhouse).await?;
+        }
+        Ok(())
+    }
+
+    /// A failed snapshot is skipped, the next one has the current userlists anyway
+    async fn snapshot(&mut self) {
+        let mut snapshots = self.userlists.snapshot(Utc::now());
+        if snapshots.is_empty() {
+            return;
+        }
+        self.poll_viewers(&mut snapshots).await;
+        debug!("Writing {} presence snapshots", snapshots.len());
+        if let Some(sqlite) = &self.sqlite {
+            if let Err(error) = submit_presence_snapshots(sqlite, &snapshots).await {
+                error!("Error writing presence snapshots to sqlite: {:?}", error);
+            }
+        }
+        if let Some(clickhouse) = &self.clickhouse {
+            if let Err(error) = insert_snapshots(clickhouse, &snapshots).await {
+                error!(
+                    "Error writing presence snapshots to clickhouse: {:?}",
+                    error
+                );
+            }
+        }
+    }
+
+    /// Viewers stay unknown when the poll fails, the chat counts are written regardless
+    async fn poll_viewers(&mut self, snapshots: &mut [PresenceSnapshot]) {
+        let helix = match &mut self.helix {
+            Some(helix) => helix,
+            None => return,
+        };
+        let logins: Vec<String> = snapshots
+            .iter()
+            .filter(|snapshot| snapshot.platform == ChannelType::Twitch)
+            .map(|snapshot| snapshot.channel.clone())
+            .collect();
+        if logins.is_empty() {
+            return;
+        }
+        match helix.viewer_counts(&logins).await {
+            Ok(viewers) => {
+                for snapshot in snapshots
+                    .iter_mut()
+                    .filter(|snapshot| snapshot.platform == ChannelType::Twitch)
+                {
+                    snapshot.viewers = viewers.get(&snapshot.channel).copied();
+                }
+            }
+            Err(error) => error!("Error polling twitch viewer counts: {:?}", error),
+        }
+    }
+}
+
+async fn insert_snapshots(client: &Client, snapshots: &[PresenceSnapshot]) -> Result<()> {
+    let mut insert = client.insert::<ClickhousePresence>("presence")?;
+    for snapshot in snapshots {
+        insert.write(&ClickhousePresence::from(snapshot)).await?;
+    }
+    insert.end().await?;
+    Ok(())
+}
@@ -1,158 +1,225 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
-use chrono::{DateTime, Utc};
-use log::{debug, error};
+use async_trait::async_trait;
+use chrono::Utc;
+use log::{debug, error, info};
 use sqlx::SqlitePool;
-use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::{Receiver, Sender};
 use twitch_irc::message::UserNoticeEvent;
 
-use super::Writer;
+use super::{flush_worker, try_send_all, writer_channel, WorkerMessage, Writer};
 use crate::{
-    events::AllEvents, scrapers::twitch::events::TwitchEvent, settings::UsernameTrackerSettings,
+    alerts::DiscordAlerting,
+    events::AllEvents,
+    scrapers::twitch::events::{TwitchEvent, TwitchMessage},
+    settings::UsernameTrackerSettings,
+    sinks::sqlite::{
+        dgg_users::{init_dgg_user_tables, submit_dgg_user_batch, DggUserSighting},
+        usernames::{init_username_tables, submit_username_batch, UsernameUpdate},
+    },
 };
 
+/// How often queued updates are checked against the batch period, so they're written while it's
+/// quiet
+const FLUSH_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Batches kept while sqlite fails, the oldest updates are dropped past that
+const MAX_QUEUED_BATCHES: usize = 10;
+
+/// Keeps a timeline of the logins every twitch id has used, for `tl2 user-history`, and logs
+/// renames as they're noticed, or alerts about them with `alert_renames`. Dgg users have no ids,
+/// for them it keeps when they were seen and the history of their sub tier and flairs per site.
 pub struct UsernameTracker {
-    tx: UnboundedSender<TwitchEvent>,
+    tx: Sender<WorkerMessage<TwitchEvent>>,
+    /// A `NAMES` frame can list thousands of users, its sightings are sent as one item
+    dgg_tx: Sender<WorkerMessage<Vec<DggUserSighting>>>,
     pub config: Arc<UsernameTrackerSettings>,
 }
 
 impl UsernameTracker {
-    pub fn new(config: UsernameTrackerSettings, sqlite: SqlitePool) -> UsernameTracker {
+    pub fn new(
+        config: UsernameTrackerSettings,
+        sqlite: SqlitePool,
+        alerting: Arc<DiscordAlerting>,
+    ) -> UsernameTracker {
         let config = Arc::new(config);
-        let (tx, rx) = mpsc::unbounded_channel();
-        UsernameWorker::spawn(config.clone(), rx, sqlite);
-        UsernameTracker { tx, config }
+        let (tx, rx) = writer_channel();
+        UsernameWorker::spawn(config.clone(), rx, sqlite.clone(), alerting);
+        let (dgg_tx, dgg_rx) = writer_channel();
+        DggUserWorker::spawn(config.clone(), dgg_rx, sqlite);
+        UsernameTracker { tx, dgg_tx, config }
     }
 }
 
+#[async_trait]
 impl Writer for UsernameTracker {
     fn write(&self, event: AllEvents) -> Result<()> {
-        if let AllEvents::Twitch(t) = event {
-            self.tx.send(t)?;
+        match event {
+            AllEvents::Twitch(t) => try_send_all(&self.tx, vec![t])?,
+            AllEvents::Dgg(d) => {
+                // `NAMES` is the user list at the time it's received
+                let timestamp = d.timestamp().unwrap_or_else(Utc::now);
+                let sightings: Vec<DggUserSighting> = d
+                    .users()
+                    .into_iter()
+                    .map(|user| DggUserSighting::new(d.channel(), user, timestamp))
+                    .collect();
+                if !sightings.is_empty() {
+                    try_send_all(&self.dgg_tx, vec![sightings])?;
+                }
+            }
+            AllEvents::Raw(_) => {}
         }
         Ok(())
     }
-}
 
-#[derive(Clone, Debug)]
-struct UsernameUpdateEvent {
-    pub id: String,
-    pub username: String,
-    pub timestamp: DateTime<Utc>,
+    async fn flush(&self) -> Result<()> {
+        flush_worker(&self.tx).await?;
+        flush_worker(&self.dgg_tx).await
+    }
 }
 
 struct UsernameWorker {
     sqlite: SqlitePool,
     config: Arc<UsernameTrackerSettings>,
-    rx: UnboundedReceiver<TwitchEvent>,
+    rx: Receiver<WorkerMessage<TwitchEvent>>,
+    alerting: Arc<DiscordAlerting>,
+    queue: Vec<UsernameUpdate>,
+    /// When the oldest update that's still queued came in
+    oldest: Option<Instant>,
+    /// Set after a failed write, until then only the flush check tries again
+    failing: bool,
 }
 impl UsernameWorker {
     fn spawn(
         config: Arc<UsernameTrackerSettings>,
-        rx: UnboundedReceiver<TwitchEvent>,
+        rx: Receiver<WorkerMessage<TwitchEvent>>,
         sqlite: SqlitePool,
+        alerting: Arc<DiscordAlerting>,
     ) {
-        let worker = UsernameWorker { config, rx, sqlite };
+        let worker = UsernameWorker {
+            config,
+            rx,
+            sqlite,
+            alerting,
+            queue: Vec::new(),
+            oldest: None,
+            failing: false,
+        };
         tokio::spawn(worker.run());
     }
     async fn run(mut self) {
-        if let Err(error) = init_tables(&self.sqlite).await {
+        if let Err(error) = init_username_tables(&self.sqlite).await {
             error!(
                 "Couldn't initialize sqlite table for usernames: {:?}",
                 error
             );
             return;
         }
-        let mut updates_queue: Vec<UsernameUpdateEvent> = Vec::new();
-        while let Some(evt) = self.rx.recv().await {
-            let mut new_update_events = self.get_username_updates(evt);
-            updates_queue.append(&mut new_update_events);
-
-            let batch_size = self.config.batch_size as usize;
-            if updates_queue.len() >= batch_size {
-                if let Err(error) = self.process(&updates_queue).await {
-                    error!("Error writing usernames to disk: {:?}", error);
-                } else {
-                    updates_queue.clear();
+        let batch_size = self.config.batch_size as usize;
+        let batch_period = Duration::from_secs(self.config.batch_period_seconds);
+        let mut flush_check = tokio::time::interval(FLUSH_CHECK_PERIOD);
+        loop {
+            tokio::select! {
+                message = self.rx.recv() => match message {
+                    Some(WorkerMessage::Item(evt)) => {
+                        let mut updates = self.get_username_updates(evt);
+                        if updates.is_empty() {
+                            continue;
+                        }
+                        self.queue.append(&mut updates);
+                        self.oldest.get_or_insert_with(Instant::now);
+                        if self.queue.len() >= batch_size && !self.failing {
+                            self.write_queue().await;
+                        }
+                    }
+                    Some(WorkerMessage::Flush(done)) => {
+                        if self.write_queue().await {
+                            let _ = done.send(());
+                        }
+                    }
+                    None => break,
+                },
+                _ = flush_check.tick() => {
+                    if matches!(self.oldest, Some(oldest) if oldest.elapsed() >= batch_period) {
+                        self.write_queue().await;
+                    }
                 }
             }
+        }
+        // The tracker is gone, write out what's left
+        self.write_queue().await;
+    }
 
-            if updates_queue.len() >= batch_size * 10 {
-                error!(
-                    "Update queue backed up to {} items! Removing {} old items",
-                    updates_queue.len(),
-                    batch_size
-                );
-                updates_queue.drain(0..batch_size);
+    /// Writes out the queue, returns whether that worked. What couldn't be written stays queued,
+    /// up to `MAX_QUEUED_BATCHES` batches.
+    async fn write_queue(&mut self) -> bool {
+        match self.process(&self.queue).await {
+            Ok(()) => {
+                self.queue.clear();
+                self.oldest = None;
+                self.failing = false;
+                return true;
             }
+            Err(error) => error!("Error writing usernames to disk: {:?}", error),
+        }
+        self.failing = true;
+        let max_queued = (self.config.batch_size as usize).max(1) * MAX_QUEUED_BATCHES;
+        if self.queue.len() > max_queued {
+            let dropped = self.queue.len() - max_queued;
+            self.queue.drain(..dropped);
+            error!(
+                "Dropped the {} oldest username updates, sqlite is still failing",
+                dropped
+            );
         }
+        false
     }
-    async fn process(&mut self, update_events: &[UsernameUpdateEvent]) -> Result<()> {
+
+    async fn process(&self, update_events: &[UsernameUpdate]) -> Result<()> {
+        if update_events.is_empty() {
+            return Ok(());
+        }
         debug!(
             "Writing {} username update events to db",
             update_events.len()
         );
-        let trx = self.sqlite.begin().await?;
-        for update_event in update_events {
-            self.write_to_db(update_event).await?;
+        let renames = submit_username_batch(&self.sqlite, update_events).await?;
+        for rename in renames {
+            info!("{}", rename);
+            if self.config.alert_renames {
+                self.alerting.info(&rename.to_string());
+            }
         }
-
-        trx.commit().await?;
-        Ok(())
-    }
-
-    async fn write_to_db(&mut self, update_event: &UsernameUpdateEvent) -> Result<()> {
-        let timestamp_unix = update_event.timestamp.timestamp();
-        sqlx::query(
-            r#"
-              INSERT OR REPLACE INTO name_changes(username, twitch_id, last_seen)
-              VALUES (?, ?, ?);
-            "#,
-        )
-        .bind(&update_event.username)
-        .bind(&update_event.id)
-        .bind(timestamp_unix)
-        .execute(&self.sqlite)
-        .await?;
-        // let filename = date.format("%Y-%m-%d").to_string() + ".txt";
-        // let path = Path::new(&self.config.path).join(&channel).join(&filename);
-        // if !self.file_queues.contains_key(channel) {
-        //     tokio::fs::create_dir_all(path.parent().unwrap()).await?;
-        // }
-        // let queue = self
-        //     .file_queues
-        //     .entry(channel.to_string())
-        //     .or_insert_with(|| {
-        //         QueuedAppender::new(channel.to_string(), 50, Duration::from_secs(5))
-        //     });
-        // queue.write(path, line.to_string()).await?;
-
         Ok(())
     }
 
-    fn get_username_updates(&self, evt: TwitchEvent) -> Vec<UsernameUpdateEvent> {
-        let mut ues: Vec<UsernameUpdateEvent> = Vec::new();
-        match evt {
-            TwitchEvent::Privmsg(msg) => ues.push(UsernameUpdateEvent {
+    fn get_username_updates(&self, evt: TwitchEvent) -> Vec<UsernameUpdate> {
+        let mut ues: Vec<UsernameUpdate> = Vec::new();
+        match evt.message {
+            TwitchMessage::Privmsg(msg) => ues.push(UsernameUpdate {
                 id: msg.sender.id,
                 username: msg.sender.login.trim().to_string(),
                 timestamp: msg.server_timestamp,
             }),
-            TwitchEvent::UserNotice(notice) => match notice.event {
+            TwitchMessage::UserNotice(notice) => match notice.event {
                 UserNoticeEvent::SubGift {
                     is_sender_anonymous,
                     recipient,
                     ..
                 } => {
                     if !is_sender_anonymous {
-                        ues.push(UsernameUpdateEvent {
+                        ues.push(UsernameUpdate {
                             id: notice.sender.id,
                             username: notice.sender.login.trim().to_string(),
                             timestamp: notice.server_timestamp,
                         })
                     }
-                    ues.push(UsernameUpdateEvent {
+                    ues.push(UsernameUpdate {
                         id: recipient.id,
                         username: recipient.login,
                         timestamp: notice.server_timestamp,
@@ -162,7 +229,7 @@ impl UsernameWorker {
                 | UserNoticeEvent::Ritual { .. }
                 | UserNoticeEvent::SubOrResub { .. }
                 | UserNoticeEvent::BitsBadgeTier { .. }
-                | UserNoticeEvent::GiftPaidUpgrade { .. } => ues.push(UsernameUpdateEvent {
+                | UserNoticeEvent::GiftPaidUpgrade { .. } => ues.push(UsernameUpdate {
                     id: notice.sender.id,
                     username: notice.sender.login.trim().to_string(),
                     timestamp: notice.server_timestamp,
@@ -175,18 +242,108 @@ impl UsernameWorker {
     }
 }
 
-pub async fn init_tables(pool: &SqlitePool) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        r#"
-          CREATE TABLE IF NOT EXISTS name_changes (
-            username TEXT NOT NULL,
-            twitch_id TEXT NOT NULL,
-            last_seen INTEGER NOT NULL,
-            PRIMARY KEY(username, twitch_id)
-          );
-      "#,
-    )
-    .execute(pool)
-    .await?;
-    Ok(())
+struct DggUserWorker {
+    sqlite: SqlitePool,
+    config: Arc<UsernameTrackerSettings>,
+    rx: Receiver<WorkerMessage<Vec<DggUserSighting>>>,
+    queue: Vec<DggUserSighting>,
+    /// When the oldest sighting that's still queued came in
+    oldest: Option<Instant>,
+    /// Set after a failed write, until then only the flush check tries again
+    failing: bool,
+}
+
+impl DggUserWorker {
+    fn spawn(
+        config: Arc<UsernameTrackerSettings>,
+        rx: Receiver<WorkerMessage<Vec<DggUserSighting>>>,
+        sqlite: SqlitePool,
+    ) {
+        let worker = DggUserWorker {
+            config,
+            rx,
+            sqlite,
+            queue: Vec::new(),
+            oldest: None,
+            failing: false,
+        };
+        tokio::spawn(worker.run());
+    }
+
+    async fn run(mut self) {
+        if let Err(error) = init_dgg_user_tables(&self.sqlite).await {
+            error!(
+                "Couldn't initialize sqlite tables for dgg users: {:?}",
+                error
+            );
+            return;
+        }
+        let batch_size = self.config.batch_size as usize;
+        let batch_period = Duration::from_secs(self.config.batch_period_seconds);
+        let mut flush_check = tokio::time::interval(FLUSH_CHECK_PERIOD);
+        loop {
+            tokio::select! {
+                message = self.rx.recv() => match message {
+                    Some(WorkerMessage::Item(mut sightings)) => {
+                        self.queue.append(&mut sightings);
+                        self.oldest.get_or_insert_with(Instant::now);
+                        if self.queue.len() >= batch_size && !self.failing {
+                            self.write_queue().await;
+                        }
+                    }
+                    Some(WorkerMessage::Flush(done)) => {
+                        if self.write_queue().await {
+                            let _ = done.send(());
+                        }
+                    }
+                    None => break,
+                },
+                _ = flush_check.tick() => {
+                    if matches!(self.oldest, Some(oldest) if oldest.elapsed() >= batch_period) {
+                        self.write_queue().await;
+                    }
+                }
+            }
+        }
+        // The tracker is gone, write out what's left
+        self.write_queue().await;
+    }
+
+    /// Writes out the queue, returns whether that worked. What couldn't be written stays queued,
+    /// up to `MAX_QUEUED_BATCHES` batches.
+    async fn write_queue(&mut self) -> bool {
+        match self.process(&self.queue).await {
+            Ok(()) => {
+                self.queue.clear();
+                self.oldest = None;
+                self.failing = false;
+                return true;
+            }
+            Err(error) => error!("Error writing dgg users to disk: {:?}", error),
+        }
+        self.failing = true;
+        let max_queued = (self.config.batch_size as usize).max(1) * MAX_QUEUED_BATCHES;
+        if self.queue.len() > max_queued {
+            let dropped = self.queue.len() - max_queued;
+            self.queue.drain(..dropped);
+            error!(
+                "Dropped the {} oldest dgg user sightings, sqlite is still failing",
+                dropped
+            );
+        }
+        false
+    }
+
+    async fn process(&self, sightings: &[DggUserSighting]) -> Result<()> {
+        if sightings.is_empty() {
+            return Ok(());
+        }
+        let changes = submit_dgg_user_batch(&self.sqlite, sightings).await?;
+        debug!(
+            "Wrote {} dgg user sightings to db, {} new users or changes",
+            sightings.len(),
+            changes
+        );
+        Ok(())
+    }
 }
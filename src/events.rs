@@ -1,16 +1,629 @@
-use std::fmt;
+use std::{fmt, time::Duration};
 
 use chrono::{DateTime, Utc};
 use colored::Colorize;
 use derive_more::From;
+use humantime::format_duration;
+use serde::{Deserialize, Serialize};
+use strum::AsRefStr;
+use twitch_irc::message::AsRawIRC;
 use voca_rs::*;
 
-use crate::scrapers::{dgg::DggEvent, twitch::events::TwitchEvent};
+use crate::{
+    formats::{
+        orl::{CleanOrlLog, OrlLog, Raw, RawOrlLog},
+        unified::{ChannelType, CommonKey, SimpleLog1_0},
+    },
+    scrapers::{dgg::DggEvent, twitch::events::TwitchEvent},
+};
 
 #[derive(Clone, Debug, From)]
 pub enum AllEvents {
     Dgg(DggEvent),
     Twitch(TwitchEvent),
+    Raw(RawEvent),
+}
+
+/// Coarse kinds of events, for deciding which writers get what.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventCategory {
+    Message,
+    /// Subscriptions, gifts, raids and other platform notices
+    Notice,
+    Moderation,
+    Broadcast,
+    /// Users joining or leaving chat
+    Presence,
+    Raw,
+}
+
+impl AllEvents {
+    pub fn category(&self) -> EventCategory {
+        match self {
+            AllEvents::Dgg(event) => event.category(),
+            AllEvents::Twitch(event) => event.category(),
+            AllEvents::Raw(_) => EventCategory::Raw,
+        }
+    }
+
+    pub fn platform(&self) -> ChannelType {
+        match self {
+            AllEvents::Dgg(_) => ChannelType::Dgg,
+            AllEvents::Twitch(_) => ChannelType::Twitch,
+            AllEvents::Raw(raw) => raw.platform,
+        }
+    }
+
+    pub fn channel(&self) -> Option<&str> {
+        match self {
+            AllEvents::Dgg(event) => Some(event.channel()),
+            AllEvents::Twitch(event) => Some(event.channel_login()),
+            AllEvents::Raw(raw) => raw.channel.as_deref(),
+        }
+    }
+
+    /// When the scraper received the event
+    pub fn received_at(&self) -> DateTime<Utc> {
+        match self {
+            AllEvents::Dgg(event) => event.received_at(),
+            AllEvents::Twitch(event) => event.received_at,
+            AllEvents::Raw(raw) => raw.received_at,
+        }
+    }
+
+    /// The event in the form it was received from the platform, for archiving and replays.
+    pub fn to_raw(&self) -> RawEvent {
+        match self {
+            AllEvents::Dgg(event) => RawEvent {
+                platform: ChannelType::Dgg,
+                channel: Some(event.channel().to_string()),
+                received_at: event.received_at(),
+                data: event.raw().to_string(),
+            },
+            AllEvents::Twitch(event) => RawEvent {
+                platform: ChannelType::Twitch,
+                channel: Some(event.channel_login().to_string()),
+                received_at: event.received_at,
+                data: event.source().as_raw_irc(),
+            },
+            AllEvents::Raw(raw) => raw.clone(),
+        }
+    }
+}
+
+/// A platform message that doesn't map to any typed event, like twitch's `ROOMSTATE` or dgg
+/// frames that failed to parse. These are only forwarded so that raw archives and captures contain
+/// everything the scrapers received.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RawEvent {
+    pub platform: ChannelType,
+    pub channel: Option<String>,
+    pub received_at: DateTime<Utc>,
+    /// The message as it was received, e.g. an IRC line without the trailing CRLF. dgg frames are
+    /// kept byte for byte, twitch lines are serialized again from the parsed message, so their
+    /// tags can be in a different order.
+    pub data: String,
+}
+
+/// A chat user as seen by the scrapers. Twitch users always carry an id and display name, dgg
+/// users only have their nick.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventUser {
+    pub id: Option<String>,
+    pub login: String,
+    pub display_name: Option<String>,
+}
+
+impl EventUser {
+    pub fn from_login(login: impl Into<String>) -> Self {
+        EventUser {
+            id: None,
+            login: login.into(),
+            display_name: None,
+        }
+    }
+}
+
+/// A platform agnostic chat event, keeping all of the structured data that the scrapers have
+/// about it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Event {
+    pub platform: ChannelType,
+    pub channel: String,
+    pub channel_id: Option<String>,
+    /// The platform's own id for the message that caused this event, when there is one
+    pub id: Option<String>,
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// `as_ref` gives the same name as the serialized `kind` tag
+#[derive(Clone, Debug, AsRefStr, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum EventKind {
+    Message(ChatMessage),
+    Subscription(Subscription),
+    GiftSub(GiftSub),
+    MassGift(MassGift),
+    GiftUpgrade(GiftUpgrade),
+    Bits(Bits),
+    BitsBadge(BitsBadge),
+    Ritual(Ritual),
+    Raid(Raid),
+    Host(Host),
+    Ban(Ban),
+    Timeout(Timeout),
+    Unban(Unban),
+    Untimeout(Unban),
+    ChatCleared,
+    Broadcast(Broadcast),
+    /// Platform generated text describing an event, like twitch's `system-msg`
+    Notice(Notice),
+    Donation(Donation),
+    PollStart(Poll),
+    PollEnd(Poll),
+    Pin(Pin),
+    /// The pinned message was taken down
+    Unpin,
+    SubOnly(SubOnly),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub user: EventUser,
+    pub text: String,
+    pub is_action: bool,
+    /// Twitch badge names or dgg flairs
+    pub badges: Vec<String>,
+    pub is_subscriber: bool,
+    pub subscriber_months: Option<u64>,
+    pub color: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubTier {
+    Prime,
+    Tier1,
+    Tier2,
+    Tier3,
+    Other(String),
+}
+
+impl From<&str> for SubTier {
+    fn from(sub_plan: &str) -> Self {
+        match sub_plan {
+            "Prime" => SubTier::Prime,
+            "1000" => SubTier::Tier1,
+            "2000" => SubTier::Tier2,
+            "3000" => SubTier::Tier3,
+            _ => SubTier::Other(sub_plan.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for SubTier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let res = match self {
+            SubTier::Prime => "Prime",
+            SubTier::Tier1 => "Tier 1",
+            SubTier::Tier2 => "Tier 2",
+            SubTier::Tier3 => "Tier 3",
+            SubTier::Other(plan) => plan,
+        };
+        write!(f, "{}", res)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Subscription {
+    pub user: EventUser,
+    pub tier: SubTier,
+    pub is_resub: bool,
+    pub cumulative_months: u64,
+    pub streak_months: Option<u64>,
+    pub message: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GiftSub {
+    /// For anonymous gifts this is twitch's placeholder gifter
+    pub gifter: EventUser,
+    pub is_anonymous: bool,
+    pub recipient: EventUser,
+    pub tier: SubTier,
+    pub gifted_months: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MassGift {
+    pub gifter: EventUser,
+    pub is_anonymous: bool,
+    pub count: u64,
+    pub tier: SubTier,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GiftUpgrade {
+    pub user: EventUser,
+    /// Missing when the original gift was anonymous
+    pub gifter: Option<EventUser>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Bits {
+    pub user: EventUser,
+    pub amount: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BitsBadge {
+    pub user: EventUser,
+    pub threshold: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ritual {
+    pub user: EventUser,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Raid {
+    pub raider: EventUser,
+    pub viewer_count: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Host {
+    pub hosting_channel: String,
+    pub viewer_count: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ban {
+    pub target: EventUser,
+    /// Only known on platforms that tell us who did it
+    pub moderator: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Timeout {
+    pub target: EventUser,
+    pub moderator: Option<String>,
+    pub duration_seconds: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Unban {
+    pub target: EventUser,
+    pub moderator: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Broadcast {
+    pub text: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Notice {
+    pub text: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Donation {
+    pub user: EventUser,
+    /// dgg donations are in USD
+    pub amount_cents: u64,
+    pub message: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PollOption {
+    pub text: String,
+    pub votes: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Poll {
+    pub author: Option<String>,
+    pub question: String,
+    /// Vote counts are as of when the event was sent, so they're final for `PollEnd`
+    pub options: Vec<PollOption>,
+    pub is_weighted: bool,
+    pub duration_seconds: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Pin {
+    /// The user who wrote the pinned message
+    pub user: EventUser,
+    pub text: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SubOnly {
+    pub is_enabled: bool,
+    pub moderator: Option<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct EventGroup(pub Vec<Event>);
+
+impl From<Event> for EventGroup {
+    fn from(event: Event) -> Self {
+        EventGroup(vec![event])
+    }
+}
+
+impl From<Option<Event>> for EventGroup {
+    fn from(opt: Option<Event>) -> Self {
+        EventGroup(opt.into_iter().collect())
+    }
+}
+
+impl From<AllEvents> for EventGroup {
+    fn from(event: AllEvents) -> Self {
+        use AllEvents::*;
+        match event {
+            Dgg(e) => e.into(),
+            Twitch(e) => e.into(),
+            Raw(_) => EventGroup::default(),
+        }
+    }
+}
+
+impl Event {
+    /// Every user the event is about, like the sender of a message or the target of a ban.
+    pub fn users(&self) -> Vec<&EventUser> {
+        use EventKind::*;
+        match &self.kind {
+            Message(msg) => vec![&msg.user],
+            Subscription(sub) => vec![&sub.user],
+            GiftSub(gift) => vec![&gift.gifter, &gift.recipient],
+            MassGift(gift) => vec![&gift.gifter],
+            GiftUpgrade(upgrade) => upgrade.gifter.iter().chain([&upgrade.user]).collect(),
+            Bits(bits) => vec![&bits.user],
+            BitsBadge(badge) => vec![&badge.user],
+            Ritual(ritual) => vec![&ritual.user],
+            Raid(raid) => vec![&raid.raider],
+            Ban(ban) => vec![&ban.target],
+            Timeout(timeout) => vec![&timeout.target],
+            Unban(unban) | Untimeout(unban) => vec![&unban.target],
+            Donation(donation) => vec![&donation.user],
+            Pin(pin) => vec![&pin.user],
+            Host(_) | ChatCleared | Broadcast(_) | Notice(_) | PollStart(_) | PollEnd(_)
+            | Unpin | SubOnly(_) => Vec::new(),
+        }
+    }
+
+    /// Text written by a user, as opposed to text generated by the platform
+    pub fn user_text(&self) -> Option<&str> {
+        match &self.kind {
+            EventKind::Message(msg) => Some(&msg.text),
+            EventKind::Subscription(sub) => sub.message.as_deref(),
+            EventKind::Donation(donation) => donation.message.as_deref(),
+            EventKind::Pin(pin) => Some(&pin.text),
+            _ => None,
+        }
+    }
+
+    /// Renders the event the way it's written in ORL text logs, with system events showing up as
+    /// pseudo-users like `@subscriber`. Events that have no ORL representation return `None`.
+    pub fn to_simple_message(&self) -> Option<SimpleMessage> {
+        use EventKind::*;
+
+        let (username, text) = match &self.kind {
+            Message(msg) => (Usernames::Normal(msg.user.login.clone()), msg.text.clone()),
+            Subscription(sub) => (
+                Usernames::Subscriber,
+                format!(
+                    "{} just {} {} {} for {} months!{}",
+                    sub.user.login,
+                    if sub.is_resub {
+                        "resubscribed"
+                    } else {
+                        "subscribed"
+                    },
+                    if sub.tier == SubTier::Prime {
+                        "with"
+                    } else {
+                        "at"
+                    },
+                    sub.tier,
+                    sub.cumulative_months,
+                    if let Some(text) = &sub.message {
+                        format!(" Message: {}", text)
+                    } else {
+                        "".to_string()
+                    }
+                ),
+            ),
+            GiftSub(gift) => (
+                Usernames::Subscriber,
+                format!(
+                    "{} gifted a {} sub to {}!",
+                    gift.gifter.login, gift.tier, gift.recipient.login
+                ),
+            ),
+            MassGift(gift) => (
+                Usernames::GiftSub,
+                format!(
+                    "{} gifted {} {} subs to the community!",
+                    gift.gifter.login, gift.count, gift.tier
+                ),
+            ),
+            GiftUpgrade(upgrade) => (
+                Usernames::Subscriber,
+                match &upgrade.gifter {
+                    Some(gifter) => format!(
+                        "{} is continuing their gifted sub from {}",
+                        upgrade.user.login,
+                        gifter.display_name.as_ref().unwrap_or(&gifter.login)
+                    ),
+                    None => format!(
+                        "{} is continuing their anonymous gifted sub!",
+                        upgrade.user.login
+                    ),
+                },
+            ),
+            Bits(bits) => (
+                Usernames::Bits,
+                format!(
+                    "{} donated {} bits to the channel!",
+                    bits.user.login, bits.amount
+                ),
+            ),
+            Raid(raid) => (
+                Usernames::Raid,
+                format!(
+                    "{} just raided the channel with {} viewers!",
+                    raid.raider.login, raid.viewer_count
+                ),
+            ),
+            Host(host) => (
+                Usernames::Host,
+                format!(
+                    "{} just raided the channel with {} viewers!",
+                    host.hosting_channel,
+                    host.viewer_count.unwrap_or(0)
+                ),
+            ),
+            Ban(ban) => (
+                Usernames::Moderation,
+                match &ban.moderator {
+                    Some(moderator) => format!("{} banned {}", moderator, ban.target.login),
+                    None => format!("{} permanently banned", ban.target.login),
+                },
+            ),
+            Timeout(timeout) => (
+                Usernames::Moderation,
+                match &timeout.moderator {
+                    Some(moderator) => format!("{} muted {}", moderator, timeout.target.login),
+                    None => format!(
+                        "{} timed out for {}",
+                        timeout.target.login,
+                        format_duration(Duration::from_secs(timeout.duration_seconds.unwrap_or(0)))
+                    ),
+                },
+            ),
+            Unban(unban) => (
+                Usernames::Moderation,
+                format!(
+                    "{} unbanned {}",
+                    unban.moderator.as_deref().unwrap_or("Someone"),
+                    unban.target.login
+                ),
+            ),
+            Untimeout(unban) => (
+                Usernames::Moderation,
+                format!(
+                    "{} unmuted {}",
+                    unban.moderator.as_deref().unwrap_or("Someone"),
+                    unban.target.login
+                ),
+            ),
+            Broadcast(broadcast) => (Usernames::System, broadcast.text.clone()),
+            Notice(notice) => (Usernames::System, notice.text.clone()),
+            Donation(donation) => (
+                Usernames::Donation,
+                format!(
+                    "{} donated ${}.{:02}!{}",
+                    donation.user.login,
+                    donation.amount_cents / 100,
+                    donation.amount_cents % 100,
+                    if let Some(text) = &donation.message {
+                        format!(" Message: {}", text)
+                    } else {
+                        "".to_string()
+                    }
+                ),
+            ),
+            PollStart(poll) => (
+                Usernames::System,
+                format!(
+                    "{} started a poll: {} ({})",
+                    poll.author.as_deref().unwrap_or("Someone"),
+                    poll.question,
+                    poll.options
+                        .iter()
+                        .map(|option| option.text.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            ),
+            PollEnd(poll) => (
+                Usernames::System,
+                format!(
+                    "Poll ended: {} {}",
+                    poll.question,
+                    poll.options
+                        .iter()
+                        .map(|option| format!("{}: {}", option.text, option.votes))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            ),
+            Pin(pin) => (
+                Usernames::System,
+                format!("{} pinned a message: {}", pin.user.login, pin.text),
+            ),
+            Unpin => (
+                Usernames::System,
+                "The pinned message was removed".to_string(),
+            ),
+            SubOnly(sub_only) => (
+                Usernames::System,
+                format!(
+                    "{} {} subscriber only mode",
+                    sub_only.moderator.as_deref().unwrap_or("Someone"),
+                    if sub_only.is_enabled {
+                        "enabled"
+                    } else {
+                        "disabled"
+                    }
+                ),
+            ),
+            BitsBadge(_) | Ritual(_) | ChatCleared => return None,
+        };
+
+        Some(SimpleMessage {
+            id: self.id.clone(),
+            channel: self.channel.clone(),
+            timestamp: self.timestamp,
+            username,
+            text,
+        })
+    }
+
+    /// Converts the event into a unified `simple-log/1.0` record. The key is the same one the
+    /// ORL importers produce, so live and imported copies of a message dedupe against each other.
+    pub fn to_simple_log(&self, source: &str) -> Option<SimpleLog1_0> {
+        let orl_log = self.to_simple_message()?.to_orl_log();
+        let key = CommonKey {
+            id: orl_log.get_id(),
+            timestamp: orl_log.get_unix_millis(),
+        };
+        let (user_id, display_name) = match &self.kind {
+            EventKind::Message(msg) => (msg.user.id.clone(), msg.user.display_name.clone()),
+            _ => (None, None),
+        };
+
+        Some(SimpleLog1_0 {
+            message_id: self.id.clone().unwrap_or_else(|| key.id.clone()),
+            key,
+            channel_type: self.platform,
+            user_id,
+            username: orl_log.username,
+            display_name,
+            channel_name: orl_log.channel,
+            text: orl_log.text,
+            source: Some(source.to_string()),
+        })
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -23,6 +636,16 @@ pub struct SimpleMessage {
 }
 
 impl SimpleMessage {
+    /// The normalized ORL form of the message, which is what message ids are derived from.
+    pub fn to_orl_log(&self) -> CleanOrlLog {
+        let raw = RawOrlLog {
+            ts: self.timestamp,
+            username: self.username.to_string(),
+            text: self.text.clone(),
+        };
+        OrlLog::<Raw>::from_raw(raw, &self.channel).normalize()
+    }
+
     pub fn normalize(&self) -> Self {
         let mut username = self.username.clone();
         if let Usernames::Normal(s) = username {
@@ -57,13 +680,21 @@ impl From<Option<SimpleMessage>> for SimpleMessageGroup {
     }
 }
 
+impl From<EventGroup> for SimpleMessageGroup {
+    fn from(events: EventGroup) -> Self {
+        SimpleMessageGroup(
+            events
+                .0
+                .iter()
+                .filter_map(Event::to_simple_message)
+                .collect(),
+        )
+    }
+}
+
 impl From<AllEvents> for SimpleMessageGroup {
     fn from(event: AllEvents) -> Self {
-        use AllEvents::*;
-        match event {
-            Dgg(e) => e.into(),
-            Twitch(e) => e.into(),
-        }
+        EventGroup::from(event).into()
     }
 }
 
@@ -72,6 +703,7 @@ pub enum Usernames {
     Normal(String),
     System,
     Bits,
+    Donation,
     Subscriber,
     GiftSub,
     Raid,
@@ -85,6 +717,7 @@ impl fmt::Display for Usernames {
             Usernames::Normal(u) => u.trim(),
             Usernames::System => "@system",
             Usernames::Bits => "@bits",
+            Usernames::Donation => "@donation",
             Usernames::Subscriber => "@subscriber",
             Usernames::GiftSub => "@giftsub",
             Usernames::Raid => "@raid",
@@ -103,6 +736,7 @@ impl From<Usernames> for colored::ColoredString {
             Usernames::Normal(u) => u.trim().bright_blue(),
             Usernames::System => s.bright_yellow(),
             Usernames::Bits => s.bold(),
+            Usernames::Donation => s.bold(),
             Usernames::Subscriber => s.bright_green(),
             Usernames::GiftSub => s.bright_magenta(),
             Usernames::Raid => s.bright_cyan(),
@@ -111,3 +745,169 @@ impl From<Usernames> for colored::ColoredString {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn twitch_event(kind: EventKind) -> Event {
+        Event {
+            platform: ChannelType::Twitch,
+            channel: "xqcow".into(),
+            channel_id: Some("71092938".into()),
+            id: Some("abc".into()),
+            timestamp: Utc.timestamp_millis_opt(1628035200095).unwrap(),
+            kind,
+        }
+    }
+
+    fn render(kind: EventKind) -> (String, String) {
+        let msg = twitch_event(kind).to_simple_message().unwrap();
+        (msg.username.to_string(), msg.text)
+    }
+
+    #[test]
+    fn test_render_subscription() {
+        let sub = Subscription {
+            user: EventUser::from_login("zakwern"),
+            tier: SubTier::from("Prime"),
+            is_resub: false,
+            cumulative_months: 1,
+            streak_months: None,
+            message: None,
+        };
+        assert_eq!(
+            render(EventKind::Subscription(sub.clone())),
+            (
+                "@subscriber".into(),
+                "zakwern just subscribed with Prime for 1 months!".into()
+            )
+        );
+
+        let resub = Subscription {
+            tier: SubTier::from("2000"),
+            is_resub: true,
+            cumulative_months: 14,
+            message: Some("hello".into()),
+            ..sub
+        };
+        assert_eq!(
+            render(EventKind::Subscription(resub)).1,
+            "zakwern just resubscribed at Tier 2 for 14 months! Message: hello"
+        );
+    }
+
+    #[test]
+    fn test_render_gifts() {
+        let gift = GiftSub {
+            gifter: EventUser::from_login("gifter"),
+            is_anonymous: false,
+            recipient: EventUser::from_login("lucky"),
+            tier: SubTier::Tier1,
+            gifted_months: 1,
+        };
+        assert_eq!(
+            render(EventKind::GiftSub(gift)),
+            (
+                "@subscriber".into(),
+                "gifter gifted a Tier 1 sub to lucky!".into()
+            )
+        );
+
+        let mass_gift = MassGift {
+            gifter: EventUser::from_login("gifter"),
+            is_anonymous: false,
+            count: 50,
+            tier: SubTier::Tier3,
+        };
+        assert_eq!(
+            render(EventKind::MassGift(mass_gift)),
+            (
+                "@giftsub".into(),
+                "gifter gifted 50 Tier 3 subs to the community!".into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_render_moderation() {
+        let timeout = Timeout {
+            target: EventUser::from_login("spammer"),
+            moderator: None,
+            duration_seconds: Some(600),
+        };
+        assert_eq!(
+            render(EventKind::Timeout(timeout)),
+            ("@moderation".into(), "spammer timed out for 10m".into())
+        );
+
+        let ban = Ban {
+            target: EventUser::from_login("spammer"),
+            moderator: Some("Bot".into()),
+        };
+        assert_eq!(render(EventKind::Ban(ban)).1, "Bot banned spammer");
+    }
+
+    #[test]
+    fn test_events_without_orl_representation() {
+        assert!(twitch_event(EventKind::ChatCleared)
+            .to_simple_message()
+            .is_none());
+    }
+
+    #[test]
+    fn test_simple_log_matches_orl_id() {
+        let mut event = twitch_event(EventKind::Message(ChatMessage {
+            user: EventUser {
+                id: Some("1234".into()),
+                login: "Zakwern".into(),
+                display_name: Some("ZAKWERN".into()),
+            },
+            text: " hello\nworld ".into(),
+            is_action: false,
+            badges: vec![],
+            is_subscriber: false,
+            subscriber_months: None,
+            color: None,
+        }));
+        let log = event.to_simple_log("tl2/live").unwrap();
+        assert_eq!(log.key.timestamp, 1628035200095);
+        assert!(log.key.id.starts_with("1628035200095-"));
+        assert_eq!(log.message_id, "abc");
+        assert_eq!(log.username, "zakwern");
+        assert_eq!(log.channel_name, "Xqcow");
+        assert_eq!(log.text, "hello world");
+        assert_eq!(log.user_id.as_deref(), Some("1234"));
+        assert_eq!(log.display_name.as_deref(), Some("ZAKWERN"));
+
+        let orl_log = OrlLog::<Raw>::from_raw(
+            RawOrlLog {
+                ts: event.timestamp,
+                username: "zakwern".into(),
+                text: "hello world".into(),
+            },
+            "xqcow",
+        )
+        .normalize();
+        assert_eq!(log.key.id, orl_log.get_id());
+
+        event.id = None;
+        let log = event.to_simple_log("tl2/live").unwrap();
+        assert_eq!(log.message_id, log.key.id);
+    }
+
+    #[test]
+    fn test_serialize_event() {
+        let event = twitch_event(EventKind::Bits(Bits {
+            user: EventUser::from_login("cheerer"),
+            amount: 100,
+        }));
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(value["kind"], "bits");
+        assert_eq!(value["platform"], "twitch");
+        assert_eq!(value["amount"], 100);
+        assert_eq!(value["user"]["login"], "cheerer");
+    }
+}
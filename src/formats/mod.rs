@@ -1,2 +1,3 @@
+pub mod moderation;
 pub mod orl;
 pub mod unified;
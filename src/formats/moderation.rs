@@ -0,0 +1,145 @@
+use std::{fmt, time::Duration};
+
+use chrono::{DateTime, Utc};
+use humantime::format_duration;
+use serde::{Deserialize, Serialize};
+use strum::{AsRefStr, EnumString};
+
+use super::unified::ChannelType;
+use crate::events::{AllEvents, Event, EventGroup, EventKind};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, AsRefStr, EnumString, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ModerationAction {
+    Ban,
+    /// A dgg mute or a twitch timeout
+    Timeout,
+    Unban,
+    Untimeout,
+    /// The whole chat was cleared, there's no target
+    Clear,
+}
+
+/// One entry of the moderation log, the same for every platform. Fields a platform doesn't have
+/// are left empty, e.g. the moderator on twitch or user ids on dgg.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModerationLog {
+    pub platform: ChannelType,
+    pub channel: String,
+    pub channel_id: Option<String>,
+    pub action: ModerationAction,
+    pub target: Option<String>,
+    pub target_id: Option<String>,
+    pub moderator: Option<String>,
+    pub duration_seconds: Option<u64>,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl ModerationLog {
+    pub fn from_event(event: &Event) -> Option<Self> {
+        let (action, target, moderator, duration_seconds) = match &event.kind {
+            EventKind::Ban(ban) => (
+                ModerationAction::Ban,
+                Some(&ban.target),
+                &ban.moderator,
+                None,
+            ),
+            EventKind::Timeout(timeout) => (
+                ModerationAction::Timeout,
+                Some(&timeout.target),
+                &timeout.moderator,
+                timeout.duration_seconds,
+            ),
+            EventKind::Unban(unban) => (
+                ModerationAction::Unban,
+                Some(&unban.target),
+                &unban.moderator,
+                None,
+            ),
+            EventKind::Untimeout(unban) => (
+                ModerationAction::Untimeout,
+                Some(&unban.target),
+                &unban.moderator,
+                None,
+            ),
+            EventKind::ChatCleared => (ModerationAction::Clear, None, &None, None),
+            _ => return None,
+        };
+        Some(ModerationLog {
+            platform: event.platform,
+            channel: event.channel.clone(),
+            channel_id: event.channel_id.clone(),
+            action,
+            target: target.map(|t| t.login.clone()),
+            target_id: target.and_then(|t| t.id.clone()),
+            moderator: moderator.clone(),
+            duration_seconds,
+            timestamp: event.timestamp,
+        })
+    }
+
+    pub fn from_events(event: AllEvents) -> Vec<Self> {
+        EventGroup::from(event)
+            .0
+            .iter()
+            .filter_map(ModerationLog::from_event)
+            .collect()
+    }
+}
+
+impl fmt::Display for ModerationLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {}/{} {} {}",
+            self.timestamp.format("%Y-%m-%d %H:%M:%S%.3f %Z"),
+            self.platform.as_ref(),
+            self.channel,
+            self.action.as_ref(),
+            self.target.as_deref().unwrap_or("-"),
+        )?;
+        if let Some(duration) = self.duration_seconds {
+            write!(f, " for {}", format_duration(Duration::from_secs(duration)))?;
+        }
+        if let Some(moderator) = &self.moderator {
+            write!(f, " by {}", moderator)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrapers::dgg::parse_test_frame;
+
+    fn dgg_logs(raw: &str) -> Vec<ModerationLog> {
+        let event = parse_test_frame(raw);
+        ModerationLog::from_events(event.into())
+    }
+
+    #[test]
+    fn test_dgg_mute() {
+        let logs = dgg_logs(
+            r#"MUTE {"data":"spammer","nick":"Bot","timestamp":1628035200095,"duration":600}"#,
+        );
+        assert_eq!(logs.len(), 1);
+        let log = &logs[0];
+        assert_eq!(log.action, ModerationAction::Timeout);
+        assert_eq!(log.target.as_deref(), Some("spammer"));
+        assert_eq!(log.moderator.as_deref(), Some("Bot"));
+        assert_eq!(log.duration_seconds, Some(600));
+        assert_eq!(
+            log.to_string(),
+            "[2021-08-04 00:00:00.095 UTC] dgg/Destinygg timeout spammer for 10m by Bot"
+        );
+
+        let line = serde_json::to_string(log).unwrap();
+        assert_eq!(serde_json::from_str::<ModerationLog>(&line).unwrap(), *log);
+
+        let logs =
+            dgg_logs(r#"MSG {"nick":"bob","features":[],"timestamp":1628035200095,"data":"hi"}"#);
+        assert!(logs.is_empty());
+    }
+}
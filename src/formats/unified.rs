@@ -1,4 +1,5 @@
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Serialize};
+use strum::{AsRefStr, EnumString};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct CommonKey {
@@ -13,11 +14,11 @@ pub struct OrlLog1_0 {
 
     pub username: String,
     pub channel_name: String,
-    pub text: String
+    pub text: String,
 }
 
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, AsRefStr, EnumString, Serialize, Deserialize)]
+#[strum(serialize_all = "snake_case")]
 pub enum ChannelType {
     #[serde(rename = "dgg")]
     Dgg,
@@ -37,7 +38,7 @@ pub struct SimpleLog1_0 {
     pub display_name: Option<String>,
     pub channel_name: String,
     pub text: String,
-    pub source: Option<String>
+    pub source: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -47,10 +48,9 @@ pub enum UnifiedMessageLog {
     OrlLog1_0(OrlLog1_0),
 
     #[serde(rename = "simple-log/1.0")]
-    SimpleLog1_0(SimpleLog1_0)
+    SimpleLog1_0(SimpleLog1_0),
 }
 
-
 // #[cfg(test)]
 // mod tests {
 
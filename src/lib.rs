@@ -2,6 +2,8 @@ pub mod adapters;
 pub mod alerts;
 pub mod events;
 pub mod formats;
+pub mod processing;
+pub mod routing;
 pub mod run_scrape_ingester;
 pub mod scrapers;
 pub mod scripts;
@@ -9,3 +11,6 @@ pub mod settings;
 pub mod sinks;
 pub mod sources;
 pub mod sqlite_pool;
+pub mod supervisor;
+pub mod userlist_scraper;
+pub mod wal;
@@ -9,6 +9,8 @@ pub mod adapters;
 pub mod alerts;
 pub mod events;
 pub mod formats;
+pub mod processing;
+pub mod routing;
 pub mod run_scrape_ingester;
 pub mod scrapers;
 pub mod scripts;
@@ -16,15 +18,20 @@ pub mod settings;
 pub mod sinks;
 pub mod sources;
 pub mod sqlite_pool;
+pub mod supervisor;
+pub mod userlist_scraper;
+pub mod wal;
 
 use clap::Parser;
 use clap::ValueHint;
 use clap::{self};
 use scripts::file_to_sqlite::dir_to_sqlite;
 
+use crate::formats::unified::ChannelType;
 use crate::scripts::file_to_clickhouse::dir_to_clickhouse;
 use crate::scripts::file_to_clickhouse::files_to_clickhouse;
 use crate::scripts::file_to_elasticsearch::dir_to_elasticsearch;
+use crate::sinks::sqlite::moderation::ModerationQuery;
 
 #[derive(Parser, Debug)]
 #[clap(name = "tl2")]
@@ -48,10 +55,59 @@ enum Opt {
         files: Vec<String>,
     }, */
     /// Run twitch/dgg scraper and ingest based on config files
-    Scrape {},
+    Scrape {
+        /// Also record every received event to a capture file, for use with `replay`. Events are
+        /// recorded after processing, so dropped and redacted content stays that way
+        #[clap(long, value_hint = ValueHint::FilePath)]
+        record: Option<PathBuf>,
+    },
+    /// Create the elasticsearch templates, lifecycle policies and indices of the configured
+    /// writers ahead of time
+    Init,
+    /// Look up bans, timeouts and their reversals kept by a `moderation_log` writer
+    Moderation {
+        /// Only actions against this user
+        #[clap(short, long, required_unless_present = "channel")]
+        user: Option<String>,
+
+        /// Only actions in this channel
+        #[clap(short, long)]
+        channel: Option<String>,
+
+        /// Only actions on this platform, dgg or twitch
+        #[clap(short, long)]
+        platform: Option<ChannelType>,
+
+        /// How many of the most recent actions to show
+        #[clap(short, long, default_value = "100")]
+        limit: u32,
+
+        /// Sqlite database of the moderation_log writer
+        #[clap(long, default_value = "./data/sql/main.db", value_hint = ValueHint::FilePath)]
+        sqlite_path: PathBuf,
+    },
+    /// Show every login a twitch user has used, as kept by a `username_tracker` writer
+    UserHistory {
+        /// A current or past login, or a twitch user id
+        name_or_id: String,
+
+        /// Sqlite database of the username_tracker writer
+        #[clap(long, default_value = "./data/sql/main.db", value_hint = ValueHint::FilePath)]
+        sqlite_path: PathBuf,
+    },
+    /// Push a capture recorded with `scrape --record` through the configured writers
+    Replay {
+        /// Capture file, one raw event per line
+        #[clap(value_hint = ValueHint::FilePath)]
+        capture: PathBuf,
+
+        /// Playback speed relative to the recorded timing, 0 replays as fast as possible
+        #[clap(short, long, default_value = "1")]
+        speed: f64,
+    },
     /// Ingest structured directory with ORL-formatted files to clickhouse
     DirToClickhouse {
-        /// Directory with file structure: <root>/<Channel name>/<YYYY-MM-DD>.txt(.gz)
+        /// Directory with file structure: <root>/<Channel name>/<YYYY-MM-DD>.txt(.gz|.zst|.br)
         #[clap(value_hint = ValueHint::DirPath)]
         directory: PathBuf,
 
@@ -61,7 +117,7 @@ enum Opt {
     },
     /// Ingest ORL-formatted file(s) to clickhouse
     FileToClickhouse {
-        /// Files with ORL-formatted logs, optionally ending in .gz for gzip compression
+        /// Files with ORL-formatted logs, optionally compressed with .gz, .zst or .br
         #[clap(required = true, min_values = 1, value_hint = ValueHint::FilePath)]
         files: Vec<PathBuf>,
 
@@ -75,7 +131,7 @@ enum Opt {
     },
 
     DirToElasticsearch {
-        /// Directory with file structure: <root>/<Channel name>/<YYYY-MM-DD>.txt(.gz)
+        /// Directory with file structure: <root>/<Channel name>/<YYYY-MM-DD>.txt(.gz|.zst|.br)
         #[clap(value_hint = ValueHint::DirPath)]
         directory: PathBuf,
 
@@ -89,12 +145,12 @@ enum Opt {
     },
 
     DirToSqlite {
-        /// Directory with file structure: <root>/<Channel name>/<YYYY-MM-DD>.txt(.gz)
+        /// Directory with file structure: <root>/<Channel name>/<YYYY-MM-DD>.txt(.gz|.zst|.br)
         #[clap(value_hint = ValueHint::DirPath)]
         directory: PathBuf,
     },
     DirToJsonl {
-        /// Directory with file structure: <root>/<Channel name>/<YYYY-MM-DD>.txt(.gz)
+        /// Directory with file structure: <root>/<Channel name>/<YYYY-MM-DD>.txt(.gz|.zst|.br)
         #[clap(value_hint = ValueHint::DirPath)]
         directory: PathBuf,
 
@@ -103,13 +159,20 @@ enum Opt {
         output_directory: PathBuf,
     },
 
+    /// Re-parse raw twitch IRC archives and print the resulting messages
+    TwitchRawToConsole {
+        /// Directory with file structure: <root>/<channel>/<YYYY-MM-DD>.irc.gz
+        #[clap(value_hint = ValueHint::DirPath)]
+        directory: PathBuf,
+    },
+
     JsonlToConsole {
-        /// Directory with file structure: <root>/<Channel name>/<YYYY-MM-DD>.jsonl(.gz|.br)
+        /// Directory with file structure: <root>/<Channel name>/<YYYY-MM-DD>.jsonl(.gz|.zst|.br)
         #[clap(value_hint = ValueHint::DirPath)]
         directory: PathBuf,
     },
     JsonlToElasticsearch {
-        /// Directory with file structure: <root>/<Channel name>/<YYYY-MM-DD>.jsonl(.gz|.br)
+        /// Directory with file structure: <root>/<Channel name>/<YYYY-MM-DD>.jsonl(.gz|.zst|.br)
         #[clap(value_hint = ValueHint::DirPath)]
         directory: PathBuf,
 
@@ -122,7 +185,7 @@ enum Opt {
         index: String,
     },
     JsonlToClickhouse {
-        /// Directory with file structure: <root>/<Channel name>/<YYYY-MM-DD>.jsonl(.gz|.br)
+        /// Directory with file structure: <root>/<Channel name>/<YYYY-MM-DD>.jsonl(.gz|.zst|.br)
         #[clap(value_hint = ValueHint::DirPath)]
         directory: PathBuf,
 
@@ -138,8 +201,46 @@ async fn main() {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
     match cli_opts {
-        Opt::Scrape {} => {
-            if let Err(e) = run_ingester().await {
+        Opt::Scrape { record } => {
+            if let Err(e) = run_ingester(record).await {
+                error!("{:?}", e);
+            }
+        }
+        Opt::Init => {
+            if let Err(e) = scripts::init().await {
+                error!("{:?}", e);
+            }
+        }
+        Opt::Moderation {
+            user,
+            channel,
+            platform,
+            limit,
+            sqlite_path,
+        } => {
+            let query = ModerationQuery {
+                target: user,
+                channel,
+                platform,
+                limit,
+            };
+            if let Err(e) = scripts::moderation(sqlite_path, query).await {
+                error!("{:?}", e);
+            }
+        }
+        Opt::UserHistory {
+            name_or_id,
+            sqlite_path,
+        } => {
+            if let Err(e) = scripts::user_history(sqlite_path, &name_or_id).await {
+                error!("{:?}", e);
+            }
+        }
+        Opt::Replay { capture, speed } => {
+            info!("Capture: {:?}", capture);
+            info!("Speed: {:?}", speed);
+
+            if let Err(e) = scripts::replay(capture, speed).await {
                 error!("{:?}", e);
             }
         }
@@ -192,6 +293,13 @@ async fn main() {
                 error!("{:?}", e);
             }
         }
+        Opt::TwitchRawToConsole { directory } => {
+            info!("Directory: {:?}", directory);
+
+            if let Err(e) = scripts::twitch_raw_to_console(directory).await {
+                error!("{:?}", e);
+            }
+        }
         Opt::JsonlToConsole { directory } => {
             info!("Directory: {:?}", directory);
 
@@ -0,0 +1,429 @@
+use std::{borrow::Cow, collections::HashSet};
+
+use anyhow::{Context, Result};
+use log::info;
+use regex::{Regex, RegexSet};
+use twitch_irc::message::{AsRawIRC, IRCMessage, IRCPrefix};
+
+use crate::{
+    events::{AllEvents, EventGroup, RawEvent},
+    formats::unified::ChannelType,
+    scrapers::{dgg::map_frame_text, twitch::events::map_irc_text},
+    settings::ProcessingSettings,
+    userlist_scraper::PresenceChange,
+};
+
+const EMAIL_PATTERN: &str = r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}";
+const PHONE_NUMBER_PATTERN: &str =
+    r"(?:\+\d{1,3}[-.\s]?)?(?:\(\d{3}\)|\b\d{3})[-.\s]?\d{3}[-.\s]?\d{4}\b";
+const IPV4_PATTERN: &str = r"\b(?:(?:25[0-5]|2[0-4]\d|1?\d?\d)\.){3}(?:25[0-5]|2[0-4]\d|1?\d?\d)\b";
+const IPV6_PATTERN: &str = concat!(
+    r"\b(?:[0-9A-Fa-f]{1,4}:){7}[0-9A-Fa-f]{1,4}\b",
+    "|",
+    r"\b(?:[0-9A-Fa-f]{1,4}:){1,6}:(?:[0-9A-Fa-f]{1,4}:){0,5}[0-9A-Fa-f]{1,4}\b",
+);
+
+/// Twitch commands whose second parameter is text written by a user
+const TWITCH_TEXT_COMMANDS: [&str; 4] = ["PRIVMSG", "USERNOTICE", "NOTICE", "WHISPER"];
+
+/// Filters and redacts events before any writer sees them, to honor takedown and privacy
+/// requests at ingest time.
+pub struct Processor {
+    channels: HashSet<String>,
+    users: HashSet<String>,
+    drop_patterns: RegexSet,
+    redactions: Vec<Regex>,
+    replacement: String,
+    strip_control_chars: bool,
+}
+
+impl Processor {
+    pub async fn new(settings: ProcessingSettings) -> Result<Processor> {
+        let mut users: HashSet<String> = settings
+            .drop
+            .users
+            .iter()
+            .map(|u| u.trim().to_lowercase())
+            .collect();
+        for path in &settings.opt_out_lists {
+            let contents = tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("Reading opt-out list '{}' failed", path))?;
+            let before = users.len();
+            users.extend(parse_opt_out_list(&contents));
+            info!(
+                "Loaded {} opted out users from '{}'",
+                users.len() - before,
+                path
+            );
+        }
+
+        let drop_patterns = RegexSet::new(&settings.drop.patterns)
+            .with_context(|| "Invalid drop pattern in processing config")?;
+
+        let redact = &settings.redact;
+        let mut redaction_patterns: Vec<&str> = Vec::new();
+        if redact.emails {
+            redaction_patterns.push(EMAIL_PATTERN);
+        }
+        if redact.phone_numbers {
+            redaction_patterns.push(PHONE_NUMBER_PATTERN);
+        }
+        if redact.ip_addresses {
+            redaction_patterns.push(IPV4_PATTERN);
+            redaction_patterns.push(IPV6_PATTERN);
+        }
+        redaction_patterns.extend(redact.patterns.iter().map(|p| p.as_str()));
+        let redactions = redaction_patterns
+            .into_iter()
+            .map(|p| Regex::new(p).with_context(|| format!("Invalid redaction pattern '{}'", p)))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Processor {
+            channels: settings
+                .drop
+                .channels
+                .iter()
+                .map(|c| c.trim().to_lowercase())
+                .collect(),
+            users,
+            drop_patterns,
+            redactions,
+            replacement: settings.redact.replacement,
+            strip_control_chars: settings.strip_control_chars,
+        })
+    }
+
+    /// Returns the event with its text cleaned up, or `None` if it shouldn't be written at all.
+    pub fn process(&self, mut event: AllEvents) -> Option<AllEvents> {
+        if self.should_drop(&event) {
+            return None;
+        }
+        if !self.users.is_empty() {
+            let keep = |login: &str| !self.users.contains(&login.to_lowercase());
+            match &mut event {
+                AllEvents::Twitch(e) => e.retain_names(keep),
+                AllEvents::Dgg(e) => e.retain_names(keep),
+                AllEvents::Raw(_) => {}
+            }
+        }
+        if self.strip_control_chars || !self.redactions.is_empty() {
+            let clean = |text: &str| self.clean_text(text);
+            match &mut event {
+                AllEvents::Twitch(e) => e.map_text(clean),
+                AllEvents::Dgg(e) => e.map_text(clean),
+                AllEvents::Raw(raw) => map_raw_text(raw, clean),
+            }
+        }
+        Some(event)
+    }
+
+    fn should_drop(&self, event: &AllEvents) -> bool {
+        if let Some(channel) = event.channel() {
+            if self.channels.contains(&channel.to_lowercase()) {
+                return true;
+            }
+        }
+
+        if self.users.is_empty() && self.drop_patterns.is_empty() {
+            return false;
+        }
+        // Presence events carry no event group, their user is checked on its own
+        let presence = match event {
+            AllEvents::Raw(raw) => {
+                return raw_user(raw)
+                    .map_or(false, |user| self.users.contains(&user.to_lowercase()));
+            }
+            AllEvents::Dgg(e) => e.presence(),
+            AllEvents::Twitch(e) => e.presence(),
+        };
+        match presence {
+            Some(PresenceChange::Join(user)) | Some(PresenceChange::Leave(user)) => {
+                return self.users.contains(&user.to_lowercase());
+            }
+            // Filtered users are taken out of the list instead
+            Some(PresenceChange::Names { .. }) => return false,
+            None => {}
+        }
+        EventGroup::from(event.clone()).0.iter().any(|e| {
+            let opted_out = e.users().iter().any(|user| {
+                self.users.contains(&user.login.to_lowercase())
+                    || user.id.as_ref().map_or(false, |id| self.users.contains(id))
+            });
+            opted_out
+                || e.user_text()
+                    .map_or(false, |t| self.drop_patterns.is_match(t))
+        })
+    }
+
+    fn clean_text(&self, text: &str) -> String {
+        let mut text = Cow::Borrowed(text);
+        if self.strip_control_chars && text.chars().any(char::is_control) {
+            text = Cow::Owned(text.chars().filter(|c| !c.is_control()).collect());
+        }
+        for redaction in &self.redactions {
+            if let Cow::Owned(redacted) = redaction.replace_all(&text, self.replacement.as_str()) {
+                text = Cow::Owned(redacted);
+            }
+        }
+        text.into_owned()
+    }
+}
+
+fn parse_opt_out_list(contents: &str) -> impl Iterator<Item = String> + '_ {
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_lowercase())
+}
+
+/// The sender of a raw event, if the platform format has one
+fn raw_user(raw: &RawEvent) -> Option<String> {
+    match raw.platform {
+        ChannelType::Twitch => match IRCMessage::parse(&raw.data).ok()?.prefix? {
+            IRCPrefix::Full { nick, .. } => Some(nick),
+            IRCPrefix::HostOnly { .. } => None,
+        },
+        ChannelType::Dgg => {
+            let (_, body) = raw.data.split_once(' ')?;
+            let value: serde_json::Value = serde_json::from_str(body).ok()?;
+            value.get("nick")?.as_str().map(|nick| nick.to_string())
+        }
+    }
+}
+
+/// Raw events are only rewritten when their text changes, so archives keep the exact bytes the
+/// platform sent otherwise
+fn map_raw_text(raw: &mut RawEvent, f: impl Fn(&str) -> String) {
+    match raw.platform {
+        ChannelType::Twitch => match IRCMessage::parse(&raw.data) {
+            Ok(mut message) => {
+                if TWITCH_TEXT_COMMANDS.contains(&message.command.as_str())
+                    && map_irc_text(&mut message, f)
+                {
+                    raw.data = message.as_raw_irc();
+                }
+            }
+            Err(_) => raw.data = f(&raw.data),
+        },
+        ChannelType::Dgg => {
+            if let Some(data) = map_frame_text(&raw.data, f) {
+                raw.data = data;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use twitch_irc::message::{IRCTags, JoinMessage, PartMessage};
+
+    use super::*;
+    use crate::{
+        events::SimpleMessageGroup,
+        scrapers::{
+            dgg::{parse_test_frame, test_dgg_message},
+            twitch::events::{NamesReply, TwitchEvent, TwitchMessage},
+        },
+        settings::{DropSettings, RedactSettings},
+    };
+
+    async fn processor(settings: ProcessingSettings) -> Processor {
+        Processor::new(settings).await.unwrap()
+    }
+
+    fn text_of(event: &AllEvents) -> String {
+        SimpleMessageGroup::from(event.clone()).0[0].text.clone()
+    }
+
+    #[tokio::test]
+    async fn test_redacts_text_and_raw_frame() {
+        let processor = processor(ProcessingSettings {
+            redact: RedactSettings {
+                emails: true,
+                phone_numbers: true,
+                ip_addresses: true,
+                ..RedactSettings::default()
+            },
+            strip_control_chars: true,
+            ..ProcessingSettings::default()
+        })
+        .await;
+
+        let event = processor
+            .process(test_dgg_message(
+                "bob",
+                "mail me at bob@example.com\u{7} or call (555) 123-4567, I'm on 10.0.0.1",
+            ))
+            .unwrap();
+        let expected = "mail me at [redacted] or call [redacted], I'm on [redacted]";
+        assert_eq!(text_of(&event), expected);
+
+        if let AllEvents::Dgg(dgg) = event {
+            assert_eq!(
+                dgg.raw(),
+                format!(
+                    r#"MSG {{"nick":"bob","features":[],"timestamp":1628035200095,"data":"{}"}}"#,
+                    expected
+                )
+            );
+        } else {
+            panic!("Expected a dgg event");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keeps_ordinary_text() {
+        let processor = processor(ProcessingSettings {
+            redact: RedactSettings {
+                emails: true,
+                phone_numbers: true,
+                ip_addresses: true,
+                ..RedactSettings::default()
+            },
+            ..ProcessingSettings::default()
+        })
+        .await;
+
+        let text = "version 1.2.3 at 12:30, 1000 viewers @bob";
+        let event = processor.process(test_dgg_message("bob", text)).unwrap();
+        assert_eq!(text_of(&event), text);
+        // The frame is kept exactly as it was received
+        match (event, test_dgg_message("bob", text)) {
+            (AllEvents::Dgg(processed), AllEvents::Dgg(original)) => {
+                assert_eq!(processed.raw(), original.raw())
+            }
+            _ => panic!("Expected dgg events"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drops_users_channels_and_patterns() {
+        let processor = processor(ProcessingSettings {
+            drop: DropSettings {
+                channels: vec!["Somechannel".into()],
+                users: vec!["Bob".into()],
+                patterns: vec![r"(?i)\bspam\b".into()],
+            },
+            ..ProcessingSettings::default()
+        })
+        .await;
+
+        assert!(processor.process(test_dgg_message("bob", "hi")).is_none());
+        assert!(processor
+            .process(test_dgg_message("alice", "SPAM"))
+            .is_none());
+        assert!(processor.process(test_dgg_message("alice", "hi")).is_some());
+
+        let raw = RawEvent {
+            platform: ChannelType::Dgg,
+            channel: Some("somechannel".into()),
+            received_at: chrono::Utc::now(),
+            data: "PING {}".into(),
+        };
+        assert!(processor.process(raw.clone().into()).is_none());
+
+        let raw = RawEvent {
+            channel: Some("Destinygg".into()),
+            data: r#"PRIVMSG {"nick":"BOB","data":"hi"}"#.into(),
+            ..raw
+        };
+        assert!(processor.process(raw.into()).is_none());
+    }
+
+    async fn opt_out_bob() -> Processor {
+        processor(ProcessingSettings {
+            drop: DropSettings {
+                users: vec!["Bob".into()],
+                ..DropSettings::default()
+            },
+            ..ProcessingSettings::default()
+        })
+        .await
+    }
+
+    fn presence_users(event: &AllEvents) -> Vec<String> {
+        let presence = match event {
+            AllEvents::Dgg(e) => e.presence(),
+            AllEvents::Twitch(e) => e.presence(),
+            AllEvents::Raw(_) => None,
+        };
+        match presence {
+            Some(PresenceChange::Names { users, .. }) => {
+                users.into_iter().map(str::to_string).collect()
+            }
+            _ => panic!("Expected a names list"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drops_opted_out_dgg_presence() {
+        let processor = opt_out_bob().await;
+        for raw in [
+            r#"JOIN {"nick":"Bob","features":[],"timestamp":1628035200095}"#,
+            r#"QUIT {"nick":"Bob","features":[],"timestamp":1628035200095}"#,
+        ] {
+            assert!(processor.process(parse_test_frame(raw).into()).is_none());
+        }
+        let join = r#"JOIN {"nick":"alice","features":[],"timestamp":1628035200095}"#;
+        assert!(processor.process(parse_test_frame(join).into()).is_some());
+
+        let names = concat!(
+            r#"NAMES {"connectioncount":30,"users":"#,
+            r#"[{"nick":"alice","features":[]},{"nick":"Bob","features":[]}]}"#,
+        );
+        let event = processor.process(parse_test_frame(names).into()).unwrap();
+        assert_eq!(presence_users(&event), vec!["alice"]);
+        match event {
+            AllEvents::Dgg(dgg) => assert!(!dgg.raw().contains("Bob")),
+            _ => panic!("Expected a dgg event"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drops_opted_out_twitch_presence() {
+        let processor = opt_out_bob().await;
+        let source = |command: &str, params: &[&str]| IRCMessage {
+            tags: IRCTags(Default::default()),
+            prefix: Some(IRCPrefix::HostOnly {
+                host: "tmi.twitch.tv".into(),
+            }),
+            command: command.into(),
+            params: params.iter().map(|p| p.to_string()).collect(),
+        };
+        let join = |user_login: &str| {
+            let join = TwitchMessage::Join(JoinMessage {
+                channel_login: "destiny".into(),
+                user_login: user_login.into(),
+                source: source("JOIN", &["#destiny"]),
+            });
+            AllEvents::from(TwitchEvent::new(join, chrono::Utc::now()))
+        };
+        assert!(processor.process(join("bob")).is_none());
+        assert!(processor.process(join("alice")).is_some());
+        let part = TwitchMessage::Part(PartMessage {
+            channel_login: "destiny".into(),
+            user_login: "bob".into(),
+            source: source("PART", &["#destiny"]),
+        });
+        let part = TwitchEvent::new(part, chrono::Utc::now());
+        assert!(processor.process(part.into()).is_none());
+
+        let names = source("353", &["justinfan123", "=", "#destiny", "alice bob carol"]);
+        let names = TwitchMessage::Names(NamesReply::parse(&names).unwrap());
+        let names = TwitchEvent::new(names, chrono::Utc::now());
+        let event = processor.process(names.into()).unwrap();
+        assert_eq!(presence_users(&event), vec!["alice", "carol"]);
+        match event {
+            AllEvents::Twitch(twitch) => assert_eq!(twitch.source().params[3], "alice carol"),
+            _ => panic!("Expected a twitch event"),
+        }
+    }
+
+    #[test]
+    fn test_parse_opt_out_list() {
+        let users: Vec<String> = parse_opt_out_list("# takedowns\nBob\n\n  12345 \n").collect();
+        assert_eq!(users, vec!["bob", "12345"]);
+    }
+}
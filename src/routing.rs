@@ -0,0 +1,149 @@
+use std::{collections::HashSet, hash::Hash};
+
+use crate::{
+    events::{AllEvents, EventCategory},
+    formats::unified::ChannelType,
+    settings::{IncludeExclude, RouteSettings},
+};
+
+#[derive(Clone, Debug)]
+struct Filter<T: Eq + Hash> {
+    include: HashSet<T>,
+    exclude: HashSet<T>,
+}
+
+impl<T: Eq + Hash + Clone> Filter<T> {
+    fn new(settings: &IncludeExclude<T>) -> Self {
+        Filter {
+            include: settings.include.iter().cloned().collect(),
+            exclude: settings.exclude.iter().cloned().collect(),
+        }
+    }
+
+    fn allows(&self, value: &T) -> bool {
+        (self.include.is_empty() || self.include.contains(value)) && !self.exclude.contains(value)
+    }
+}
+
+/// Decides which events a writer receives, built from its `routes` settings.
+#[derive(Clone, Debug)]
+pub struct Route {
+    channels: Filter<String>,
+    platforms: Filter<ChannelType>,
+    kinds: Filter<EventCategory>,
+}
+
+impl Route {
+    pub fn new(settings: &RouteSettings) -> Route {
+        let channels = IncludeExclude {
+            include: lowercase(&settings.channels.include),
+            exclude: lowercase(&settings.channels.exclude),
+        };
+        Route {
+            channels: Filter::new(&channels),
+            platforms: Filter::new(&settings.platforms),
+            kinds: Filter::new(&settings.kinds),
+        }
+    }
+
+    pub fn matches(&self, event: &AllEvents) -> bool {
+        let channel_allowed = match event.channel() {
+            Some(channel) => self.channels.allows(&channel.to_lowercase()),
+            // Server-wide messages aren't part of any channel, so only an include list stops them
+            None => self.channels.include.is_empty(),
+        };
+        channel_allowed
+            && self.platforms.allows(&event.platform())
+            && self.kinds.allows(&event.category())
+    }
+}
+
+impl Default for Route {
+    fn default() -> Self {
+        Route::new(&RouteSettings::default())
+    }
+}
+
+fn lowercase(values: &[String]) -> Vec<String> {
+    values.iter().map(|v| v.trim().to_lowercase()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrapers::dgg::parse_test_frame;
+
+    fn dgg_event(raw: &str) -> AllEvents {
+        parse_test_frame(raw).into()
+    }
+
+    #[test]
+    fn test_route_matches() {
+        let message =
+            dgg_event(r#"MSG {"nick":"bob","features":[],"timestamp":1628035200095,"data":"hi"}"#);
+        let join = dgg_event(r#"JOIN {"nick":"bob","features":[],"timestamp":1628035200095}"#);
+
+        assert!(Route::default().matches(&message));
+
+        let only_dgg = Route::new(&RouteSettings {
+            platforms: IncludeExclude {
+                include: vec![ChannelType::Dgg],
+                exclude: vec![],
+            },
+            ..RouteSettings::default()
+        });
+        assert!(only_dgg.matches(&message));
+
+        let only_twitch = Route::new(&RouteSettings {
+            platforms: IncludeExclude {
+                include: vec![ChannelType::Twitch],
+                exclude: vec![],
+            },
+            ..RouteSettings::default()
+        });
+        assert!(!only_twitch.matches(&message));
+
+        let no_presence = Route::new(&RouteSettings {
+            kinds: IncludeExclude {
+                include: vec![],
+                exclude: vec![EventCategory::Presence],
+            },
+            ..RouteSettings::default()
+        });
+        assert!(no_presence.matches(&message));
+        assert!(!no_presence.matches(&join));
+
+        let other_channel = Route::new(&RouteSettings {
+            channels: IncludeExclude {
+                include: vec!["xQcOW".into()],
+                exclude: vec![],
+            },
+            ..RouteSettings::default()
+        });
+        assert!(!other_channel.matches(&message));
+
+        let excluded_channel = Route::new(&RouteSettings {
+            channels: IncludeExclude {
+                include: vec![],
+                exclude: vec!["destinygg".into()],
+            },
+            ..RouteSettings::default()
+        });
+        assert!(!excluded_channel.matches(&message));
+    }
+
+    #[test]
+    fn test_deserialize_routes() {
+        let settings: RouteSettings = serde_json::from_value(serde_json::json!({
+            "platforms": { "include": ["dgg"] },
+            "kinds": { "exclude": ["presence", "raw"] }
+        }))
+        .unwrap();
+        assert_eq!(settings.platforms.include, vec![ChannelType::Dgg]);
+        assert_eq!(
+            settings.kinds.exclude,
+            vec![EventCategory::Presence, EventCategory::Raw]
+        );
+        assert!(settings.channels.include.is_empty());
+    }
+}
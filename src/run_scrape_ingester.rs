@@ -1,60 +1,162 @@
-use log::{error, info, warn};
-use tokio::sync::mpsc;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use futures::future::join_all;
+use log::{error, info};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
 
 use crate::{
     adapters::{
-        clickhouse::ClickhouseWriter, console::ConsoleWriter,
-        console_metrics::ConsoleMetricsWriter, elasticsearch::ElasticsearchWriter,
-        file::FileWriter, username_tracker::UsernameTracker, Writer, Writers,
+        capture::CaptureWriter,
+        clickhouse::ClickhouseWriter,
+        console::ConsoleWriter,
+        console_metrics::ConsoleMetricsWriter,
+        elasticsearch::ElasticsearchWriter,
+        file::{FileFormat, FileWriter},
+        moderation_log::ModerationLogWriter,
+        presence::PresenceWriter,
+        username_tracker::UsernameTracker,
+        RoutedWriter, Writers,
     },
     alerts::DiscordAlerting,
     events::AllEvents,
+    processing::Processor,
     scrapers::{dgg::DggScraper, twitch::TwitchScraper},
-    settings::Settings,
+    settings::{
+        CaptureSettings, QueueSettings, RestartSettings, RouteSettings, Settings, WalSettings,
+        WriterKindSettings, WriterSettings,
+    },
     sqlite_pool::create_sqlite,
+    supervisor::{SupervisedWriter, BLOCK_RETRY_DELAY},
+    wal::WorkerInput,
 };
 
-pub async fn run_ingester() -> Result<(), anyhow::Error> {
-    let settings = Settings::new()?;
+/// How often the queue depth and dropped events of every writer are logged
+const METRICS_PERIOD: Duration = Duration::from_secs(60);
 
-    info!("Logger initialized!");
+/// Events on their way from the scrapers to the writers. Once it's full the scrapers wait, which
+/// is how a blocking writer holds up ingestion without buffering without bounds.
+const INGEST_CHANNEL_CAPACITY: usize = 10_000;
 
-    let alerting = DiscordAlerting::new(settings.discord_alerting);
+/// Creates every writer instance that's enabled in the config, along with its routes.
+pub async fn create_writers(
+    settings: Vec<WriterSettings>,
+    alerting: &Arc<DiscordAlerting>,
+) -> Result<Vec<RoutedWriter>> {
+    validate_writer_names(&settings)?;
 
-    alerting.info("Starting TL2");
-    let mut writers: Vec<Option<Writers>> = Vec::new();
-    if settings.writers.elasticsearch.enabled {
-        writers.push(Some(
-            ElasticsearchWriter::new(settings.writers.elasticsearch, alerting.clone())?.into(),
-        ));
-    }
-    if settings.writers.filesystem.enabled {
-        writers.push(Some(FileWriter::new(settings.writers.filesystem).into()));
-    }
-    if settings.writers.console.enabled {
-        writers.push(Some(ConsoleWriter::new().into()));
+    let mut writers: Vec<RoutedWriter> = Vec::new();
+    for config in settings.iter().filter(|config| config.enabled) {
+        let writer = create_writer(config, alerting).await?;
+        writers.push(RoutedWriter::new(&config.name, writer, &config.routes));
     }
+    Ok(writers)
+}
 
-    if settings.writers.console_metrics.enabled {
-        writers.push(Some(ConsoleMetricsWriter::new().into()))
+pub fn validate_writer_names(settings: &[WriterSettings]) -> Result<()> {
+    let mut names = HashSet::new();
+    for config in settings {
+        if config.name.trim().is_empty() {
+            bail!("Writer names can't be empty");
+        }
+        if !names.insert(config.name.as_str()) {
+            bail!("Writer name '{}' is used more than once", config.name);
+        }
     }
+    Ok(())
+}
 
-    if settings.writers.clickhouse.enabled {
-        writers.push(Some(
-            ClickhouseWriter::new(settings.writers.clickhouse, alerting.clone()).into(),
-        ))
-    }
+/// Creates a single writer instance, also used to restart it after it failed.
+pub async fn create_writer(
+    config: &WriterSettings,
+    alerting: &Arc<DiscordAlerting>,
+) -> Result<Writers> {
+    let wal_directory = match &config.kind {
+        _ if !config.wal.enabled => None,
+        WriterKindSettings::Elasticsearch(_) | WriterKindSettings::Clickhouse(_) => {
+            Some(Path::new(&config.wal.directory).join(&config.name))
+        }
+        _ => bail!(
+            "Writer '{}' doesn't support a write-ahead log, only elasticsearch and clickhouse do",
+            config.name
+        ),
+    };
+    let segment_bytes = config.wal.segment_size_mb * 1024 * 1024;
+
+    let writer: Writers = match config.kind.clone() {
+        WriterKindSettings::Elasticsearch(es) => {
+            let (tx, input) = WorkerInput::new(wal_directory, segment_bytes).await?;
+            ElasticsearchWriter::new(es, tx, input, alerting.clone())?.into()
+        }
+        WriterKindSettings::Clickhouse(clickhouse) => {
+            let (tx, input) = WorkerInput::new(wal_directory, segment_bytes).await?;
+            ClickhouseWriter::new(clickhouse, tx, input, alerting.clone()).into()
+        }
+        WriterKindSettings::Filesystem(file) => FileWriter::new(file, FileFormat::Orl).into(),
+        WriterKindSettings::Jsonl(file) => FileWriter::new(file, FileFormat::Jsonl).into(),
+        WriterKindSettings::ModerationJsonl(file) => {
+            FileWriter::new(file, FileFormat::ModerationJsonl).into()
+        }
+        WriterKindSettings::TwitchRaw(file) => FileWriter::new(file, FileFormat::TwitchIrc).into(),
+        WriterKindSettings::Console => ConsoleWriter::new().into(),
+        WriterKindSettings::ConsoleMetrics => ConsoleMetricsWriter::new().into(),
+        WriterKindSettings::UsernameTracker(tracker) => {
+            let sqlite = create_sqlite(&tracker.sqlite_path).await?;
+            UsernameTracker::new(tracker, sqlite, alerting.clone()).into()
+        }
+        WriterKindSettings::ModerationLog(moderation) => {
+            let sqlite = create_sqlite(&moderation.sqlite_path).await?;
+            ModerationLogWriter::new(moderation, sqlite).into()
+        }
+        WriterKindSettings::Presence(presence) => PresenceWriter::new(presence).await?.into(),
+        WriterKindSettings::Capture(capture) => CaptureWriter::new(PathBuf::from(capture.path))
+            .await?
+            .into(),
+    };
+    info!("Created writer '{}'", config.name);
+    Ok(writer)
+}
+
+pub async fn run_ingester(record: Option<PathBuf>) -> Result<(), anyhow::Error> {
+    let settings = Settings::new()?;
+
+    info!("Logger initialized!");
 
-    if settings.writers.username_tracker.enabled {
-        let sqlite = create_sqlite(&settings.writers.username_tracker.sqlite_path).await?;
-        writers.push(Some(
-            UsernameTracker::new(settings.writers.username_tracker, sqlite).into(),
-        ))
+    let alerting = DiscordAlerting::new(settings.discord_alerting);
+
+    alerting.info("Starting TL2");
+    let processor = Processor::new(settings.processing).await?;
+    let mut writer_settings = settings.writers;
+    if let Some(path) = record {
+        writer_settings.push(WriterSettings {
+            name: "capture".into(),
+            enabled: true,
+            routes: RouteSettings::default(),
+            restart: RestartSettings::default(),
+            queue: QueueSettings::default(),
+            wal: WalSettings::default(),
+            kind: WriterKindSettings::Capture(CaptureSettings {
+                path: path.to_string_lossy().into(),
+            }),
+        });
+    }
+    validate_writer_names(&writer_settings)?;
+    let mut writers = Vec::new();
+    for config in writer_settings.into_iter().filter(|config| config.enabled) {
+        writers.push(SupervisedWriter::start(config, alerting.clone()).await?);
     }
 
-    let (event_sender, mut event_receiver) = mpsc::unbounded_channel::<AllEvents>();
+    let (event_sender, mut event_receiver) = mpsc::channel::<AllEvents>(INGEST_CHANNEL_CAPACITY);
     if settings.twitch.enabled {
-        TwitchScraper::start(event_sender.clone(), settings.twitch.clone());
+        TwitchScraper::start(event_sender.clone(), settings.twitch.clone()).await?;
         // scraper.sync_channels().await;
     }
 
@@ -66,22 +168,110 @@ pub async fn run_ingester() -> Result<(), anyhow::Error> {
         );
     }
 
-    while let Some(message) = event_receiver.recv().await {
-        let mut to_remove = Vec::new();
-        for (i, writer) in writers.iter().enumerate() {
-            if let Some(writer) = writer {
-                if let Err(e) = writer.write(message.clone()) {
-                    error!("Error writing message for writer #{}: {:?}", i, e);
-                    to_remove.push(i);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut restart_check = tokio::time::interval(Duration::from_secs(1));
+    let mut last_metrics = Instant::now();
+    loop {
+        let blocked = writers.iter().any(SupervisedWriter::is_blocking);
+        tokio::select! {
+            result = &mut shutdown => {
+                result?;
+                break;
+            }
+            message = event_receiver.recv(), if !blocked => {
+                let message = match message {
+                    Some(message) => message,
+                    None => break,
+                };
+                let message = match processor.process(message) {
+                    Some(message) => message,
+                    None => continue,
+                };
+                for writer in writers.iter_mut() {
+                    writer.write(&message).await;
+                }
+            }
+            // Events pile up in the ingest channel meanwhile, until the scrapers have to wait
+            _ = tokio::time::sleep(BLOCK_RETRY_DELAY), if blocked => {
+                for writer in writers.iter_mut() {
+                    writer.poll().await;
+                }
+            }
+            _ = restart_check.tick() => {
+                for writer in writers.iter_mut() {
+                    writer.poll().await;
+                }
+                if last_metrics.elapsed() >= METRICS_PERIOD {
+                    log_queue_metrics(&writers);
+                    last_metrics = Instant::now();
                 }
             }
         }
-        for i in to_remove {
-            warn!("Removing failing writer #{} from queue", i);
-            alerting.error("Removed a writer from the queue, probably means elasticsearch broke!");
-            writers[i] = None;
+    }
+
+    // Taking what the scrapers already sent counts towards the shutdown timeout too
+    let deadline = Instant::now() + Duration::from_secs(settings.shutdown_timeout_seconds);
+    info!("Shutting down, stopping scrapers");
+    // Scrapers stop once they can't send anymore, what they already sent is still written
+    event_receiver.close();
+    let drain = async {
+        while let Some(message) = event_receiver.recv().await {
+            if let Some(message) = processor.process(message) {
+                for writer in writers.iter_mut() {
+                    writer.write(&message).await;
+                }
+            }
         }
+    };
+    if tokio::time::timeout_at(deadline.into(), drain)
+        .await
+        .is_err()
+    {
+        let mut dropped = 0;
+        while event_receiver.try_recv().is_ok() {
+            dropped += 1;
+        }
+        error!(
+            "Timed out passing events from the scrapers on to the writers, dropped {} events",
+            dropped
+        );
     }
 
+    info!(
+        "Flushing writers, waiting up to {:?}",
+        deadline.saturating_duration_since(Instant::now())
+    );
+    let results = join_all(writers.iter_mut().map(|writer| writer.shutdown(deadline))).await;
+    for (writer, result) in writers.iter().zip(results) {
+        if let Err(e) = result {
+            error!("Shutting down writer '{}' failed: {:#}", writer.name(), e);
+        }
+    }
+    info!("Shutdown complete");
+
     Ok(())
 }
+
+/// Resolves once the process gets SIGINT or SIGTERM
+async fn shutdown_signal() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
+    Ok(())
+}
+
+fn log_queue_metrics(writers: &[SupervisedWriter]) {
+    for writer in writers {
+        let metrics = writer.metrics();
+        info!(
+            "Writer '{}' queue: {} queued, {} spilled, {} dropped",
+            writer.name(),
+            metrics.depth,
+            metrics.spilled,
+            metrics.dropped
+        );
+    }
+}
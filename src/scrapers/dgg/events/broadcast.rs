@@ -1,8 +1,8 @@
 use chrono::{serde::ts_milliseconds, DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-use super::DggAsSimpleMessageGroup;
-use crate::events::{SimpleMessage, Usernames};
+use super::{dgg_event, DggAsEventGroup};
+use crate::events::{self, EventGroup, EventKind};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Broadcast {
@@ -12,15 +12,15 @@ pub struct Broadcast {
     pub timestamp: DateTime<Utc>,
 }
 
-impl DggAsSimpleMessageGroup for Broadcast {
-    fn as_group(&self, channel: String) -> crate::events::SimpleMessageGroup {
-        SimpleMessage {
-            id: None,
+impl DggAsEventGroup for Broadcast {
+    fn as_group(&self, channel: String) -> EventGroup {
+        dgg_event(
             channel,
-            username: Usernames::System,
-            timestamp: self.timestamp,
-            text: self.description.to_string(),
-        }
+            self.timestamp,
+            EventKind::Broadcast(events::Broadcast {
+                text: self.description.to_string(),
+            }),
+        )
         .into()
     }
 }
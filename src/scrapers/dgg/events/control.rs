@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+
+use super::DggAsEventGroup;
+use crate::events::EventGroup;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawError {
+    Described { description: String },
+    Plain(String),
+}
+
+/// An error the chat server sent us, like `throttled` or `needlogin`. Older servers send the bare
+/// description as a JSON string.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "RawError")]
+pub struct ChatError {
+    pub description: String,
+}
+
+impl From<RawError> for ChatError {
+    fn from(raw: RawError) -> Self {
+        let description = match raw {
+            RawError::Described { description } | RawError::Plain(description) => description,
+        };
+        ChatError { description }
+    }
+}
+
+impl DggAsEventGroup for ChatError {
+    fn as_group(&self, _channel: String) -> EventGroup {
+        None.into()
+    }
+}
+
+/// Sent when the server wants clients to drop the connection and connect again, e.g. after the
+/// session changed. Whatever comes with it doesn't matter.
+#[derive(Clone, Debug, Serialize)]
+pub struct Refresh;
+
+impl DggAsEventGroup for Refresh {
+    fn as_group(&self, _channel: String) -> EventGroup {
+        None.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        events::{AllEvents, EventCategory},
+        scrapers::dgg::parse_test_frame,
+    };
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            parse_test_frame(r#"ERR {"description":"throttled"}"#).error(),
+            Some("throttled")
+        );
+        assert_eq!(
+            parse_test_frame(r#"ERR "needlogin""#).error(),
+            Some("needlogin")
+        );
+    }
+
+    #[test]
+    fn test_refresh() {
+        for raw in [r#"REFRESH {"nick":"tl2bot","features":[]}"#, "REFRESH"] {
+            let event = parse_test_frame(raw);
+            assert!(event.is_refresh());
+            assert_eq!(event.raw(), raw);
+            assert_eq!(AllEvents::from(event).category(), EventCategory::Raw);
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{dgg_event, shared::user::User, DggAsEventGroup};
+use crate::events::{self, EventGroup, EventKind};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Donation {
+    pub user: User,
+    /// Cents of USD
+    pub amount: u64,
+    /// The message sent along with the donation, empty when there's none
+    #[serde(default)]
+    pub data: String,
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: DateTime<Utc>,
+}
+
+impl DggAsEventGroup for Donation {
+    fn as_group(&self, channel: String) -> EventGroup {
+        dgg_event(
+            channel,
+            self.timestamp,
+            EventKind::Donation(events::Donation {
+                user: (&self.user).into(),
+                amount_cents: self.amount,
+                message: Some(self.data.clone()).filter(|text| !text.is_empty()),
+            }),
+        )
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        events::{AllEvents, SimpleMessageGroup},
+        scrapers::dgg::parse_test_frame,
+    };
+
+    #[test]
+    fn test_donation() {
+        let raw = concat!(
+            r#"DONATION {"timestamp":1700000000123,"uuid":"0b9d","#,
+            r#""data":"for the stream","user":{"nick":"Bob","features":[]},"amount":1050}"#,
+        );
+        let event = parse_test_frame(raw);
+        let group = SimpleMessageGroup::from(AllEvents::from(event));
+        assert_eq!(group.0[0].username.to_string(), "@donation");
+        assert_eq!(
+            group.0[0].text,
+            "Bob donated $10.50! Message: for the stream"
+        );
+    }
+}
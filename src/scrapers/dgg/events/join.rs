@@ -1,7 +1,7 @@
 use chrono::{serde::ts_milliseconds, DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-use super::{shared::user::User, DggAsSimpleMessageGroup};
+use super::{shared::user::User, DggAsEventGroup};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Join {
@@ -11,8 +11,8 @@ pub struct Join {
     pub timestamp: DateTime<Utc>,
 }
 
-impl DggAsSimpleMessageGroup for Join {
-    fn as_group(&self, _channel: String) -> crate::events::SimpleMessageGroup {
+impl DggAsEventGroup for Join {
+    fn as_group(&self, _channel: String) -> crate::events::EventGroup {
         None.into()
     }
 }
@@ -1,8 +1,8 @@
 use chrono::{serde::ts_milliseconds, DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-use super::{shared::user::User, DggAsSimpleMessageGroup};
-use crate::events::{SimpleMessage, SimpleMessageGroup, Usernames};
+use super::{dgg_event, shared::user::User, DggAsEventGroup};
+use crate::events::{ChatMessage, EventGroup, EventKind};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Message {
@@ -19,15 +19,21 @@ pub struct Message {
     pub timestamp: DateTime<Utc>,
 }
 
-impl DggAsSimpleMessageGroup for Message {
-    fn as_group(&self, channel: String) -> SimpleMessageGroup {
-        SimpleMessage {
-            id: None,
+impl DggAsEventGroup for Message {
+    fn as_group(&self, channel: String) -> EventGroup {
+        dgg_event(
             channel,
-            username: Usernames::Normal(self.user.username.clone()),
-            timestamp: self.timestamp,
-            text: self.text.clone(),
-        }
+            self.timestamp,
+            EventKind::Message(ChatMessage {
+                user: (&self.user).into(),
+                text: self.text.clone(),
+                is_action: self.text.starts_with("/me "),
+                badges: self.user.flairs.iter().map(|f| f.to_string()).collect(),
+                is_subscriber: self.user.is_subscriber,
+                subscriber_months: None,
+                color: None,
+            }),
+        )
         .into()
     }
 }
@@ -1,57 +1,122 @@
 mod broadcast;
+mod control;
+mod donation;
 mod join;
 mod message;
 mod moderation;
 mod names;
+mod pin;
+mod poll;
 mod quit;
 mod shared;
+mod sub_only;
+mod subscription;
 
 pub use broadcast::*;
+pub use control::*;
+pub use donation::*;
 use enum_dispatch::enum_dispatch;
 pub use join::*;
 pub use message::*;
 pub use moderation::*;
 pub use names::*;
+pub use pin::*;
+pub use poll::*;
 pub use quit::*;
 pub use shared::*;
+pub use sub_only::*;
+pub use subscription::*;
 
-use crate::events::SimpleMessageGroup;
+use std::ops::Range;
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    events::{Event, EventCategory, EventGroup, EventKind},
+    formats::unified::ChannelType,
+    userlist_scraper::PresenceChange,
+};
 
 #[enum_dispatch]
 #[derive(Clone, Debug)]
 enum Events {
     Broadcast(Broadcast),
+    ChatError(ChatError),
+    Donation(Donation),
+    GiftSub(GiftSub),
     Join(Join),
+    MassGift(MassGift),
     Message(Message),
     Moderation(Moderation),
     Names(Names),
+    Pin(Pin),
+    Poll(Poll),
     Quit(Quit),
+    Refresh(Refresh),
+    SubOnly(SubOnly),
+    Subscription(Subscription),
+    Vote(Vote),
+}
+
+impl Events {
+    /// The text a user wrote, which dgg always sends as `data`
+    fn user_text_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Events::Message(msg) => Some(&mut msg.text),
+            Events::Subscription(sub) => Some(&mut sub.data),
+            Events::GiftSub(gift) => Some(&mut gift.data),
+            Events::MassGift(gift) => Some(&mut gift.data),
+            Events::Donation(donation) => Some(&mut donation.data),
+            Events::Pin(pin) => Some(&mut pin.data),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct DggEvent {
     channel: String,
     event: Events,
+    /// The websocket frame this event was parsed from
+    raw: String,
+    received_at: DateTime<Utc>,
 }
 
 #[enum_dispatch(Events)]
-trait DggAsSimpleMessageGroup {
-    fn as_group(&self, channel: String) -> SimpleMessageGroup;
+trait DggAsEventGroup {
+    fn as_group(&self, channel: String) -> EventGroup;
 }
 
-impl From<DggEvent> for SimpleMessageGroup {
+/// dgg frames carry no message ids and there's a single channel per site
+fn dgg_event(channel: String, timestamp: DateTime<Utc>, kind: EventKind) -> Event {
+    Event {
+        platform: ChannelType::Dgg,
+        channel,
+        channel_id: None,
+        id: None,
+        timestamp,
+        kind,
+    }
+}
+
+impl From<DggEvent> for EventGroup {
     fn from(dgg_event: DggEvent) -> Self {
         dgg_event.event.as_group(dgg_event.channel)
     }
 }
 
 impl DggEvent {
-    pub fn from_ws(raw: String, channel: String) -> serde_json::Result<Option<DggEvent>> {
-        let split: Vec<&str> = raw.splitn(2, ' ').collect();
-        if split.len() < 2 {
-            return Ok(None);
-        }
-        let (event_type, body) = (split[0], split[1]);
+    pub fn from_ws(
+        raw: String,
+        channel: String,
+        received_at: DateTime<Utc>,
+    ) -> serde_json::Result<Option<DggEvent>> {
+        let (event_type, body) = match raw.split_once(' ') {
+            Some(split) => split,
+            // The only frame that doesn't need a body
+            None if raw == "REFRESH" => (raw.as_str(), ""),
+            None => return Ok(None),
+        };
         let event = match event_type {
             "BROADCAST" => Events::Broadcast(serde_json::from_str(body)?),
             "MSG" => Events::Message(serde_json::from_str(body)?),
@@ -68,9 +133,346 @@ impl DggEvent {
             "NAMES" => Events::Names(serde_json::from_str(body)?),
             "JOIN" => Events::Join(serde_json::from_str(body)?),
             "QUIT" => Events::Quit(serde_json::from_str(body)?),
+            "SUBSCRIPTION" => Events::Subscription(serde_json::from_str(body)?),
+            "GIFTSUB" => Events::GiftSub(serde_json::from_str(body)?),
+            "MASSGIFT" => Events::MassGift(serde_json::from_str(body)?),
+            "DONATION" => Events::Donation(serde_json::from_str(body)?),
+            "POLLSTART" | "POLLSTOP" => {
+                let mut poll: Poll = serde_json::from_str(body)?;
+                poll.is_ended = event_type == "POLLSTOP";
+                Events::Poll(poll)
+            }
+            "VOTECAST" | "VOTECOUNTED" => Events::Vote(serde_json::from_str(body)?),
+            "PIN" => Events::Pin(serde_json::from_str(body)?),
+            "SUBONLY" => Events::SubOnly(serde_json::from_str(body)?),
+            "ERR" => Events::ChatError(serde_json::from_str(body)?),
+            "REFRESH" => Events::Refresh(Refresh),
             _ => return Ok(None),
         };
 
-        Ok(Some(DggEvent { event, channel }))
+        Ok(Some(DggEvent {
+            event,
+            channel,
+            raw,
+            received_at,
+        }))
+    }
+
+    /// Rewrites the user written text of the event, both in the parsed event and in its raw
+    /// frame, so raw archives get the same treatment.
+    pub fn map_text(&mut self, f: impl Fn(&str) -> String) {
+        if let Some(text) = self.event.user_text_mut() {
+            let mapped = f(text);
+            if mapped == *text {
+                return;
+            }
+            *text = mapped;
+            if let Some(raw) = map_frame_text(&self.raw, f) {
+                self.raw = raw;
+            }
+        }
+    }
+
+    /// Removes users from a `NAMES` list, both in the parsed event and in its raw frame
+    pub fn retain_names(&mut self, keep: impl Fn(&str) -> bool) {
+        let names = match &mut self.event {
+            Events::Names(names) => names,
+            _ => return,
+        };
+        let before = names.users.len();
+        names.users.retain(|user| keep(&user.username));
+        if names.users.len() == before {
+            return;
+        }
+        if let Some(raw) = retain_frame_names(&self.raw, keep) {
+            self.raw = raw;
+        }
+    }
+
+    pub fn category(&self) -> EventCategory {
+        match self.event {
+            Events::Broadcast(_) => EventCategory::Broadcast,
+            Events::Message(_) => EventCategory::Message,
+            Events::Moderation(_) => EventCategory::Moderation,
+            Events::Names(_) | Events::Join(_) | Events::Quit(_) => EventCategory::Presence,
+            Events::Subscription(_)
+            | Events::GiftSub(_)
+            | Events::MassGift(_)
+            | Events::Donation(_)
+            | Events::Poll(_)
+            | Events::Vote(_)
+            | Events::Pin(_)
+            | Events::SubOnly(_) => EventCategory::Notice,
+            Events::ChatError(_) | Events::Refresh(_) => EventCategory::Raw,
+        }
+    }
+
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    /// Users the event carries along with their current flairs. Gift recipients and the authors
+    /// of pinned messages are left out, their flairs are partial or from when the pin was written.
+    pub fn users(&self) -> Vec<&user::User> {
+        match &self.event {
+            Events::Message(msg) => vec![&msg.user],
+            Events::Join(join) => vec![&join.user],
+            Events::Quit(quit) => vec![&quit.user],
+            Events::Names(names) => names.users.iter().collect(),
+            Events::Subscription(sub) => vec![&sub.user],
+            Events::GiftSub(gift) => vec![&gift.user],
+            Events::MassGift(gift) => vec![&gift.user],
+            Events::Donation(donation) => vec![&donation.user],
+            Events::Broadcast(_)
+            | Events::Moderation(_)
+            | Events::Pin(_)
+            | Events::Poll(_)
+            | Events::Vote(_)
+            | Events::SubOnly(_)
+            | Events::ChatError(_)
+            | Events::Refresh(_) => Vec::new(),
+        }
+    }
+
+    pub fn presence(&self) -> Option<PresenceChange<'_>> {
+        Some(match &self.event {
+            Events::Names(names) => PresenceChange::Names {
+                users: names.users.iter().map(|u| u.username.as_str()).collect(),
+                connection_count: Some(names.connection_count),
+                is_complete: true,
+            },
+            Events::Join(join) => PresenceChange::Join(&join.user.username),
+            Events::Quit(quit) => PresenceChange::Leave(&quit.user.username),
+            _ => return None,
+        })
+    }
+
+    /// When the event happened, `NAMES`, votes, errors and refreshes don't say
+    pub fn timestamp(&self) -> Option<DateTime<Utc>> {
+        match &self.event {
+            Events::Broadcast(broadcast) => Some(broadcast.timestamp),
+            Events::Message(msg) => Some(msg.timestamp),
+            Events::Moderation(moderation) => Some(moderation.timestamp),
+            Events::Join(join) => Some(join.timestamp),
+            Events::Quit(quit) => Some(quit.timestamp),
+            Events::Subscription(sub) => Some(sub.timestamp),
+            Events::GiftSub(gift) => Some(gift.timestamp),
+            Events::MassGift(gift) => Some(gift.timestamp),
+            Events::Donation(donation) => Some(donation.timestamp),
+            Events::Pin(pin) => Some(pin.timestamp),
+            Events::SubOnly(sub_only) => Some(sub_only.timestamp),
+            Events::Poll(poll) => poll.timestamp(),
+            Events::Names(_) | Events::Vote(_) | Events::ChatError(_) | Events::Refresh(_) => None,
+        }
+    }
+
+    /// The description of an `ERR` frame
+    pub fn error(&self) -> Option<&str> {
+        match &self.event {
+            Events::ChatError(error) => Some(&error.description),
+            _ => None,
+        }
+    }
+
+    pub fn message(&self) -> Option<&Message> {
+        match &self.event {
+            Events::Message(msg) => Some(msg),
+            _ => None,
+        }
+    }
+
+    /// Whether the server asked us to reconnect
+    pub fn is_refresh(&self) -> bool {
+        matches!(self.event, Events::Refresh(_))
+    }
+
+    /// When the scraper received the frame
+    pub fn received_at(&self) -> DateTime<Utc> {
+        self.received_at
+    }
+
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+}
+
+/// Rewrites the `data` field of a websocket frame, returning `None` when the text doesn't change
+/// or the frame can't be parsed. The new string is spliced into the frame as it was received, so
+/// the rest of it stays byte for byte the same.
+pub fn map_frame_text(raw: &str, f: impl Fn(&str) -> String) -> Option<String> {
+    let (event_type, body) = raw.split_once(' ')?;
+    serde_json::from_str::<serde::de::IgnoredAny>(body).ok()?;
+    let span = data_span(body)?;
+    let data: String = serde_json::from_str(&body[span.clone()]).ok()?;
+    let mapped = f(&data);
+    if mapped == data {
+        return None;
+    }
+
+    let offset = event_type.len() + 1;
+    let mut frame = raw.to_string();
+    frame.replace_range(
+        offset + span.start..offset + span.end,
+        &serde_json::to_string(&mapped).ok()?,
+    );
+    Some(frame)
+}
+
+/// Rewrites the `users` of a `NAMES` frame. Unlike text, the whole frame is serialized again.
+fn retain_frame_names(raw: &str, keep: impl Fn(&str) -> bool) -> Option<String> {
+    let (event_type, body) = raw.split_once(' ')?;
+    let mut value: serde_json::Value = serde_json::from_str(body).ok()?;
+    value.get_mut("users")?.as_array_mut()?.retain(|user| {
+        !matches!(user.get("nick").and_then(|nick| nick.as_str()), Some(nick) if !keep(nick))
+    });
+    Some(format!("{} {}", event_type, value))
+}
+
+/// Where the top level `data` string of a JSON object starts and ends, quotes included. Only
+/// called on bodies that are known to be valid JSON.
+fn data_span(body: &str) -> Option<Range<usize>> {
+    let bytes = body.as_bytes();
+    let mut i = skip_whitespace(bytes, 0);
+    if bytes.get(i) != Some(&b'{') {
+        return None;
+    }
+    loop {
+        i = skip_whitespace(bytes, i + 1);
+        if bytes.get(i) != Some(&b'"') {
+            return None;
+        }
+        let key_end = string_end(bytes, i)?;
+        let key: String = serde_json::from_str(&body[i..key_end]).ok()?;
+        i = skip_whitespace(bytes, key_end);
+        if bytes.get(i) != Some(&b':') {
+            return None;
+        }
+        let value_start = skip_whitespace(bytes, i + 1);
+        let value_end = value_end(bytes, value_start)?;
+        if key == "data" && bytes.get(value_start) == Some(&b'"') {
+            return Some(value_start..value_end);
+        }
+        i = skip_whitespace(bytes, value_end);
+        if bytes.get(i) != Some(&b',') {
+            return None;
+        }
+    }
+}
+
+fn skip_whitespace(bytes: &[u8], mut i: usize) -> usize {
+    while matches!(bytes.get(i), Some(b) if b.is_ascii_whitespace()) {
+        i += 1;
+    }
+    i
+}
+
+/// The index right after the closing quote of the string starting at `start`
+fn string_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+    loop {
+        match bytes.get(i)? {
+            b'\\' => i += 2,
+            b'"' => return Some(i + 1),
+            _ => i += 1,
+        }
+    }
+}
+
+/// The index right after the JSON value starting at `start`
+fn value_end(bytes: &[u8], start: usize) -> Option<usize> {
+    match bytes.get(start)? {
+        b'"' => string_end(bytes, start),
+        b'{' | b'[' => {
+            let mut depth = 0;
+            let mut i = start;
+            loop {
+                match bytes.get(i)? {
+                    b'"' => {
+                        i = string_end(bytes, i)?;
+                        continue;
+                    }
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(i + 1);
+                        }
+                    }
+                    _ => {}
+                }
+                i += 1;
+            }
+        }
+        // Numbers, booleans and null
+        _ => {
+            let mut i = start;
+            while let Some(b) = bytes.get(i) {
+                if matches!(b, b',' | b'}' | b']') || b.is_ascii_whitespace() {
+                    break;
+                }
+                i += 1;
+            }
+            Some(i)
+        }
+    }
+}
+
+/// Parses a frame of the Destinygg chat for tests, which only feed it events
+#[cfg(test)]
+pub fn parse_test_frame(raw: &str) -> DggEvent {
+    use chrono::TimeZone;
+
+    let received_at = Utc.timestamp_millis_opt(1628035200095).unwrap();
+    DggEvent::from_ws(raw.to_string(), "Destinygg".into(), received_at)
+        .unwrap()
+        .unwrap()
+}
+
+/// A chat message from `nick` for tests, as it comes out of the scraper
+#[cfg(test)]
+pub fn test_dgg_message(nick: &str, text: &str) -> crate::events::AllEvents {
+    let raw = format!(
+        r#"MSG {{"nick":"{}","features":[],"timestamp":1628035200095,"data":{}}}"#,
+        nick,
+        serde_json::to_string(text).unwrap()
+    );
+    parse_test_frame(&raw).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redact(text: &str) -> String {
+        text.replace("secret", "[redacted]")
+    }
+
+    #[test]
+    fn test_map_frame_text_keeps_the_frame() {
+        let frame = concat!(
+            r#"MSG {"timestamp":1.6e12,"nick":"bob","#,
+            r#""data":"a \"secret\" \u00e9","features":[]}"#,
+        );
+        assert_eq!(
+            map_frame_text(frame, redact).unwrap(),
+            concat!(
+                r#"MSG {"timestamp":1.6e12,"nick":"bob","#,
+                r#""data":"a \"[redacted]\" é","features":[]}"#,
+            )
+        );
+
+        // Nested objects with their own data field and spacing are left alone
+        let frame = r#"MSG { "user" : {"data":"secret"} , "data" : "secret" }"#;
+        assert_eq!(
+            map_frame_text(frame, redact).unwrap(),
+            r#"MSG { "user" : {"data":"secret"} , "data" : "[redacted]" }"#
+        );
+
+        assert_eq!(
+            map_frame_text(r#"MSG {"data":"nothing here"}"#, redact),
+            None
+        );
+        assert_eq!(map_frame_text(r#"MSG {"data":"secret""#, redact), None);
+        assert_eq!(map_frame_text("secret", redact), None);
     }
 }
@@ -1,8 +1,8 @@
 use chrono::{serde::ts_milliseconds, DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-use super::DggAsSimpleMessageGroup;
-use crate::events::{SimpleMessage, Usernames};
+use super::{dgg_event, DggAsEventGroup};
+use crate::events::{Ban, EventGroup, EventKind, EventUser, Timeout, Unban};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RawModeration {
@@ -12,6 +12,9 @@ pub struct RawModeration {
     pub nick: String,
     #[serde(with = "ts_milliseconds")]
     pub timestamp: DateTime<Utc>,
+    /// Seconds a mute lasts, only sent with `MUTE`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
 }
 
 pub struct WithEventType<T> {
@@ -30,6 +33,7 @@ pub struct Moderation {
     /// Milliseconds event timestamp
     #[serde(with = "ts_milliseconds")]
     pub timestamp: DateTime<Utc>,
+    pub duration_seconds: Option<u64>,
 }
 
 impl From<WithEventType<RawModeration>> for Moderation {
@@ -46,29 +50,28 @@ impl From<WithEventType<RawModeration>> for Moderation {
             target: raw.value.data,
             sender: raw.value.nick,
             timestamp: raw.value.timestamp,
+            duration_seconds: raw.value.duration,
         }
     }
 }
 
-impl DggAsSimpleMessageGroup for Moderation {
-    fn as_group(&self, channel: String) -> crate::events::SimpleMessageGroup {
-        if let ModerationTypes::Unknown = self.moderation_type {
-            return None.into();
-        }
+impl DggAsEventGroup for Moderation {
+    fn as_group(&self, channel: String) -> EventGroup {
+        let target = EventUser::from_login(self.target.clone());
+        let moderator = Some(self.sender.clone());
+        let kind = match self.moderation_type {
+            ModerationTypes::Ban => EventKind::Ban(Ban { target, moderator }),
+            ModerationTypes::Unban => EventKind::Unban(Unban { target, moderator }),
+            ModerationTypes::Mute => EventKind::Timeout(Timeout {
+                target,
+                moderator,
+                duration_seconds: self.duration_seconds,
+            }),
+            ModerationTypes::Unmute => EventKind::Untimeout(Unban { target, moderator }),
+            ModerationTypes::Unknown => return None.into(),
+        };
 
-        SimpleMessage {
-            id: None,
-            channel,
-            username: Usernames::Moderation,
-            timestamp: self.timestamp,
-            text: format!(
-                "{} {} {}",
-                self.sender,
-                self.moderation_type.action_verbs(),
-                self.target
-            ),
-        }
-        .into()
+        dgg_event(channel, self.timestamp, kind).into()
     }
 }
 
@@ -86,16 +89,21 @@ pub enum ModerationTypes {
     Unknown,
 }
 
-impl ModerationTypes {
-    fn action_verbs(&self) -> String {
-        use ModerationTypes::*;
-        match self {
-            Ban => "banned",
-            Unban => "unbanned",
-            Mute => "muted",
-            Unmute => "unmuted",
-            Unknown => "did something to",
-        }
-        .to_string()
+#[cfg(test)]
+mod tests {
+    use crate::{
+        events::{AllEvents, SimpleMessageGroup, Usernames},
+        scrapers::dgg::parse_test_frame,
+    };
+
+    #[test]
+    fn test_moderation_as_simple_messages() {
+        let raw = r#"MUTE {"data":"spammer","nick":"Bot","timestamp":1628035200095}"#;
+        let event = parse_test_frame(raw);
+
+        let group = SimpleMessageGroup::from(AllEvents::from(event));
+        assert_eq!(group.0.len(), 1);
+        assert!(matches!(group.0[0].username, Usernames::Moderation));
+        assert_eq!(group.0[0].text, "Bot muted spammer");
     }
 }
@@ -2,7 +2,7 @@ use serde::Deserialize;
 use serde::Serialize;
 
 use super::shared::user::User;
-use super::DggAsSimpleMessageGroup;
+use super::DggAsEventGroup;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Names {
@@ -11,8 +11,8 @@ pub struct Names {
     pub users: Vec<User>,
 }
 
-impl DggAsSimpleMessageGroup for Names {
-    fn as_group(&self, _channel: String) -> crate::events::SimpleMessageGroup {
+impl DggAsEventGroup for Names {
+    fn as_group(&self, _channel: String) -> crate::events::EventGroup {
         None.into()
     }
 }
@@ -0,0 +1,70 @@
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{dgg_event, shared::user::User, DggAsEventGroup};
+use crate::events::{self, EventGroup, EventKind};
+
+/// A message pinned to the top of chat, `data` is empty when the pin is taken down
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Pin {
+    /// The user who wrote the pinned message
+    #[serde(flatten)]
+    pub user: User,
+    #[serde(default)]
+    pub data: String,
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: DateTime<Utc>,
+}
+
+impl DggAsEventGroup for Pin {
+    fn as_group(&self, channel: String) -> EventGroup {
+        let kind = if self.data.is_empty() {
+            EventKind::Unpin
+        } else {
+            EventKind::Pin(events::Pin {
+                user: (&self.user).into(),
+                text: self.data.clone(),
+            })
+        };
+        dgg_event(channel, self.timestamp, kind).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        events::{AllEvents, EventGroup, EventKind, SimpleMessageGroup},
+        scrapers::dgg::parse_test_frame,
+    };
+
+    fn render(raw: &str) -> (String, String) {
+        let event = parse_test_frame(raw);
+        let msg = &SimpleMessageGroup::from(AllEvents::from(event)).0[0];
+        (msg.username.to_string(), msg.text.clone())
+    }
+
+    #[test]
+    fn test_pin_and_unpin() {
+        let pin = concat!(
+            r#"PIN {"nick":"Destiny","features":["flair12","admin"],"#,
+            r#""timestamp":1700000000123,"data":"stream at 5","uuid":"c81e"}"#,
+        );
+        assert_eq!(
+            render(pin),
+            (
+                "@system".to_string(),
+                "Destiny pinned a message: stream at 5".to_string()
+            )
+        );
+
+        let unpin = concat!(
+            r#"PIN {"nick":"Destiny","features":["flair12","admin"],"#,
+            r#""timestamp":1700000060123,"data":"","uuid":"c81e"}"#,
+        );
+        let event = parse_test_frame(unpin);
+        assert!(matches!(
+            EventGroup::from(event).0[0].kind,
+            EventKind::Unpin
+        ));
+    }
+}
@@ -0,0 +1,155 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{dgg_event, DggAsEventGroup};
+use crate::events::{self, EventGroup, EventKind, PollOption};
+
+/// `POLLSTART` and `POLLSTOP` both carry the whole poll
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Poll {
+    /// Who started the poll
+    #[serde(default)]
+    pub nick: Option<String>,
+    pub question: String,
+    pub options: Vec<String>,
+    /// Votes for each of the options, in the same order
+    #[serde(default)]
+    pub totals: Vec<u64>,
+    #[serde(default, rename = "totalvotes")]
+    pub total_votes: u64,
+    /// Weighted polls count subscribers' votes by their tier
+    #[serde(default)]
+    pub weighted: bool,
+    #[serde(default)]
+    pub start: Option<DateTime<Utc>>,
+    /// Server time when the frame was sent
+    #[serde(default)]
+    pub now: Option<DateTime<Utc>>,
+    /// Milliseconds the poll runs for
+    #[serde(default)]
+    pub time: Option<u64>,
+    /// Set from the event type, only `POLLSTOP` ends a poll
+    #[serde(skip_deserializing)]
+    pub is_ended: bool,
+}
+
+impl Poll {
+    pub fn timestamp(&self) -> Option<DateTime<Utc>> {
+        self.now.or(self.start)
+    }
+}
+
+impl DggAsEventGroup for Poll {
+    fn as_group(&self, channel: String) -> EventGroup {
+        let timestamp = match self.timestamp() {
+            Some(timestamp) => timestamp,
+            None => return None.into(),
+        };
+        let poll = events::Poll {
+            author: self.nick.clone(),
+            question: self.question.clone(),
+            options: self
+                .options
+                .iter()
+                .enumerate()
+                .map(|(i, text)| PollOption {
+                    text: text.clone(),
+                    votes: self.totals.get(i).copied().unwrap_or(0),
+                })
+                .collect(),
+            is_weighted: self.weighted,
+            duration_seconds: self.time.map(|millis| millis / 1000),
+        };
+        let kind = if self.is_ended {
+            EventKind::PollEnd(poll)
+        } else {
+            EventKind::PollStart(poll)
+        };
+        dgg_event(channel, timestamp, kind).into()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawVote {
+    Number(usize),
+    Text(String),
+}
+
+/// A vote in the running poll, `VOTECAST` for our own and `VOTECOUNTED` for everyone's. Votes are
+/// anonymous, they only say which option got it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Vote {
+    vote: RawVote,
+}
+
+impl Vote {
+    /// The option voted for, counting from 1 like dgg does
+    pub fn option(&self) -> Option<usize> {
+        match &self.vote {
+            RawVote::Number(option) => Some(*option),
+            RawVote::Text(option) => option.parse().ok(),
+        }
+    }
+}
+
+impl DggAsEventGroup for Vote {
+    fn as_group(&self, _channel: String) -> EventGroup {
+        None.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Events;
+    use crate::{
+        events::{AllEvents, EventGroup, EventKind, SimpleMessageGroup},
+        scrapers::dgg::parse_test_frame,
+    };
+
+    #[test]
+    fn test_poll_start_and_stop() {
+        let start = parse_test_frame(concat!(
+            r#"POLLSTART {"canvote":true,"myvote":0,"nick":"Destiny","weighted":false,"#,
+            r#""start":"2023-11-14T22:13:20.000Z","now":"2023-11-14T22:13:20.000Z","#,
+            r#""time":30000,"question":"Is this a poll?","options":["yes","no"],"#,
+            r#""totals":[0,0],"totalvotes":0}"#,
+        ));
+        let group = SimpleMessageGroup::from(AllEvents::from(start));
+        assert_eq!(
+            group.0[0].text,
+            "Destiny started a poll: Is this a poll? (yes, no)"
+        );
+
+        let stop = parse_test_frame(concat!(
+            r#"POLLSTOP {"canvote":false,"myvote":0,"nick":"Destiny","weighted":true,"#,
+            r#""start":"2023-11-14T22:13:20.000Z","now":"2023-11-14T22:13:50.000Z","#,
+            r#""time":30000,"question":"Is this a poll?","options":["yes","no"],"#,
+            r#""totals":[120,34],"totalvotes":154}"#,
+        ));
+        let group = EventGroup::from(stop);
+        let poll = match &group.0[0].kind {
+            EventKind::PollEnd(poll) => poll,
+            kind => panic!("Expected a poll end, got {:?}", kind),
+        };
+        assert!(poll.is_weighted);
+        assert_eq!(poll.options[0].votes, 120);
+        assert_eq!(group.0[0].timestamp.timestamp(), 1700000030);
+        assert_eq!(
+            group.0[0].to_simple_message().unwrap().text,
+            "Poll ended: Is this a poll? yes: 120, no: 34"
+        );
+    }
+
+    #[test]
+    fn test_votes() {
+        for raw in [r#"VOTECOUNTED {"vote":"2"}"#, r#"VOTECAST {"vote":2}"#] {
+            let event = parse_test_frame(raw);
+            match &event.event {
+                Events::Vote(vote) => assert_eq!(vote.option(), Some(2)),
+                event => panic!("Expected a vote, got {:?}", event),
+            }
+            assert!(EventGroup::from(event).0.is_empty());
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use chrono::{serde::ts_milliseconds, DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-use super::{shared::user::User, DggAsSimpleMessageGroup};
+use super::{shared::user::User, DggAsEventGroup};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Quit {
@@ -12,8 +12,8 @@ pub struct Quit {
     pub timestamp: DateTime<Utc>,
 }
 
-impl DggAsSimpleMessageGroup for Quit {
-    fn as_group(&self, _channel: String) -> crate::events::SimpleMessageGroup {
+impl DggAsEventGroup for Quit {
+    fn as_group(&self, _channel: String) -> crate::events::EventGroup {
         None.into()
     }
 }
@@ -1,11 +1,14 @@
 use std::{str::FromStr, string::ToString};
 
 use serde::{Deserialize, Serialize};
-use strum::{Display, EnumString};
+use strum::{AsRefStr, Display, EnumString};
+
+use crate::events::EventUser;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RawUser {
     pub nick: String,
+    #[serde(default)]
     pub features: Vec<String>,
 }
 
@@ -74,6 +77,22 @@ impl From<RawUser> for User {
     }
 }
 
+impl User {
+    /// Names of the user's flairs as dgg sends them, sorted so that equal sets compare equal
+    pub fn flair_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.flairs.iter().map(|f| f.to_string()).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+impl From<&User> for EventUser {
+    fn from(user: &User) -> Self {
+        EventUser::from_login(user.username.clone())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Display, EnumString, Serialize)]
 #[serde(into = "String")]
 pub enum Flairs {
@@ -153,7 +172,8 @@ impl From<Flairs> for String {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize)]
+#[derive(Clone, Debug, PartialEq, AsRefStr, Serialize)]
+#[strum(serialize_all = "lowercase")]
 pub enum Subscriptions {
     #[serde(rename = "twitch")]
     Twitch,
@@ -0,0 +1,54 @@
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{dgg_event, DggAsEventGroup};
+use crate::events::{self, EventGroup, EventKind};
+
+/// Toggles subscriber only mode, `data` is `on` or `off`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SubOnly {
+    /// The moderator toggling it
+    pub nick: String,
+    pub data: String,
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: DateTime<Utc>,
+}
+
+impl DggAsEventGroup for SubOnly {
+    fn as_group(&self, channel: String) -> EventGroup {
+        dgg_event(
+            channel,
+            self.timestamp,
+            EventKind::SubOnly(events::SubOnly {
+                is_enabled: self.data == "on",
+                moderator: Some(self.nick.clone()),
+            }),
+        )
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        events::{AllEvents, SimpleMessageGroup},
+        scrapers::dgg::parse_test_frame,
+    };
+
+    fn render(data: &str) -> String {
+        let raw = format!(
+            r#"SUBONLY {{"nick":"Bot","features":["bot"],"timestamp":1700000000123,"data":"{}"}}"#,
+            data
+        );
+        let event = parse_test_frame(&raw);
+        SimpleMessageGroup::from(AllEvents::from(event)).0[0]
+            .text
+            .clone()
+    }
+
+    #[test]
+    fn test_sub_only() {
+        assert_eq!(render("on"), "Bot enabled subscriber only mode");
+        assert_eq!(render("off"), "Bot disabled subscriber only mode");
+    }
+}
@@ -0,0 +1,173 @@
+use chrono::{serde::ts_milliseconds, DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{dgg_event, shared::user::User, DggAsEventGroup};
+use crate::events::{self, EventGroup, EventKind, SubTier};
+
+/// dgg tiers are numbered 1 through 4. Their labels use roman numerals (`Tier IV`), the number
+/// is used instead so every tier renders like the twitch ones.
+fn sub_tier(tier: u8) -> SubTier {
+    match tier {
+        1 => SubTier::Tier1,
+        2 => SubTier::Tier2,
+        3 => SubTier::Tier3,
+        _ => SubTier::Other(format!("Tier {}", tier)),
+    }
+}
+
+/// Empty `data` means the user didn't write anything
+fn sub_message(data: &str) -> Option<String> {
+    Some(data.to_string()).filter(|text| !text.is_empty())
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Subscription {
+    pub user: User,
+    pub tier: u8,
+    #[serde(default, rename = "tierlabel")]
+    pub tier_label: Option<String>,
+    /// Months subscribed, counting this one
+    #[serde(default)]
+    pub streak: Option<u64>,
+    /// The message the subscriber wrote, empty when there's none
+    #[serde(default)]
+    pub data: String,
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: DateTime<Utc>,
+}
+
+impl DggAsEventGroup for Subscription {
+    fn as_group(&self, channel: String) -> EventGroup {
+        let months = self.streak.unwrap_or(1).max(1);
+        dgg_event(
+            channel,
+            self.timestamp,
+            EventKind::Subscription(events::Subscription {
+                user: (&self.user).into(),
+                tier: sub_tier(self.tier),
+                is_resub: months > 1,
+                cumulative_months: months,
+                streak_months: self.streak,
+                message: sub_message(&self.data),
+            }),
+        )
+        .into()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GiftSub {
+    /// The user paying for the sub
+    pub user: User,
+    pub recipient: User,
+    pub tier: u8,
+    #[serde(default, rename = "tierlabel")]
+    pub tier_label: Option<String>,
+    /// Set on each of the gifts that make up a `MASSGIFT`
+    #[serde(default, rename = "fromMassGift")]
+    pub from_mass_gift: bool,
+    #[serde(default)]
+    pub data: String,
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: DateTime<Utc>,
+}
+
+impl DggAsEventGroup for GiftSub {
+    fn as_group(&self, channel: String) -> EventGroup {
+        dgg_event(
+            channel,
+            self.timestamp,
+            EventKind::GiftSub(events::GiftSub {
+                gifter: (&self.user).into(),
+                is_anonymous: false,
+                recipient: (&self.recipient).into(),
+                tier: sub_tier(self.tier),
+                gifted_months: 1,
+            }),
+        )
+        .into()
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MassGift {
+    pub user: User,
+    pub tier: u8,
+    #[serde(default, rename = "tierlabel")]
+    pub tier_label: Option<String>,
+    pub quantity: u64,
+    #[serde(default)]
+    pub data: String,
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: DateTime<Utc>,
+}
+
+impl DggAsEventGroup for MassGift {
+    fn as_group(&self, channel: String) -> EventGroup {
+        dgg_event(
+            channel,
+            self.timestamp,
+            EventKind::MassGift(events::MassGift {
+                gifter: (&self.user).into(),
+                is_anonymous: false,
+                count: self.quantity,
+                tier: sub_tier(self.tier),
+            }),
+        )
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        events::{AllEvents, EventGroup, EventKind, SimpleMessageGroup, SubTier},
+        scrapers::dgg::parse_test_frame,
+    };
+
+    #[test]
+    fn test_subscription() {
+        let event = parse_test_frame(concat!(
+            r#"SUBSCRIPTION {"timestamp":1700000000123,"uuid":"5f0e6c2a","#,
+            r#""data":"three years PepoTurkey","user":{"id":42,"nick":"Bob","#,
+            r#""features":["flair13"]},"tier":1,"tierlabel":"Tier I","streak":36}"#,
+        ));
+        let group = EventGroup::from(event);
+        let sub = match &group.0[0].kind {
+            EventKind::Subscription(sub) => sub,
+            kind => panic!("Expected a subscription, got {:?}", kind),
+        };
+        assert_eq!(sub.user.login, "Bob");
+        assert_eq!(sub.tier, SubTier::Tier1);
+        assert!(sub.is_resub);
+        assert_eq!(sub.cumulative_months, 36);
+        assert_eq!(sub.message.as_deref(), Some("three years PepoTurkey"));
+        assert_eq!(group.0[0].timestamp.timestamp_millis(), 1700000000123);
+    }
+
+    #[test]
+    fn test_gifts() {
+        let gift = parse_test_frame(concat!(
+            r#"GIFTSUB {"timestamp":1700000000123,"uuid":"91c4","data":"","#,
+            r#""user":{"nick":"Gifter","features":["flair1"]},"#,
+            r#""recipient":{"nick":"lucky","features":[]},"#,
+            r#""tier":4,"tierlabel":"Tier IV","fromMassGift":false}"#,
+        ));
+        // The recipient only comes with some of their flairs
+        let users: Vec<&str> = gift.users().iter().map(|u| u.username.as_str()).collect();
+        assert_eq!(users, vec!["Gifter"]);
+        let group = SimpleMessageGroup::from(AllEvents::from(gift));
+        assert_eq!(group.0[0].text, "Gifter gifted a Tier 4 sub to lucky!");
+
+        let mass_gift = parse_test_frame(concat!(
+            r#"MASSGIFT {"timestamp":1700000000123,"uuid":"77ab","data":"enjoy","#,
+            r#""user":{"nick":"Gifter","features":["flair1"]},"#,
+            r#""tier":2,"tierlabel":"Tier II","quantity":5}"#,
+        ));
+        let group = SimpleMessageGroup::from(AllEvents::from(mass_gift));
+        assert_eq!(
+            group.0[0].text,
+            "Gifter gifted 5 Tier 2 subs to the community!"
+        );
+    }
+}
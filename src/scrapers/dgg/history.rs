@@ -0,0 +1,169 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+use log::error;
+
+use super::DggEvent;
+
+/// dgg messages carry no ids, so they're told apart by when, who and what
+type MessageKey = (DateTime<Utc>, String, String);
+
+fn message_key(event: &DggEvent) -> Option<MessageKey> {
+    let msg = event.message()?;
+    Some((msg.timestamp, msg.user.username.clone(), msg.text.clone()))
+}
+
+/// Keeps track of the newest messages seen, so that the chat history fetched after a reconnect
+/// only fills in what was missed while disconnected.
+#[derive(Debug, Default)]
+pub struct HistoryTracker {
+    last_seen: Option<DateTime<Utc>>,
+    /// Messages sent at `last_seen`, several can share the same millisecond
+    latest: HashSet<MessageKey>,
+    /// Messages emitted from the history, which the websocket may send again right after
+    backfilled: HashSet<MessageKey>,
+}
+
+impl HistoryTracker {
+    fn is_new(&self, key: &MessageKey) -> bool {
+        match self.last_seen {
+            Some(last_seen) => {
+                key.0 > last_seen || (key.0 == last_seen && !self.latest.contains(key))
+            }
+            None => true,
+        }
+    }
+
+    fn see(&mut self, key: MessageKey) {
+        let is_newest = match self.last_seen {
+            Some(last_seen) => key.0 > last_seen,
+            None => true,
+        };
+        if is_newest {
+            self.last_seen = Some(key.0);
+            self.latest.clear();
+        }
+        if Some(key.0) == self.last_seen {
+            self.latest.insert(key);
+        }
+    }
+
+    /// Remembers a message from the websocket, returns whether it was already emitted from the
+    /// history
+    pub fn observe(&mut self, event: &DggEvent) -> bool {
+        let key = match message_key(event) {
+            Some(key) => key,
+            None => return false,
+        };
+        if self.backfilled.remove(&key) {
+            return true;
+        }
+        self.see(key);
+        false
+    }
+
+    /// Parses history frames and returns the messages newer than the last one seen, oldest
+    /// first. Nothing is backfilled before the first message, there's no gap to fill then and
+    /// the history would only repeat what previous runs wrote.
+    pub fn backfill(
+        &mut self,
+        frames: Vec<String>,
+        channel: &str,
+        received_at: DateTime<Utc>,
+    ) -> Vec<DggEvent> {
+        self.backfilled.clear();
+        if self.last_seen.is_none() {
+            return Vec::new();
+        }
+
+        let mut keys = HashSet::new();
+        let mut events: Vec<(MessageKey, DggEvent)> = frames
+            .into_iter()
+            .filter_map(
+                |frame| match DggEvent::from_ws(frame, channel.to_string(), received_at) {
+                    Ok(event) => event,
+                    Err(err) => {
+                        error!("Serde parsing error from dgg history: {:?}", err);
+                        None
+                    }
+                },
+            )
+            .filter_map(|event| Some((message_key(&event)?, event)))
+            // The history can repeat itself too
+            .filter(|(key, _)| self.is_new(key) && keys.insert(key.clone()))
+            .collect();
+        events.sort_by_key(|(key, _)| key.0);
+
+        events
+            .into_iter()
+            .map(|(key, event)| {
+                self.see(key.clone());
+                self.backfilled.insert(key);
+                event
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrapers::dgg::parse_test_frame;
+
+    fn msg(nick: &str, millis: i64, text: &str) -> String {
+        format!(
+            r#"MSG {{"nick":"{}","features":[],"timestamp":{},"data":"{}"}}"#,
+            nick, millis, text
+        )
+    }
+
+    fn texts(events: &[DggEvent]) -> Vec<&str> {
+        events
+            .iter()
+            .map(|e| e.message().unwrap().text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_backfills_only_missed_messages() {
+        let mut tracker = HistoryTracker::default();
+        assert!(tracker
+            .backfill(
+                vec![msg("a", 100, "before we connected")],
+                "Destinygg",
+                Utc::now()
+            )
+            .is_empty());
+
+        assert!(!tracker.observe(&parse_test_frame(&msg("a", 100, "first"))));
+        assert!(!tracker.observe(&parse_test_frame(&msg("b", 200, "seen"))));
+
+        let history = vec![
+            msg("a", 100, "first"),
+            r#"BROADCAST {"data":"not a message","timestamp":250}"#.to_string(),
+            msg("b", 200, "seen"),
+            // Same millisecond as the last message seen, but a different one
+            msg("c", 200, "missed"),
+            msg("d", 300, "missed too"),
+            msg("c", 300, "missed as well"),
+            msg("d", 300, "missed too"),
+            msg("e", 400, "also sent live"),
+        ];
+        let events = tracker.backfill(history.clone(), "Destinygg", Utc::now());
+        assert_eq!(
+            texts(&events),
+            vec!["missed", "missed too", "missed as well", "also sent live"]
+        );
+
+        // The websocket repeats one of them, which is dropped once
+        assert!(tracker.observe(&parse_test_frame(&msg("e", 400, "also sent live"))));
+        assert!(!tracker.observe(&parse_test_frame(&msg("e", 500, "new"))));
+
+        // Reconnecting again only gets what's newer
+        let mut history = history;
+        history.push(msg("e", 500, "new"));
+        history.push(msg("f", 600, "newest"));
+        let events = tracker.backfill(history, "Destinygg", Utc::now());
+        assert_eq!(texts(&events), vec!["newest"]);
+    }
+}
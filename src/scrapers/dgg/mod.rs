@@ -1,5 +1,7 @@
 mod events;
+mod history;
 mod scraper;
 
 pub use events::*;
+pub use history::*;
 pub use scraper::*;
@@ -1,12 +1,13 @@
 use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use futures::{SinkExt, StreamExt};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use reqwest::Client;
 use serde::Deserialize;
 use tokio::{
-    sync::mpsc::UnboundedSender,
+    sync::mpsc::Sender,
     time::{interval_at, Instant},
 };
 use tokio_tungstenite::{
@@ -14,8 +15,15 @@ use tokio_tungstenite::{
     tungstenite::{http::Request, Error as WsError, Message},
 };
 
-use super::DggEvent;
-use crate::{events::AllEvents, settings::DggSiteSettings};
+use super::{DggEvent, HistoryTracker};
+use crate::{
+    events::{AllEvents, RawEvent},
+    formats::unified::ChannelType,
+    settings::DggSiteSettings,
+};
+
+/// For the chat history and key requests, which would otherwise hang the reconnect
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct DggScraper {
     pub config: DggSiteSettings,
@@ -23,7 +31,7 @@ pub struct DggScraper {
 
 impl DggScraper {
     pub fn start(
-        tx: UnboundedSender<AllEvents>,
+        tx: Sender<AllEvents>,
         config: DggSiteSettings,
         max_retry_seconds: u64,
     ) -> Arc<DggScraper> {
@@ -32,10 +40,13 @@ impl DggScraper {
         let origin = config.origin.clone();
         let mut worker = DggWorker {
             tx,
+            http: Client::new(),
             channel,
             endpoint,
             origin,
             use_get_key: config.use_get_key,
+            backfill_history: config.backfill_history,
+            history: HistoryTracker::default(),
             failing: false,
             backoff_min: 2,
             backoff_max: max_retry_seconds,
@@ -52,11 +63,14 @@ enum WorkerCommands {
 }
 
 pub struct DggWorker {
-    tx: UnboundedSender<AllEvents>,
+    tx: Sender<AllEvents>,
+    http: Client,
     channel: String,
     endpoint: String,
     origin: String,
     use_get_key: bool,
+    backfill_history: bool,
+    history: HistoryTracker,
     failing: bool,
     backoff_min: u64,
     backoff_max: u64,
@@ -68,6 +82,10 @@ impl DggWorker {
 
         let mut backoff = self.backoff_min;
         loop {
+            if self.tx.is_closed() {
+                info!("Event channel closed, stopping '{}' scraper", self.channel);
+                return;
+            }
             if self.failing {
                 info!("Reconnecting after {} seconds...", backoff);
                 tokio::time::sleep(Duration::from_secs(backoff)).await;
@@ -123,6 +141,27 @@ impl DggWorker {
 
         let (mut write, mut read) = ws_stream.split();
 
+        // Fetched after connecting, so nothing falls between the history and the websocket
+        if self.backfill_history {
+            match self.fetch_history().await {
+                Ok(frames) => {
+                    let events = self.history.backfill(frames, &self.channel, Utc::now());
+                    if !events.is_empty() {
+                        info!("Backfilling {} messages from chat history", events.len());
+                    }
+                    for event in events {
+                        if self.tx.send(event.into()).await.is_err() {
+                            return WorkerCommands::Stop;
+                        }
+                    }
+                }
+                Err(err) => error!(
+                    "Error fetching chat history for '{}': {:?}",
+                    self.channel, err
+                ),
+            }
+        }
+
         info!("Starting request loop...");
 
         let ping_every = Duration::from_secs(30);
@@ -139,15 +178,30 @@ impl DggWorker {
                         Ok(msg) => match msg {
                             Message::Text(text) => {
                                 self.failing = false;
-                                let event = DggEvent::from_ws(text, self.channel.clone());
-                                match event {
-                                    Ok(Some(event)) => {
-                                        self.tx.send(event.into()).unwrap();
+                                let event = map_frame(text, &self.channel, Utc::now());
+                                let mut refresh = false;
+                                if let AllEvents::Dgg(event) = &event {
+                                    if self.history.observe(event) {
+                                        continue;
                                     }
-                                    Err(err) => {
-                                        error!("Serde parsing error from dgg messages: {:?}", err);
+                                    if let Some(description) = event.error() {
+                                        warn!(
+                                            "'{}' chat sent an error: {}",
+                                            self.channel, description
+                                        );
                                     }
-                                    _ => {}
+                                    refresh = event.is_refresh();
+                                }
+                                // The ingester is shutting down
+                                if self.tx.send(event).await.is_err() {
+                                    return WorkerCommands::Stop;
+                                }
+                                if refresh {
+                                    info!(
+                                        "Received REFRESH from '{}', triggering reconnect",
+                                        self.channel
+                                    );
+                                    return WorkerCommands::Reconnect;
                                 }
                             }
                             Message::Pong(_) => {
@@ -211,13 +265,53 @@ impl DggWorker {
         }
     }
 
+    async fn fetch_history(&self) -> Result<Vec<String>> {
+        let history_url = self.origin.clone() + "/api/chat/history";
+        let response: Vec<String> = self
+            .http
+            .get(history_url)
+            .timeout(HTTP_TIMEOUT)
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        Ok(response)
+    }
+
     async fn fetch_get_key(&self, get_key_url: String) -> Result<GetKeyResponse> {
-        let response: GetKeyResponse = Client::new().get(get_key_url).send().await?.json().await?;
+        let response: GetKeyResponse = self
+            .http
+            .get(get_key_url)
+            .timeout(HTTP_TIMEOUT)
+            .send()
+            .await?
+            .json()
+            .await?;
 
         Ok(response)
     }
 }
 
+/// Maps a websocket frame to a typed event, falling back to a raw event for frames that aren't
+/// events or that fail to parse, so they still end up in raw archives and captures.
+pub fn map_frame(text: String, channel: &str, received_at: DateTime<Utc>) -> AllEvents {
+    match DggEvent::from_ws(text.clone(), channel.to_string(), received_at) {
+        Ok(Some(event)) => return event.into(),
+        Err(err) => {
+            error!("Serde parsing error from dgg messages: {:?}", err);
+        }
+        _ => {}
+    }
+    RawEvent {
+        platform: ChannelType::Dgg,
+        channel: Some(channel.to_string()),
+        received_at,
+        data: text,
+    }
+    .into()
+}
+
 #[derive(Clone, Debug, Deserialize)]
 struct GetKeyResponse {
     #[serde(rename = "chatKey")]
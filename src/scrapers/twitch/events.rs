@@ -1,48 +1,261 @@
-use chrono::Utc;
-use humantime::format_duration;
+use chrono::{DateTime, Utc};
 use twitch_irc::message::{
-    ClearChatAction, ClearChatMessage, HostTargetAction, HostTargetMessage, PrivmsgMessage,
-    UserNoticeEvent, UserNoticeMessage,
+    Badge, ClearChatAction, ClearChatMessage, HostTargetAction, HostTargetMessage, IRCMessage,
+    JoinMessage, PartMessage, PrivmsgMessage, TwitchUserBasics, UserNoticeEvent, UserNoticeMessage,
 };
 
-use crate::events::{SimpleMessage, SimpleMessageGroup, Usernames};
+use crate::{
+    events::{
+        Ban, Bits, BitsBadge, ChatMessage, Event, EventCategory, EventGroup, EventKind, EventUser,
+        GiftSub, GiftUpgrade, Host, MassGift, Notice, Raid, Ritual, SubTier, Subscription, Timeout,
+    },
+    formats::unified::ChannelType,
+    userlist_scraper::PresenceChange,
+};
+
+use super::is_anonymous;
+
+/// Login twitch uses as the sender of anonymous gifts
+const ANONYMOUS_GIFTER: &str = "ananonymousgifter";
+
+/// A twitch message along with when the scraper received it
+#[derive(Clone, Debug)]
+pub struct TwitchEvent {
+    pub message: TwitchMessage,
+    pub received_at: DateTime<Utc>,
+}
 
 #[derive(Clone, Debug)]
-pub enum TwitchEvent {
+pub enum TwitchMessage {
     HostTarget(HostTargetMessage),
     Privmsg(PrivmsgMessage),
     UserNotice(UserNoticeMessage),
     ClearChat(ClearChatMessage),
+    /// Only sent for other users with the membership capability
+    Join(JoinMessage),
+    Part(PartMessage),
+    /// Who's in a channel when we join it, also only with the membership capability
+    Names(NamesReply),
 }
 
-impl From<TwitchEvent> for SimpleMessageGroup {
-    fn from(events: TwitchEvent) -> Self {
-        use TwitchEvent::*;
-        match events {
+/// A `353` reply with some of the users in a channel, or the `366` that ends the list. Chats with
+/// more than 1000 users only list their moderators.
+#[derive(Clone, Debug)]
+pub struct NamesReply {
+    pub channel_login: String,
+    pub user_logins: Vec<String>,
+    /// Whether this is the `366`, which has no users
+    pub is_end: bool,
+    pub source: IRCMessage,
+}
+
+impl NamesReply {
+    /// Parses `:host 353 <us> = #<channel> :<users>` and `:host 366 <us> #<channel> :End of
+    /// /NAMES list`, twitch-irc has no type for either
+    pub fn parse(source: &IRCMessage) -> Option<Self> {
+        let (channel, users, is_end) = match source.command.as_str() {
+            "353" => (source.params.get(2)?, source.params.get(3)?.as_str(), false),
+            "366" => (source.params.get(1)?, "", true),
+            _ => return None,
+        };
+        Some(NamesReply {
+            channel_login: channel.strip_prefix('#')?.to_string(),
+            user_logins: users
+                .split_whitespace()
+                .filter(|login| !is_anonymous(login))
+                .map(str::to_string)
+                .collect(),
+            is_end,
+            source: source.clone(),
+        })
+    }
+}
+
+impl TwitchEvent {
+    pub fn new(message: TwitchMessage, received_at: DateTime<Utc>) -> Self {
+        TwitchEvent {
+            message,
+            received_at,
+        }
+    }
+
+    /// The IRC message this event was parsed from
+    pub fn source(&self) -> &IRCMessage {
+        use TwitchMessage::*;
+        match &self.message {
+            HostTarget(m) => &m.source,
+            Privmsg(m) => &m.source,
+            UserNotice(m) => &m.source,
+            ClearChat(m) => &m.source,
+            Join(m) => &m.source,
+            Part(m) => &m.source,
+            Names(m) => &m.source,
+        }
+    }
+
+    /// Rewrites the user written text of the event, both in the parsed message and in its IRC
+    /// source, so raw archives get the same treatment.
+    pub fn map_text(&mut self, f: impl Fn(&str) -> String) {
+        match &mut self.message {
+            TwitchMessage::Privmsg(msg) => {
+                let changed = map_irc_text(&mut msg.source, &f);
+                if changed {
+                    msg.message_text = f(&msg.message_text);
+                }
+            }
+            TwitchMessage::UserNotice(msg) => {
+                if let Some(text) = &mut msg.message_text {
+                    if map_irc_text(&mut msg.source, &f) {
+                        *text = f(text);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Removes users from a `353` names reply, both in the parsed reply and in its IRC source
+    pub fn retain_names(&mut self, keep: impl Fn(&str) -> bool) {
+        let names = match &mut self.message {
+            TwitchMessage::Names(names) => names,
+            _ => return,
+        };
+        let before = names.user_logins.len();
+        names.user_logins.retain(|login| keep(login));
+        if names.user_logins.len() == before {
+            return;
+        }
+        if let Some(users) = names.source.params.get_mut(3) {
+            *users = users
+                .split_whitespace()
+                .filter(|login| keep(login))
+                .collect::<Vec<_>>()
+                .join(" ");
+        }
+    }
+
+    pub fn category(&self) -> EventCategory {
+        use TwitchMessage::*;
+        match &self.message {
+            HostTarget(_) | UserNotice(_) => EventCategory::Notice,
+            Privmsg(_) => EventCategory::Message,
+            ClearChat(_) => EventCategory::Moderation,
+            Join(_) | Part(_) | Names(_) => EventCategory::Presence,
+        }
+    }
+
+    pub fn channel_login(&self) -> &str {
+        use TwitchMessage::*;
+        match &self.message {
+            HostTarget(m) => &m.channel_login,
+            Privmsg(m) => &m.channel_login,
+            UserNotice(m) => &m.channel_login,
+            ClearChat(m) => &m.channel_login,
+            Join(m) => &m.channel_login,
+            Part(m) => &m.channel_login,
+            Names(m) => &m.channel_login,
+        }
+    }
+
+    pub fn presence(&self) -> Option<PresenceChange<'_>> {
+        match &self.message {
+            TwitchMessage::Join(m) => Some(PresenceChange::Join(&m.user_login)),
+            TwitchMessage::Part(m) => Some(PresenceChange::Leave(&m.user_login)),
+            TwitchMessage::Names(m) => Some(PresenceChange::Names {
+                users: m.user_logins.iter().map(String::as_str).collect(),
+                connection_count: None,
+                is_complete: m.is_end,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Rewrites the trailing text parameter of a `PRIVMSG`-like IRC message, leaving the CTCP
+/// `ACTION` wrapper of `/me` messages intact, along with the quoted text of replies. Returns
+/// whether anything changed.
+pub fn map_irc_text(source: &mut IRCMessage, f: impl Fn(&str) -> String) -> bool {
+    let mut changed = false;
+    if let Some(text) = source.params.get_mut(1) {
+        let mapped = match text
+            .strip_prefix("\u{1}ACTION ")
+            .and_then(|t| t.strip_suffix('\u{1}'))
+        {
+            Some(action) => format!("\u{1}ACTION {}\u{1}", f(action)),
+            None => f(text),
+        };
+        if mapped != *text {
+            *text = mapped;
+            changed = true;
+        }
+    }
+    if let Some(Some(parent)) = source.tags.0.get_mut("reply-parent-msg-body") {
+        let mapped = f(parent);
+        if mapped != *parent {
+            *parent = mapped;
+            changed = true;
+        }
+    }
+    changed
+}
+
+impl From<TwitchEvent> for EventGroup {
+    fn from(event: TwitchEvent) -> Self {
+        use TwitchMessage::*;
+        match event.message {
             HostTarget(m) => m.into(),
             Privmsg(m) => m.into(),
             UserNotice(m) => m.into(),
             ClearChat(m) => m.into(),
+            Join(_) | Part(_) | Names(_) => EventGroup::default(),
+        }
+    }
+}
+
+impl From<&TwitchUserBasics> for EventUser {
+    fn from(user: &TwitchUserBasics) -> Self {
+        EventUser {
+            id: Some(user.id.clone()),
+            login: user.login.clone(),
+            display_name: Some(user.name.clone()),
         }
     }
 }
 
-impl From<HostTargetMessage> for SimpleMessageGroup {
+fn badge_names(badges: &[Badge]) -> Vec<String> {
+    badges.iter().map(|b| b.name.clone()).collect()
+}
+
+fn is_subscriber(badges: &[Badge]) -> bool {
+    badges
+        .iter()
+        .any(|b| b.name == "subscriber" || b.name == "founder")
+}
+
+/// The exact subscription length is only present in the `badge-info` tag.
+fn subscriber_months(badge_info: &[Badge]) -> Option<u64> {
+    badge_info
+        .iter()
+        .find(|b| b.name == "subscriber" || b.name == "founder")
+        .and_then(|b| b.version.parse().ok())
+}
+
+impl From<HostTargetMessage> for EventGroup {
     fn from(msg: HostTargetMessage) -> Self {
         match msg.action {
             HostTargetAction::HostModeOn {
                 viewer_count,
                 hosted_channel_login,
-            } => Some(SimpleMessage {
-                id: None,
+            } => Some(Event {
+                platform: ChannelType::Twitch,
                 channel: hosted_channel_login,
+                channel_id: None,
+                id: None,
                 timestamp: Utc::now(),
-                username: Usernames::Host,
-                text: format!(
-                    "{} just raided the channel with {} viewers!",
-                    msg.channel_login,
-                    viewer_count.unwrap_or(0)
-                ),
+                kind: EventKind::Host(Host {
+                    hosting_channel: msg.channel_login,
+                    viewer_count,
+                }),
             }),
 
             _ => None,
@@ -50,177 +263,235 @@ impl From<HostTargetMessage> for SimpleMessageGroup {
         .into()
     }
 }
-impl From<PrivmsgMessage> for SimpleMessageGroup {
+
+impl From<PrivmsgMessage> for EventGroup {
     fn from(msg: PrivmsgMessage) -> Self {
-        let mut messages = vec![SimpleMessage {
-            id: Some(msg.message_id),
-            timestamp: msg.server_timestamp,
+        let user = EventUser::from(&msg.sender);
+        let event = |id, kind| Event {
+            platform: ChannelType::Twitch,
             channel: msg.channel_login.clone(),
-            username: Usernames::Normal(msg.sender.login.clone()),
-            text: msg.message_text,
-        }];
+            channel_id: Some(msg.channel_id.clone()),
+            id,
+            timestamp: msg.server_timestamp,
+            kind,
+        };
 
-        if let Some(bits) = msg.bits {
-            messages.push(SimpleMessage {
-                id: None,
-                timestamp: msg.server_timestamp,
-                channel: msg.channel_login.clone(),
-                username: Usernames::Bits,
-                text: format!(
-                    "{} donated {} bits to the channel!",
-                    &msg.sender.login, bits
-                ),
-            })
+        let mut events = vec![event(
+            Some(msg.message_id.clone()),
+            EventKind::Message(ChatMessage {
+                user: user.clone(),
+                text: msg.message_text.clone(),
+                is_action: msg.is_action,
+                badges: badge_names(&msg.badges),
+                is_subscriber: is_subscriber(&msg.badges),
+                subscriber_months: subscriber_months(&msg.badge_info),
+                color: msg
+                    .name_color
+                    .as_ref()
+                    .map(|c| format!("#{:02X}{:02X}{:02X}", c.r, c.g, c.b)),
+            }),
+        )];
+
+        if let Some(amount) = msg.bits {
+            events.push(event(None, EventKind::Bits(Bits { user, amount })))
         }
-        SimpleMessageGroup(messages)
+        EventGroup(events)
     }
 }
 
-impl From<ClearChatMessage> for SimpleMessageGroup {
+impl From<ClearChatMessage> for EventGroup {
     fn from(msg: ClearChatMessage) -> Self {
-        let text = match msg.action {
-            ClearChatAction::UserBanned { user_login, .. } => {
-                format!("{} permanently banned", user_login)
-            }
+        let kind = match msg.action {
+            ClearChatAction::UserBanned {
+                user_login,
+                user_id,
+            } => EventKind::Ban(Ban {
+                target: EventUser {
+                    id: Some(user_id),
+                    login: user_login,
+                    display_name: None,
+                },
+                moderator: None,
+            }),
             ClearChatAction::UserTimedOut {
                 user_login,
+                user_id,
                 timeout_length,
-                ..
-            } => format!(
-                "{} timed out for {}",
-                user_login,
-                format_duration(timeout_length).to_string()
-            ),
-            _ => return None.into(),
+            } => EventKind::Timeout(Timeout {
+                target: EventUser {
+                    id: Some(user_id),
+                    login: user_login,
+                    display_name: None,
+                },
+                moderator: None,
+                duration_seconds: Some(timeout_length.as_secs()),
+            }),
+            ClearChatAction::ChatCleared => EventKind::ChatCleared,
         };
-        SimpleMessage {
+        Event {
+            platform: ChannelType::Twitch,
+            channel: msg.channel_login,
+            channel_id: Some(msg.channel_id),
             id: None,
-            channel: msg.channel_login.clone(),
             timestamp: msg.server_timestamp,
-            username: Usernames::Moderation,
-            text,
+            kind,
         }
         .into()
     }
 }
 
-impl From<UserNoticeMessage> for SimpleMessageGroup {
+impl From<UserNoticeMessage> for EventGroup {
     fn from(msg: UserNoticeMessage) -> Self {
-        let mut messages: Vec<SimpleMessage> = Vec::new();
-
-        let easy_transform =
-            |msg: &UserNoticeMessage, username: Usernames, text: String| SimpleMessage {
-                id: Some(msg.message_id.clone()),
-                channel: msg.channel_login.clone(),
-                timestamp: msg.server_timestamp,
-                username,
-                text,
-            };
-
-        let tiers_format = |sub_plan| match sub_plan {
-            "Prime" => "Prime",
-            "1000" => "Tier 1",
-            "2000" => "Tier 2",
-            "3000" => "Tier 3",
-            _ => sub_plan,
+        let mut events: Vec<Event> = Vec::new();
+
+        let event = |id, kind| Event {
+            platform: ChannelType::Twitch,
+            channel: msg.channel_login.clone(),
+            channel_id: Some(msg.channel_id.clone()),
+            id,
+            timestamp: msg.server_timestamp,
+            kind,
         };
-        match msg.event.clone() {
+        let sender = EventUser::from(&msg.sender);
+
+        let kind = match msg.event.clone() {
             UserNoticeEvent::SubOrResub {
-                sub_plan,
-                cumulative_months,
                 is_resub,
+                cumulative_months,
+                streak_months,
+                sub_plan,
                 ..
-            } => {
-                let sub_verb = match is_resub {
-                    true => "resubscribed",
-                    false => "subscribed",
-                };
-
-                messages.push(easy_transform(
-                    &msg,
-                    Usernames::Subscriber,
-                    format!(
-                        "{} just {} {} {} for {} months!{}",
-                        msg.sender.login,
-                        sub_verb,
-                        if sub_plan == "Prime" { "with" } else { "at" },
-                        tiers_format(&sub_plan),
-                        cumulative_months,
-                        if let Some(text) = msg.message_text.clone() {
-                            format!(" Message: {}", text)
-                        } else {
-                            "".to_string()
-                        }
-                    ),
-                ))
-            }
+            } => Some(EventKind::Subscription(Subscription {
+                user: sender,
+                tier: SubTier::from(sub_plan.as_str()),
+                is_resub,
+                cumulative_months,
+                streak_months,
+                message: msg.message_text.clone(),
+            })),
             UserNoticeEvent::SubGift {
                 recipient,
                 sub_plan,
+                num_gifted_months,
                 ..
-            } => messages.push(easy_transform(
-                &msg,
-                Usernames::Subscriber,
-                format!(
-                    "{} gifted a {} sub to {}!",
-                    msg.sender.login,
-                    tiers_format(&sub_plan),
-                    recipient.login
-                ),
-            )),
-            UserNoticeEvent::AnonSubMysteryGift {
+            } => Some(EventKind::GiftSub(GiftSub {
+                is_anonymous: sender.login == ANONYMOUS_GIFTER,
+                gifter: sender,
+                recipient: EventUser::from(&recipient),
+                tier: SubTier::from(sub_plan.as_str()),
+                gifted_months: num_gifted_months,
+            })),
+            UserNoticeEvent::SubMysteryGift {
                 mass_gift_count,
                 sub_plan,
-            }
-            | UserNoticeEvent::SubMysteryGift {
+                ..
+            } => Some(EventKind::MassGift(MassGift {
+                is_anonymous: sender.login == ANONYMOUS_GIFTER,
+                gifter: sender,
+                count: mass_gift_count,
+                tier: SubTier::from(sub_plan.as_str()),
+            })),
+            UserNoticeEvent::AnonSubMysteryGift {
                 mass_gift_count,
                 sub_plan,
+            } => Some(EventKind::MassGift(MassGift {
+                gifter: sender,
+                is_anonymous: true,
+                count: mass_gift_count,
+                tier: SubTier::from(sub_plan.as_str()),
+            })),
+            UserNoticeEvent::Raid { viewer_count, .. } => Some(EventKind::Raid(Raid {
+                raider: sender,
+                viewer_count,
+            })),
+            UserNoticeEvent::GiftPaidUpgrade {
+                gifter_login,
+                gifter_name,
                 ..
-            } => messages.push(easy_transform(
-                &msg,
-                Usernames::GiftSub,
-                format!(
-                    "{} gifted {} {} subs to the community!",
-                    msg.sender.login,
-                    mass_gift_count,
-                    tiers_format(&sub_plan)
-                ),
-            )),
-            UserNoticeEvent::Raid { viewer_count, .. } => messages.push(easy_transform(
-                &msg,
-                Usernames::Raid,
-                format!(
-                    "{} just raided the channel with {} viewers!",
-                    msg.sender.login, viewer_count
-                ),
-            )),
-            UserNoticeEvent::GiftPaidUpgrade { gifter_name, .. } => messages.push(easy_transform(
-                &msg,
-                Usernames::Subscriber,
-                format!(
-                    "{} is continuing their gifted sub from {}",
-                    msg.sender.login, gifter_name
-                ),
-            )),
-            UserNoticeEvent::AnonGiftPaidUpgrade { .. } => messages.push(easy_transform(
-                &msg,
-                Usernames::Subscriber,
-                format!(
-                    "{} is continuing their anonymous gifted sub!",
-                    msg.sender.login,
-                ),
-            )),
-            UserNoticeEvent::Ritual { ritual_name: _ } => {}
-            UserNoticeEvent::BitsBadgeTier { threshold: _ } => {}
-            _ => {}
+            } => Some(EventKind::GiftUpgrade(GiftUpgrade {
+                user: sender,
+                gifter: Some(EventUser {
+                    id: None,
+                    login: gifter_login,
+                    display_name: Some(gifter_name),
+                }),
+            })),
+            UserNoticeEvent::AnonGiftPaidUpgrade { .. } => {
+                Some(EventKind::GiftUpgrade(GiftUpgrade {
+                    user: sender,
+                    gifter: None,
+                }))
+            }
+            UserNoticeEvent::Ritual { ritual_name } => Some(EventKind::Ritual(Ritual {
+                user: sender,
+                name: ritual_name,
+            })),
+            UserNoticeEvent::BitsBadgeTier { threshold } => Some(EventKind::BitsBadge(BitsBadge {
+                user: sender,
+                threshold,
+            })),
+            _ => None,
         };
-        messages.push(SimpleMessage {
-            id: None,
-            channel: msg.channel_login,
-            timestamp: msg.server_timestamp,
-            username: Usernames::System,
-            text: msg.system_message,
-        });
-        SimpleMessageGroup(messages)
+        if let Some(kind) = kind {
+            events.push(event(Some(msg.message_id.clone()), kind));
+        }
+        events.push(event(
+            None,
+            EventKind::Notice(Notice {
+                text: msg.system_message.clone(),
+            }),
+        ));
+        EventGroup(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use twitch_irc::message::{IRCPrefix, IRCTags};
+
+    use super::*;
+
+    fn privmsg(text: &str, reply_to: &str) -> IRCMessage {
+        let mut tags = HashMap::new();
+        tags.insert(
+            "reply-parent-msg-body".to_string(),
+            Some(reply_to.to_string()),
+        );
+        tags.insert(
+            "reply-parent-user-login".to_string(),
+            Some("bob".to_string()),
+        );
+        IRCMessage {
+            tags: IRCTags(tags),
+            prefix: Some(IRCPrefix::Full {
+                nick: "alice".into(),
+                user: Some("alice".into()),
+                host: Some("alice.tmi.twitch.tv".into()),
+            }),
+            command: "PRIVMSG".into(),
+            params: vec!["#xqcow".into(), text.into()],
+        }
+    }
+
+    #[test]
+    fn test_map_irc_text_redacts_replies() {
+        let redact = |text: &str| text.replace("bob@example.com", "[redacted]");
+
+        let mut message = privmsg("@bob got it", "mail me at bob@example.com");
+        assert!(map_irc_text(&mut message, redact));
+        assert_eq!(message.params[1], "@bob got it");
+        assert_eq!(
+            message.tags.0["reply-parent-msg-body"].as_deref(),
+            Some("mail me at [redacted]")
+        );
+
+        let mut message = privmsg("\u{1}ACTION is bob@example.com\u{1}", "hi");
+        assert!(map_irc_text(&mut message, redact));
+        assert_eq!(message.params[1], "\u{1}ACTION is [redacted]\u{1}");
+
+        let mut message = privmsg("nothing to see", "here either");
+        assert!(!map_irc_text(&mut message, redact));
     }
 }
@@ -0,0 +1,124 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{Context, Result};
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+
+use crate::settings::HelixSettings;
+
+const TOKEN_URL: &str = "https://id.twitch.tv/oauth2/token";
+const STREAMS_URL: &str = "https://api.twitch.tv/helix/streams";
+/// Helix takes at most 100 logins per request
+const LOGINS_PER_REQUEST: usize = 100;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Stream {
+    user_login: String,
+    viewer_count: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamsResponse {
+    data: Vec<Stream>,
+}
+
+/// Viewer counts aren't in chat, they come from the streams endpoint of the Helix API with an app
+/// access token.
+pub struct HelixClient {
+    http: Client,
+    config: HelixSettings,
+    token: Option<String>,
+}
+
+impl HelixClient {
+    pub fn new(config: HelixSettings) -> Result<Self> {
+        let http = Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        Ok(HelixClient {
+            http,
+            config,
+            token: None,
+        })
+    }
+
+    async fn token(&mut self) -> Result<String> {
+        if let Some(token) = &self.token {
+            return Ok(token.clone());
+        }
+        let response: TokenResponse = self
+            .http
+            .post(TOKEN_URL)
+            .form(&[
+                ("client_id", self.config.client_id.as_str()),
+                ("client_secret", self.config.client_secret.as_str()),
+                ("grant_type", "client_credentials"),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .context("Unexpected token response from twitch")?;
+        self.token = Some(response.access_token.clone());
+        Ok(response.access_token)
+    }
+
+    /// Viewers of every channel, 0 for the ones that aren't live
+    pub async fn viewer_counts(&mut self, logins: &[String]) -> Result<HashMap<String, u64>> {
+        let mut viewers = HashMap::new();
+        for chunk in logins.chunks(LOGINS_PER_REQUEST) {
+            let token = self.token().await?;
+            let mut query: Vec<(&str, &str)> = vec![("first", "100")];
+            query.extend(chunk.iter().map(|login| ("user_login", login.as_str())));
+            let response = self
+                .http
+                .get(STREAMS_URL)
+                .query(&query)
+                .header("Client-Id", &self.config.client_id)
+                .bearer_auth(token)
+                .send()
+                .await?;
+            // App tokens expire after a while, the next poll gets a new one
+            if response.status() == StatusCode::UNAUTHORIZED {
+                self.token = None;
+            }
+            let response: StreamsResponse = response
+                .error_for_status()?
+                .json()
+                .await
+                .context("Unexpected streams response from twitch")?;
+            viewers.extend(live_viewers(chunk, response));
+        }
+        Ok(viewers)
+    }
+}
+
+fn live_viewers(logins: &[String], response: StreamsResponse) -> HashMap<String, u64> {
+    let mut viewers: HashMap<String, u64> = logins.iter().map(|login| (login.clone(), 0)).collect();
+    for stream in response.data {
+        viewers.insert(stream.user_login.to_lowercase(), stream.viewer_count);
+    }
+    viewers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offline_channels_have_no_viewers() {
+        let response: StreamsResponse = serde_json::from_str(
+            r#"{"data":[{"id":"1","user_id":"2","user_login":"destiny","user_name":"Destiny",
+                "type":"live","viewer_count":12345}],"pagination":{}}"#,
+        )
+        .unwrap();
+        let viewers = live_viewers(&["destiny".to_string(), "offline".to_string()], response);
+        assert_eq!(viewers.get("destiny"), Some(&12345));
+        assert_eq!(viewers.get("offline"), Some(&0));
+    }
+}
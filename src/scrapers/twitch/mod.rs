@@ -1,64 +1,112 @@
 use serde::Deserialize;
 pub mod events;
+pub mod helix;
+pub mod sqlite_channels;
+pub mod transport;
 use std::{collections::HashSet, iter::FromIterator, sync::Arc, time::Duration};
 
 use anyhow::{Context, Result};
-use events::TwitchEvent;
-use log::error;
+use chrono::{DateTime, Utc};
+use events::{NamesReply, TwitchEvent, TwitchMessage};
+use log::{error, info};
 use reqwest::Client;
+use sqlite_channels::SqliteChannels;
 use tokio::{
     fs,
     sync::{
-        mpsc::{UnboundedReceiver, UnboundedSender},
+        mpsc::{Sender, UnboundedReceiver},
         Semaphore,
     },
 };
+use transport::MembershipTransport;
 use twitch_irc::{
-    login::StaticLoginCredentials, message::ServerMessage, ClientConfig, SecureWSTransport,
-    TwitchIRCClient,
+    login::StaticLoginCredentials,
+    message::{AsRawIRC, IRCMessage, ServerMessage},
+    ClientConfig, SecureWSTransport, TwitchIRCClient,
 };
 
 use crate::{
-    events::AllEvents,
+    events::{AllEvents, RawEvent},
+    formats::unified::ChannelType,
     settings::{ChannelsAdapter, TwitchSettings},
 };
 
+/// The membership capability is asked for by the transport, so the client's type depends on it
+pub enum IrcClient {
+    Plain(TwitchIRCClient<SecureWSTransport, StaticLoginCredentials>),
+    Membership(TwitchIRCClient<MembershipTransport, StaticLoginCredentials>),
+}
+
+impl IrcClient {
+    fn new(config: &TwitchSettings) -> (UnboundedReceiver<ServerMessage>, IrcClient) {
+        if config.membership {
+            let (incoming, client) = TwitchIRCClient::new(client_config());
+            (incoming, IrcClient::Membership(client))
+        } else {
+            let (incoming, client) = TwitchIRCClient::new(client_config());
+            (incoming, IrcClient::Plain(client))
+        }
+    }
+
+    pub fn set_wanted_channels(&self, channels: HashSet<String>) {
+        match self {
+            IrcClient::Plain(client) => client.set_wanted_channels(channels),
+            IrcClient::Membership(client) => client.set_wanted_channels(channels),
+        }
+    }
+}
+
+fn client_config() -> ClientConfig<StaticLoginCredentials> {
+    ClientConfig {
+        login_credentials: StaticLoginCredentials::anonymous(),
+        max_channels_per_connection: 20,
+
+        max_waiting_messages_per_connection: 5,
+        time_per_message: Duration::from_millis(150),
+
+        // 1 connection every 2 seconds seems to work well
+        connection_rate_limiter: Arc::new(Semaphore::new(3)),
+        new_connection_every: Duration::from_secs(5),
+        connect_timeout: Duration::from_secs(20),
+    }
+}
+
 pub struct TwitchScraper {
-    pub client: TwitchIRCClient<SecureWSTransport, StaticLoginCredentials>,
+    pub client: IrcClient,
     config: TwitchSettings,
+    sqlite_channels: Option<SqliteChannels>,
 }
 
 impl TwitchScraper {
-    pub fn start(sender: UnboundedSender<AllEvents>, config: TwitchSettings) -> Arc<TwitchScraper> {
-        let client_config = ClientConfig {
-            login_credentials: StaticLoginCredentials::anonymous(),
-            max_channels_per_connection: 20,
-
-            max_waiting_messages_per_connection: 5,
-            time_per_message: Duration::from_millis(150),
-
-            // 1 connection every 2 seconds seems to work well
-            connection_rate_limiter: Arc::new(Semaphore::new(3)),
-            new_connection_every: Duration::from_secs(5),
-            connect_timeout: Duration::from_secs(20),
+    pub async fn start(
+        sender: Sender<AllEvents>,
+        config: TwitchSettings,
+    ) -> Result<Arc<TwitchScraper>> {
+        let sqlite_channels = match &config.channels {
+            ChannelsAdapter::Sqlite(sqlite_config) => {
+                Some(SqliteChannels::connect(sqlite_config).await?)
+            }
+            _ => None,
         };
-        let (incoming_messages, client) =
-            TwitchIRCClient::<SecureWSTransport, StaticLoginCredentials>::new(client_config);
+
+        let (incoming_messages, client) = IrcClient::new(&config);
 
         // first thing you should do: start consuming incoming messages,
         // otherwise they will back up.
 
-        tokio::spawn({
-            async move { TwitchScraper::run_forwarder(incoming_messages, &sender).await }
-        });
+        tokio::spawn(async move { TwitchScraper::run_forwarder(incoming_messages, &sender).await });
 
-        let scraper = Arc::new(TwitchScraper { client, config });
+        let scraper = Arc::new(TwitchScraper {
+            client,
+            config,
+            sqlite_channels,
+        });
         tokio::spawn({
             let scraper = scraper.clone();
             async move { scraper.run_channel_syncer().await }
         });
 
-        scraper
+        Ok(scraper)
     }
 
     pub async fn hydrate_channels(&self) -> Result<Vec<String>> {
@@ -70,7 +118,13 @@ impl TwitchScraper {
                 let channels: Vec<String> = serde_json::from_str(&content)?;
                 Ok(channels)
             }
-            ChannelsAdapter::Sqlite { path: _, table: _ } => Ok(vec!["Xqcow".to_string()]),
+            ChannelsAdapter::Sqlite(_) => {
+                self.sqlite_channels
+                    .as_ref()
+                    .context("Sqlite channels adapter wasn't connected")?
+                    .fetch_channels()
+                    .await
+            }
             ChannelsAdapter::Http { url, bearer_token } => {
                 let response = Client::new()
                     .get(url)
@@ -92,13 +146,17 @@ impl TwitchScraper {
         ));
     }
 
-    async fn run_forwarder(
-        mut rx: UnboundedReceiver<ServerMessage>,
-        sender: &UnboundedSender<AllEvents>,
-    ) {
+    /// Passes messages on to the ingester
+    async fn run_forwarder(mut rx: UnboundedReceiver<ServerMessage>, sender: &Sender<AllEvents>) {
+        // While the ingester is blocked, messages wait in the client's own channel
         while let Some(raw) = rx.recv().await {
-            if let Some(msg) = TwitchScraper::map_message(raw) {
-                sender.send(msg.into()).unwrap();
+            if sender
+                .send(TwitchScraper::to_event(raw, Utc::now()))
+                .await
+                .is_err()
+            {
+                info!("Event channel closed, stopping twitch forwarder");
+                return;
             }
         }
     }
@@ -119,20 +177,47 @@ impl TwitchScraper {
         }
     }
 
-    pub fn map_message(raw: ServerMessage) -> Option<TwitchEvent> {
+    /// Maps a server message to a typed event, falling back to a raw event so that nothing the
+    /// client receives is lost for the raw archive.
+    pub fn to_event(raw: ServerMessage, received_at: DateTime<Utc>) -> AllEvents {
+        let source = raw.source().clone();
+        match TwitchScraper::map_message(raw) {
+            Some(message) => TwitchEvent::new(message, received_at).into(),
+            None => raw_event(&source, received_at).into(),
+        }
+    }
+
+    pub fn map_message(raw: ServerMessage) -> Option<TwitchMessage> {
         Some(match raw {
-            ServerMessage::Privmsg(msg) => TwitchEvent::Privmsg(msg),
-            ServerMessage::UserNotice(msg) => TwitchEvent::UserNotice(msg),
-            ServerMessage::ClearChat(msg) => TwitchEvent::ClearChat(msg),
-            // ServerMessage::HostTarget(msg) => TwitchEvent::HostTarget(msg),
-            _ => {
-                // println!("Some random thing: {}", raw.source().command);
-                return None;
-            }
+            ServerMessage::Privmsg(msg) => TwitchMessage::Privmsg(msg),
+            ServerMessage::UserNotice(msg) => TwitchMessage::UserNotice(msg),
+            ServerMessage::ClearChat(msg) => TwitchMessage::ClearChat(msg),
+            // Anonymous logins like ours aren't chatters
+            ServerMessage::Join(msg) if !is_anonymous(&msg.user_login) => TwitchMessage::Join(msg),
+            ServerMessage::Part(msg) if !is_anonymous(&msg.user_login) => TwitchMessage::Part(msg),
+            // ServerMessage::HostTarget(msg) => TwitchMessage::HostTarget(msg),
+            _ => TwitchMessage::Names(NamesReply::parse(raw.source())?),
         })
     }
 }
 
+fn is_anonymous(login: &str) -> bool {
+    login.starts_with("justinfan")
+}
+
+fn raw_event(source: &IRCMessage, received_at: DateTime<Utc>) -> RawEvent {
+    RawEvent {
+        platform: ChannelType::Twitch,
+        channel: source
+            .params
+            .first()
+            .and_then(|p| p.strip_prefix('#'))
+            .map(|c| c.to_string()),
+        received_at,
+        data: source.as_raw_irc(),
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 struct GenericHttpResponse<T> {
     data: T,
@@ -0,0 +1,212 @@
+use anyhow::{bail, Context, Result};
+use log::info;
+use sqlx::{Row, SqlitePool};
+
+use crate::{settings::SqliteChannelsSettings, sqlite_pool::create_sqlite};
+
+/// Channel list backed by a table in a sqlite database, typically one that's maintained by
+/// some other channel management tool.
+pub struct SqliteChannels {
+    pool: SqlitePool,
+    query: String,
+}
+
+impl SqliteChannels {
+    /// Opens the database and validates that the configured table and columns exist, so that a
+    /// misconfiguration is caught at startup rather than on the first channel sync.
+    pub async fn connect(config: &SqliteChannelsSettings) -> Result<SqliteChannels> {
+        let pool = create_sqlite(&config.path)
+            .await
+            .with_context(|| format!("Opening channels sqlite db '{}' failed", config.path))?;
+
+        validate_schema(&pool, config).await?;
+        let query = build_channels_query(config)?;
+        info!(
+            "Using sqlite channels table '{}' from '{}'",
+            config.table, config.path
+        );
+
+        Ok(SqliteChannels { pool, query })
+    }
+
+    pub async fn fetch_channels(&self) -> Result<Vec<String>> {
+        let channels: Vec<Option<String>> = sqlx::query_scalar(&self.query)
+            .fetch_all(&self.pool)
+            .await
+            .with_context(|| "Querying sqlite channels table failed")?;
+
+        Ok(channels
+            .into_iter()
+            .flatten()
+            .map(|c| c.trim().to_string())
+            .filter(|c| !c.is_empty())
+            .collect())
+    }
+}
+
+async fn validate_schema(pool: &SqlitePool, config: &SqliteChannelsSettings) -> Result<()> {
+    let table = quote_identifier(&config.table)?;
+    let rows = sqlx::query(&format!("PRAGMA table_info({})", table))
+        .fetch_all(pool)
+        .await?;
+    if rows.is_empty() {
+        bail!(
+            "Channels table '{}' doesn't exist in '{}'",
+            config.table,
+            config.path
+        );
+    }
+
+    let columns = rows
+        .iter()
+        .map(|row| row.try_get::<String, _>("name"))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let required = std::iter::once(&config.channel_column)
+        .chain(config.enabled_column.iter())
+        .chain(config.priority_column.iter());
+    for column in required {
+        if !columns.iter().any(|c| c.eq_ignore_ascii_case(column)) {
+            bail!(
+                "Channels table '{}' is missing column '{}', found columns: {:?}",
+                config.table,
+                column,
+                columns
+            );
+        }
+    }
+    Ok(())
+}
+
+fn build_channels_query(config: &SqliteChannelsSettings) -> Result<String> {
+    let channel = quote_identifier(&config.channel_column)?;
+    let mut query = format!(
+        "SELECT {} FROM {}",
+        channel,
+        quote_identifier(&config.table)?
+    );
+
+    if let Some(enabled) = &config.enabled_column {
+        query += &format!(" WHERE {}", quote_identifier(enabled)?);
+    }
+
+    match &config.priority_column {
+        Some(priority) => {
+            let priority = quote_identifier(priority)?;
+            query += &format!(
+                " ORDER BY {} IS NULL, {} DESC, {}",
+                priority, priority, channel
+            );
+        }
+        None => query += &format!(" ORDER BY {}", channel),
+    }
+
+    if let Some(limit) = config.limit {
+        query += &format!(" LIMIT {}", limit);
+    }
+
+    Ok(query)
+}
+
+/// Table and column names can't be bound as parameters, so only allow plain identifiers.
+fn quote_identifier(identifier: &str) -> Result<String> {
+    let valid = identifier
+        .chars()
+        .next()
+        .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && identifier
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        bail!(
+            "Invalid sqlite identifier in channels config: '{}'",
+            identifier
+        );
+    }
+    Ok(format!("\"{}\"", identifier))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> SqliteChannelsSettings {
+        SqliteChannelsSettings {
+            path: "channels.db".into(),
+            table: "channels".into(),
+            channel_column: "login".into(),
+            enabled_column: None,
+            priority_column: None,
+            limit: None,
+        }
+    }
+
+    #[test]
+    fn test_build_minimal_query() {
+        let query = build_channels_query(&settings()).unwrap();
+        assert_eq!(query, r#"SELECT "login" FROM "channels" ORDER BY "login""#);
+    }
+
+    #[test]
+    fn test_build_full_query() {
+        let config = SqliteChannelsSettings {
+            enabled_column: Some("enabled".into()),
+            priority_column: Some("priority".into()),
+            limit: Some(100),
+            ..settings()
+        };
+        let query = build_channels_query(&config).unwrap();
+        assert_eq!(
+            query,
+            r#"SELECT "login" FROM "channels" WHERE "enabled" ORDER BY "priority" IS NULL, "priority" DESC, "login" LIMIT 100"#
+        );
+    }
+
+    #[test]
+    fn test_rejects_invalid_identifiers() {
+        assert!(quote_identifier("channels").is_ok());
+        assert!(quote_identifier("_channels_2").is_ok());
+        assert!(quote_identifier("").is_err());
+        assert!(quote_identifier("2channels").is_err());
+        assert!(quote_identifier("channels; DROP TABLE x").is_err());
+        assert!(quote_identifier("chan\"nels").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_channels() {
+        let path = std::env::temp_dir().join(format!("tl2-channels-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = SqliteChannelsSettings {
+            path: path.to_str().unwrap().into(),
+            enabled_column: Some("enabled".into()),
+            priority_column: Some("priority".into()),
+            ..settings()
+        };
+
+        assert!(SqliteChannels::connect(&config).await.is_err());
+
+        let pool = create_sqlite(&config.path).await.unwrap();
+        sqlx::query(
+            r#"
+              CREATE TABLE channels (login TEXT, enabled INTEGER, priority INTEGER);
+              INSERT INTO channels VALUES
+                ('xqc', 1, 1),
+                ('destiny ', 1, 10),
+                ('disabled', 0, 100),
+                ('nopriority', 1, NULL);
+            "#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        pool.close().await;
+
+        let channels = SqliteChannels::connect(&config).await.unwrap();
+        assert_eq!(
+            channels.fetch_channels().await.unwrap(),
+            vec!["destiny", "xqc", "nopriority"]
+        );
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
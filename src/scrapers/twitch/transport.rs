@@ -0,0 +1,61 @@
+use async_trait::async_trait;
+use futures::{future, SinkExt};
+use twitch_irc::{message::IRCMessage, transport::Transport, SecureWSTransport};
+
+const MEMBERSHIP_CAPABILITY: &str = "twitch.tv/membership";
+
+/// `SecureWSTransport` that adds the membership capability to the `CAP REQ` the client sends
+/// first on every connection. Each connection of the pool asks for it when it's opened or
+/// reopened, so channels are spread over connections as usual.
+#[derive(Debug)]
+pub struct MembershipTransport(SecureWSTransport);
+
+#[async_trait]
+impl Transport for MembershipTransport {
+    type ConnectError = <SecureWSTransport as Transport>::ConnectError;
+    type IncomingError = <SecureWSTransport as Transport>::IncomingError;
+    type OutgoingError = <SecureWSTransport as Transport>::OutgoingError;
+
+    type Incoming = <SecureWSTransport as Transport>::Incoming;
+    type Outgoing = <SecureWSTransport as Transport>::Outgoing;
+
+    async fn new() -> Result<Self, Self::ConnectError> {
+        Ok(MembershipTransport(SecureWSTransport::new().await?))
+    }
+
+    fn split(self) -> (Self::Incoming, Self::Outgoing) {
+        let (incoming, outgoing) = self.0.split();
+        let outgoing = outgoing.with(|message| future::ready(Ok(with_membership(message))));
+        (incoming, Box::new(outgoing))
+    }
+}
+
+fn with_membership(mut message: IRCMessage) -> IRCMessage {
+    if message.command == "CAP" && matches!(message.params.first(), Some(p) if p == "REQ") {
+        if let Some(capabilities) = message.params.get_mut(1) {
+            if !capabilities.split(' ').any(|c| c == MEMBERSHIP_CAPABILITY) {
+                capabilities.push(' ');
+                capabilities.push_str(MEMBERSHIP_CAPABILITY);
+            }
+        }
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use twitch_irc::message::AsRawIRC;
+
+    use super::*;
+
+    #[test]
+    fn test_adds_membership_to_capability_request() {
+        let request = IRCMessage::parse("CAP REQ :twitch.tv/tags twitch.tv/commands").unwrap();
+        assert_eq!(
+            with_membership(request).as_raw_irc(),
+            "CAP REQ :twitch.tv/tags twitch.tv/commands twitch.tv/membership"
+        );
+        let nick = IRCMessage::parse("NICK justinfan12345").unwrap();
+        assert_eq!(with_membership(nick).as_raw_irc(), "NICK justinfan12345");
+    }
+}
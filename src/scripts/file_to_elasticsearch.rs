@@ -21,14 +21,16 @@ use futures::TryStreamExt;
 use log::debug;
 use log::info;
 use log::warn;
-use serde_json::json;
 use serde_json::Value;
 use tokio::time;
 
 use crate::adapters::elasticsearch::create_elasticsearch_client_from_url;
-use crate::adapters::elasticsearch::initialize_template;
+use crate::adapters::elasticsearch::setup::initialize_template;
+use crate::adapters::elasticsearch::setup::IndexLayout;
+use crate::adapters::elasticsearch::BulkDocument;
 use crate::formats::orl::CleanOrlLog;
 use crate::formats::orl::OrlLog;
+use crate::formats::unified::OrlLog1_0;
 use crate::sources::orl::orl_file_parser::parse_file_to_logs;
 use crate::sources::orl::orl_file_parser::read_orl_structured_dir;
 use crate::sources::orl::orl_file_parser::OrlDirFile;
@@ -72,28 +74,16 @@ pub async fn write_elastic_chunk(
     index: &str,
     pipeline: Option<&str>,
 ) -> Result<()> {
+    let layout = IndexLayout::monthly(index);
     let mut body: Vec<JsonBody<_>> = Vec::with_capacity(chunk.len() * 2);
     for msg in chunk {
-        let username = msg.username.to_string();
+        // Indices this script filled before use `<channel>-<username>-<ts>` ids rather than the
+        // `CommonKey` ones, keep them so running it again overwrites instead of duplicating
         let ts = msg.ts.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
-
-        // 2020-12-12 - 10 characters for ymd of an ISO date
-        let ymd_first_of_mo = ts[..7].to_string() + "-01";
-
-        let index_with_date = index.to_string() + "-" + &ymd_first_of_mo;
-
         let id = msg.channel.clone() + "-" + &msg.username + "-" + &ts;
-
-        body.push(json!({ "index": { "_index": &index_with_date , "_id": id}}).into());
-        body.push(
-            json!({
-                "channel": msg.channel,
-                "username": username,
-                "text": msg.text,
-                "ts": ts
-            })
-            .into(),
-        );
+        let mut doc = BulkDocument::from_log(&layout, OrlLog1_0::from(msg));
+        doc.id = id;
+        doc.push_to(&mut body);
     }
     let mut req = client.bulk(BulkParts::Index("non-index-placeholder"));
     if let Some(pipeline) = &pipeline {
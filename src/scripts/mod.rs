@@ -1,19 +1,39 @@
 use std::path::PathBuf;
 use std::time::Instant;
 
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use bytesize::ByteSize;
 use futures::future;
 use futures::StreamExt;
-
+use log::info;
+
+use crate::adapters::console::ConsoleWriter;
+use crate::adapters::elasticsearch::create_elasticsearch_client;
+use crate::adapters::elasticsearch::setup;
+use crate::adapters::RoutedWriter;
+use crate::alerts::DiscordAlerting;
+use crate::processing::Processor;
+use crate::run_scrape_ingester::create_writers;
+use crate::settings::RouteSettings;
+use crate::settings::Settings;
+use crate::settings::WriterKindSettings;
 use crate::sinks::clickhouse_bulk::ClickhouseBulkSink;
 use crate::sinks::elasticsearch_bulk::ElasticsearchBulkSink;
 use crate::sinks::jsonl::JsonFileSink;
+use crate::sinks::sqlite::moderation::query_moderation;
+use crate::sinks::sqlite::moderation::ModerationQuery;
+use crate::sinks::sqlite::usernames::init_username_tables;
+use crate::sinks::sqlite::usernames::query_user_history;
+use crate::sinks::writers::WritersSink;
+use crate::sources::capture::CaptureSource;
 use crate::sources::jsonl::JsonFileSource;
 use crate::sources::jsonl::KnownSize;
 use crate::sources::orl::OrlFileSource;
+use crate::sources::twitch_raw::TwitchRawSource;
 use crate::sources::Source;
+use crate::sqlite_pool::create_sqlite;
 
 pub mod file_to_clickhouse;
 pub mod file_to_elasticsearch;
@@ -56,6 +76,97 @@ pub async fn jsonl_to_clickhouse(input_directory: PathBuf, clickhouse_url: Strin
     Ok(())
 }
 
+pub async fn twitch_raw_to_console(input_directory: PathBuf) -> Result<()> {
+    let mut twitch_raw_source = TwitchRawSource::new(input_directory);
+    let console = RoutedWriter::new("console", ConsoleWriter::new(), &RouteSettings::default());
+    let writers_sink = WritersSink::new(vec![console], None);
+
+    twitch_raw_source.pipe(writers_sink).await
+}
+
+/// Pushes a capture through the writers enabled in the config, as if it was being scraped live.
+pub async fn replay(capture: PathBuf, speed: f64) -> Result<()> {
+    let settings = Settings::new()?;
+    let alerting = DiscordAlerting::new(settings.discord_alerting);
+
+    let processor = Processor::new(settings.processing).await?;
+
+    let mut capture_source = CaptureSource::new(capture, speed);
+    let writers = create_writers(settings.writers, &alerting).await?;
+    let writers_sink = WritersSink::new(writers, Some(processor));
+
+    capture_source.pipe(writers_sink).await
+}
+
+/// Creates the index templates, lifecycle policies, pipelines and first indices or data streams
+/// of every enabled elasticsearch writer. Writers also do this when they start, this is for
+/// setting a cluster up ahead of time.
+pub async fn init() -> Result<()> {
+    let settings = Settings::new()?;
+
+    let mut count = 0;
+    for writer in settings.writers.iter().filter(|writer| writer.enabled) {
+        if let WriterKindSettings::Elasticsearch(config) = &writer.kind {
+            info!("Initializing elasticsearch for writer '{}'", writer.name);
+            let client = create_elasticsearch_client(config)?;
+            setup::initialize(&client, config)
+                .await
+                .with_context(|| format!("Initializing writer '{}' failed", writer.name))?;
+            count += 1;
+        }
+    }
+    info!("Initialized {} elasticsearch writers", count);
+    Ok(())
+}
+
+/// Prints the moderation log entries written by a `moderation_log` writer that match the query.
+pub async fn moderation(sqlite_path: PathBuf, query: ModerationQuery) -> Result<()> {
+    if !sqlite_path.exists() {
+        bail!("No moderation log database at {:?}", sqlite_path);
+    }
+    let path = sqlite_path
+        .to_str()
+        .with_context(|| format!("Invalid sqlite path: {:?}", sqlite_path))?;
+    let pool = create_sqlite(path).await?;
+
+    let logs = query_moderation(&pool, &query).await?;
+    for log in &logs {
+        println!("{}", log);
+    }
+    info!("Found {} moderation actions", logs.len());
+    Ok(())
+}
+
+/// Prints every login of the twitch ids that used `name_or_id` as their login or have it as their
+/// id, as kept by a `username_tracker` writer.
+pub async fn user_history(sqlite_path: PathBuf, name_or_id: &str) -> Result<()> {
+    if !sqlite_path.exists() {
+        bail!("No username database at {:?}", sqlite_path);
+    }
+    let path = sqlite_path
+        .to_str()
+        .with_context(|| format!("Invalid sqlite path: {:?}", sqlite_path))?;
+    let pool = create_sqlite(path).await?;
+    // Databases written before first sightings were tracked are migrated
+    init_username_tables(&pool).await?;
+
+    let history = query_user_history(&pool, name_or_id).await?;
+    for (i, entry) in history.iter().enumerate() {
+        if i == 0 || history[i - 1].twitch_id != entry.twitch_id {
+            println!("twitch id {}:", entry.twitch_id);
+        }
+        let is_current = history
+            .iter()
+            .filter(|other| other.twitch_id == entry.twitch_id)
+            .all(|other| other.last_seen <= entry.last_seen);
+        println!("  {}{}", entry, if is_current { " (current)" } else { "" });
+    }
+    if history.is_empty() {
+        info!("No twitch user has used {} as login or id", name_or_id);
+    }
+    Ok(())
+}
+
 pub async fn jsonl_to_console(input_directory: PathBuf) -> Result<()> {
     let json_file_source = JsonFileSource::new(input_directory);
 
@@ -5,67 +5,446 @@ use config::{Config, Environment, File};
 use log::info;
 use serde::Deserialize;
 
+use crate::{events::EventCategory, formats::unified::ChannelType};
+
 #[derive(Clone, Debug, Deserialize)]
-pub struct DiscordAlertingSettings {
-    pub enabled: bool,
-    pub webhook_url: Option<String>,
-    pub owner: Option<String>,
+pub struct IncludeExclude<T> {
+    /// When not empty, only these values are allowed
+    #[serde(default = "Vec::new")]
+    pub include: Vec<T>,
+    #[serde(default = "Vec::new")]
+    pub exclude: Vec<T>,
 }
-#[derive(Clone, Debug, Deserialize)]
-pub struct ConsoleMetricsSettings {
-    pub enabled: bool,
+
+impl<T> Default for IncludeExclude<T> {
+    fn default() -> Self {
+        IncludeExclude {
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
 }
+
+/// Which events a writer receives, by default it receives everything.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RouteSettings {
+    #[serde(default)]
+    pub channels: IncludeExclude<String>,
+    #[serde(default)]
+    pub platforms: IncludeExclude<ChannelType>,
+    #[serde(default)]
+    pub kinds: IncludeExclude<EventCategory>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
-pub struct ConsoleSettings {
+pub struct DiscordAlertingSettings {
     pub enabled: bool,
+    pub webhook_url: Option<String>,
+    pub owner: Option<String>,
 }
 #[derive(Clone, Debug, Deserialize)]
 pub struct ElasticsearchSettings {
-    pub enabled: bool,
     pub host: String,
     pub port: u32,
+    /// Basic auth, Elasticsearch 8 has security on by default
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// API key auth, used instead of a username and password
+    pub api_key_id: Option<String>,
+    pub api_key: Option<String>,
+    /// PEM file of the CA that signed the cluster's certificate, e.g. the `http_ca.crt` a new
+    /// Elasticsearch 8 node generates. The system's CAs are trusted when unset.
+    pub ca_cert_path: Option<String>,
     pub index: String,
     pub pipeline: Option<String>,
     pub batch_size: u64,
+    /// Buffered documents are sent at least this often, even if the batch isn't full
     pub batch_period_seconds: u64,
     pub max_retry_seconds: u64,
+    /// Documents elasticsearch rejected for good are appended here, with the reason
+    #[serde(default = "default_elasticsearch_dead_letter_path")]
+    pub dead_letter_path: String,
+    /// Elasticsearch or OpenSearch, they manage index lifecycles with different APIs
+    #[serde(default = "default_search_distribution")]
+    pub distribution: SearchDistribution,
+    #[serde(default = "default_index_mode")]
+    pub index_mode: IndexMode,
+    /// Only used by the rollover and data stream index modes
+    #[serde(default)]
+    pub rollover: RolloverSettings,
+}
+
+fn default_elasticsearch_dead_letter_path() -> String {
+    "./data/dead_letter/elasticsearch.jsonl".to_string()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchDistribution {
+    /// Elasticsearch 7.13 or later, lifecycles are ILM policies
+    Elasticsearch,
+    /// OpenSearch 1.0 or later, lifecycles are ISM policies
+    Opensearch,
+}
+
+/// How documents are spread over indices
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexMode {
+    /// `<index>-<YYYY-MM>-01`, the same indices the archive importers write to
+    Monthly,
+    /// Writes go to the `<index>` alias, which a lifecycle policy rolls over to a new
+    /// `<index>-000001` index
+    Rollover,
+    /// Writes go to the `<index>` data stream, which a lifecycle policy rolls over
+    DataStream,
+}
+
+fn default_search_distribution() -> SearchDistribution {
+    SearchDistribution::Elasticsearch
+}
+
+fn default_index_mode() -> IndexMode {
+    IndexMode::Monthly
+}
+
+/// When the lifecycle policy rolls over to a new index, whichever is reached first
+#[derive(Clone, Debug, Deserialize)]
+pub struct RolloverSettings {
+    #[serde(default = "default_rollover_max_age")]
+    pub max_age: String,
+    #[serde(default = "default_rollover_max_primary_size")]
+    pub max_primary_size: String,
+    /// Rolled over indices are deleted once they're this old, they're kept forever when unset
+    pub delete_after: Option<String>,
+}
+
+impl Default for RolloverSettings {
+    fn default() -> Self {
+        RolloverSettings {
+            max_age: default_rollover_max_age(),
+            max_primary_size: default_rollover_max_primary_size(),
+            delete_after: None,
+        }
+    }
+}
+
+fn default_rollover_max_age() -> String {
+    "30d".to_string()
+}
+
+fn default_rollover_max_primary_size() -> String {
+    "50gb".to_string()
 }
 #[derive(Clone, Debug, Deserialize)]
 pub struct ClickhouseSettings {
-    pub enabled: bool,
     pub url: String,
     pub db_name: Option<String>,
     pub db_user: Option<String>,
     pub db_pass: Option<String>,
+    /// Rows per table before an INSERT is sent
+    #[serde(default = "default_clickhouse_batch_size")]
+    pub batch_size: u64,
+    /// Buffered rows are sent at least this often, even if the batch isn't full
+    #[serde(default = "default_clickhouse_batch_period_seconds")]
+    pub batch_period_seconds: u64,
+}
+
+fn default_clickhouse_batch_size() -> u64 {
+    10_000
+}
+
+fn default_clickhouse_batch_period_seconds() -> u64 {
+    5
 }
 #[derive(Clone, Debug, Deserialize)]
 pub struct FileSettings {
-    pub enabled: bool,
     pub path: String,
+    /// Buffered lines are written out once the oldest is this old, even if the channel is quiet
+    #[serde(default = "default_file_max_buffered_seconds")]
+    pub max_buffered_seconds: u64,
+    /// Lines buffered per channel before they're written out
+    #[serde(default = "default_file_max_buffered_lines")]
+    pub max_buffered_lines: usize,
+    /// Files kept open for appending, the least recently written one is closed first
+    #[serde(default = "default_file_max_open_files")]
+    pub max_open_files: usize,
+    /// Sync every write to disk, slower but nothing is lost if the machine goes down
+    #[serde(default)]
+    pub fsync: bool,
+    /// Codec that files of finished days are compressed with, they're left as they are by default
+    /// so nothing reading the directory sees its files change unless asked for
+    #[serde(default = "default_file_compression")]
+    pub compression: Compression,
+    /// How long after the end of a UTC day its files are compressed, late events can still be
+    /// appended until then
+    #[serde(default = "default_file_compress_grace_seconds")]
+    pub compress_grace_seconds: u64,
+}
+
+/// Compression of finished log files
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    /// Leave the files as they are
+    None,
+    Gzip,
+    Zstd,
+    Brotli,
+}
+
+fn default_file_max_buffered_seconds() -> u64 {
+    5
+}
+
+fn default_file_max_buffered_lines() -> usize {
+    50
+}
+
+fn default_file_max_open_files() -> usize {
+    256
+}
+
+fn default_file_compression() -> Compression {
+    Compression::None
+}
+
+fn default_file_compress_grace_seconds() -> u64 {
+    3600
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct UsernameTrackerSettings {
-    pub enabled: bool,
     pub sqlite_path: String,
     pub batch_size: u64,
+    /// Updates are written once the oldest has waited this long, even if the batch isn't full
+    #[serde(default = "default_username_tracker_batch_period_seconds")]
+    pub batch_period_seconds: u64,
+    /// Also send every twitch rename as an info alert, they're always logged and kept for
+    /// `tl2 user-history`. Big chats see renames every few minutes.
+    #[serde(default)]
+    pub alert_renames: bool,
+}
+
+fn default_username_tracker_batch_period_seconds() -> u64 {
+    5
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ModerationLogSettings {
+    pub sqlite_path: String,
+    /// Entries buffered before they're written, a flush or shutdown writes them out sooner
+    #[serde(default = "default_moderation_log_batch_size")]
+    pub batch_size: u64,
+    /// Entries are written once the oldest has waited this long, even if the batch isn't full
+    #[serde(default = "default_moderation_log_batch_period_seconds")]
+    pub batch_period_seconds: u64,
+}
+
+fn default_moderation_log_batch_size() -> u64 {
+    100
+}
+
+fn default_moderation_log_batch_period_seconds() -> u64 {
+    5
+}
+
+/// Snapshots of who's in which chat, written to sqlite, clickhouse or both
+#[derive(Clone, Debug, Deserialize)]
+pub struct PresenceSettings {
+    #[serde(default = "default_presence_snapshot_interval_seconds")]
+    pub snapshot_interval_seconds: u64,
+    pub sqlite_path: Option<String>,
+    pub clickhouse: Option<ClickhouseSettings>,
+    /// Poll the viewer counts of the twitch chats with every snapshot
+    pub helix: Option<HelixSettings>,
+}
+
+/// Credentials of a twitch application, for an app access token
+#[derive(Clone, Debug, Deserialize)]
+pub struct HelixSettings {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+fn default_presence_snapshot_interval_seconds() -> u64 {
+    60
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CaptureSettings {
+    pub path: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WriterKindSettings {
+    Elasticsearch(ElasticsearchSettings),
+    Clickhouse(ClickhouseSettings),
+    Filesystem(FileSettings),
+    Jsonl(FileSettings),
+    ModerationJsonl(FileSettings),
+    TwitchRaw(FileSettings),
+    Console,
+    ConsoleMetrics,
+    UsernameTracker(UsernameTrackerSettings),
+    ModerationLog(ModerationLogSettings),
+    Presence(PresenceSettings),
+    Capture(CaptureSettings),
+}
+
+/// How a failed writer is restarted
+#[derive(Clone, Debug, Deserialize)]
+pub struct RestartSettings {
+    /// Delay before the first restart attempt, doubled after every failed attempt
+    #[serde(default = "default_restart_base_delay_seconds")]
+    pub base_delay_seconds: u64,
+    #[serde(default = "default_restart_max_delay_seconds")]
+    pub max_delay_seconds: u64,
+    /// Events kept for the writer while it's down or behind, past this the queue's overflow
+    /// policy applies
+    #[serde(default = "default_restart_max_buffered_events")]
+    pub max_buffered_events: usize,
+}
+
+impl Default for RestartSettings {
+    fn default() -> Self {
+        RestartSettings {
+            base_delay_seconds: default_restart_base_delay_seconds(),
+            max_delay_seconds: default_restart_max_delay_seconds(),
+            max_buffered_events: default_restart_max_buffered_events(),
+        }
+    }
+}
+
+fn default_restart_base_delay_seconds() -> u64 {
+    5
+}
+
+fn default_restart_max_delay_seconds() -> u64 {
+    300
+}
+
+fn default_restart_max_buffered_events() -> usize {
+    100_000
+}
+
+/// What happens to new events when a writer's queue is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Stop ingesting until the writer catches up, this stalls every other writer too
+    Block,
+    DropOldest,
+    /// Append to a file in the spill directory and read it back once the writer catches up
+    Spill,
 }
 
+/// What to do with events once a writer has `restart.max_buffered_events` waiting
 #[derive(Clone, Debug, Deserialize)]
-pub struct WritersSettings {
-    pub elasticsearch: ElasticsearchSettings,
-    pub clickhouse: ClickhouseSettings,
-    pub filesystem: FileSettings,
-    pub console: ConsoleSettings,
-    pub console_metrics: ConsoleMetricsSettings,
-    pub username_tracker: UsernameTrackerSettings,
+pub struct QueueSettings {
+    #[serde(default = "default_overflow_policy")]
+    pub overflow: OverflowPolicy,
+    #[serde(default = "default_spill_directory")]
+    pub spill_directory: String,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        QueueSettings {
+            overflow: default_overflow_policy(),
+            spill_directory: default_spill_directory(),
+        }
+    }
+}
+
+fn default_overflow_policy() -> OverflowPolicy {
+    OverflowPolicy::DropOldest
+}
+
+fn default_spill_directory() -> String {
+    "./data/spill".into()
+}
+
+/// Write-ahead log for network writers, events are written to disk before they're sent and only
+/// dropped from it once they're committed downstream, so outages and restarts don't lose them.
+#[derive(Clone, Debug, Deserialize)]
+pub struct WalSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Each writer keeps its log in a subdirectory named after it
+    #[serde(default = "default_wal_directory")]
+    pub directory: String,
+    #[serde(default = "default_wal_segment_size_mb")]
+    pub segment_size_mb: u64,
+}
+
+impl Default for WalSettings {
+    fn default() -> Self {
+        WalSettings {
+            enabled: false,
+            directory: default_wal_directory(),
+            segment_size_mb: default_wal_segment_size_mb(),
+        }
+    }
+}
+
+fn default_wal_directory() -> String {
+    "./data/wal".into()
+}
+
+fn default_wal_segment_size_mb() -> u64 {
+    64
+}
+
+/// A single named writer instance, the same type can be configured several times, e.g. to
+/// write to two elasticsearch clusters at once.
+#[derive(Clone, Debug, Deserialize)]
+pub struct WriterSettings {
+    /// Unique name used in logs and alerts
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub routes: RouteSettings,
+    #[serde(default)]
+    pub restart: RestartSettings,
+    #[serde(default)]
+    pub queue: QueueSettings,
+    #[serde(default)]
+    pub wal: WalSettings,
+    #[serde(flatten)]
+    pub kind: WriterKindSettings,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SqliteChannelsSettings {
+    pub path: String,
+    pub table: String,
+    /// Column holding the channel login
+    #[serde(default = "default_channel_column")]
+    pub channel_column: String,
+    /// Optional boolean column, rows where it is false (0) or NULL are skipped
+    pub enabled_column: Option<String>,
+    /// Optional numeric column, higher priority channels are returned first
+    pub priority_column: Option<String>,
+    /// Optional cap on the number of channels joined, applied after priority ordering
+    pub limit: Option<u32>,
+}
+
+fn default_channel_column() -> String {
+    "channel".into()
 }
 
 #[derive(Clone, Debug, Deserialize)]
 #[serde(tag = "adapter")]
 pub enum ChannelsAdapter {
     Json { path: String },
-    Sqlite { path: String, table: String },
+    Sqlite(SqliteChannelsSettings),
     Http { url: String, bearer_token: String },
 }
 
@@ -75,6 +454,11 @@ pub struct TwitchSettings {
     pub sync_channels_interval: u64,
     pub use_websocket: bool,
     pub channels: ChannelsAdapter,
+    /// Request the membership capability, for `JOIN` and `PART` of other users and the userlist
+    /// of a channel when it's joined. Twitch only sends these for channels with fewer than 1000
+    /// chatters, and in batches every few seconds. Every connection asks for it when it's opened.
+    #[serde(default)]
+    pub membership: bool,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -83,6 +467,10 @@ pub struct DggSiteSettings {
     pub endpoint: String,
     pub origin: String,
     pub use_get_key: bool,
+    /// Fetch the recent chat history from `<origin>/api/chat/history` after every reconnect, to
+    /// fill in the messages sent while we were disconnected
+    #[serde(default)]
+    pub backfill_history: bool,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -91,13 +479,79 @@ pub struct DggSettings {
     pub max_retry_seconds: u64,
 }
 
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DropSettings {
+    /// Channels whose events are never written
+    #[serde(default)]
+    pub channels: Vec<String>,
+    /// Twitch logins or ids, or dgg nicks, whose events are never written
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// Regexes matched against message text, matching messages are dropped
+    #[serde(default)]
+    pub patterns: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RedactSettings {
+    #[serde(default)]
+    pub emails: bool,
+    #[serde(default)]
+    pub phone_numbers: bool,
+    #[serde(default)]
+    pub ip_addresses: bool,
+    /// Additional regexes to redact from message text
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    #[serde(default = "default_redaction_replacement")]
+    pub replacement: String,
+}
+
+impl Default for RedactSettings {
+    fn default() -> Self {
+        RedactSettings {
+            emails: false,
+            phone_numbers: false,
+            ip_addresses: false,
+            patterns: Vec::new(),
+            replacement: default_redaction_replacement(),
+        }
+    }
+}
+
+fn default_redaction_replacement() -> String {
+    "[redacted]".into()
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ProcessingSettings {
+    #[serde(default)]
+    pub drop: DropSettings,
+    #[serde(default)]
+    pub redact: RedactSettings,
+    #[serde(default)]
+    pub strip_control_chars: bool,
+    /// Files with one login, id or nick per line, for users that asked to not be logged
+    #[serde(default)]
+    pub opt_out_lists: Vec<String>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Settings {
     pub debug: String,
     pub discord_alerting: DiscordAlertingSettings,
-    pub writers: WritersSettings,
+    pub writers: Vec<WriterSettings>,
     pub twitch: TwitchSettings,
     pub dgg_like: DggSettings,
+    #[serde(default)]
+    pub processing: ProcessingSettings,
+    /// How long the ingester waits for writers to drain and flush on SIGINT/SIGTERM
+    #[serde(default = "default_shutdown_timeout_seconds")]
+    pub shutdown_timeout_seconds: u64,
+}
+
+fn default_shutdown_timeout_seconds() -> u64 {
+    10
 }
 
 impl Settings {
@@ -118,3 +572,67 @@ impl Settings {
         Ok(settings)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use config::FileFormat;
+
+    use super::*;
+
+    #[test]
+    fn test_parses_named_writer_list() {
+        let yaml = r#"
+writers:
+  - name: es_old
+    type: elasticsearch
+    host: http://old
+    port: 9200
+    index: rustlesearch
+    batch_size: 1000
+    batch_period_seconds: 10
+    max_retry_seconds: 360
+  - name: es_new
+    type: elasticsearch
+    enabled: false
+    host: http://new
+    port: 9201
+    index: rustlesearch
+    batch_size: 500
+    batch_period_seconds: 5
+    max_retry_seconds: 60
+    routes:
+      platforms:
+        include: [dgg]
+  - name: logs
+    type: filesystem
+    path: "./logs"
+  - name: metrics
+    type: console_metrics
+"#;
+        let mut config = Config::default();
+        config
+            .merge(File::from_str(yaml, FileFormat::Yaml))
+            .unwrap();
+        let writers: Vec<WriterSettings> = config.get("writers").unwrap();
+
+        let names: Vec<&str> = writers.iter().map(|w| w.name.as_str()).collect();
+        assert_eq!(names, vec!["es_old", "es_new", "logs", "metrics"]);
+        assert!(writers[0].enabled);
+        assert!(!writers[1].enabled);
+        assert_eq!(writers[1].routes.platforms.include, vec![ChannelType::Dgg]);
+        match (&writers[0].kind, &writers[1].kind) {
+            (WriterKindSettings::Elasticsearch(old), WriterKindSettings::Elasticsearch(new)) => {
+                assert_eq!(old.host, "http://old");
+                assert_eq!(new.port, 9201);
+            }
+            kinds => panic!("Expected two elasticsearch writers, got {:?}", kinds),
+        }
+        assert!(
+            matches!(&writers[2].kind, WriterKindSettings::Filesystem(f) if f.path == "./logs")
+        );
+        assert!(matches!(
+            writers[3].kind,
+            WriterKindSettings::ConsoleMetrics
+        ));
+    }
+}
@@ -2,16 +2,17 @@ use std::{sync::Arc, time::Duration};
 
 use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
-use chrono::{TimeZone, Utc};
 use elasticsearch::{http::request::JsonBody, BulkParts, Elasticsearch};
 use futures::prelude::*;
 use futures::{channel::mpsc, future};
 use log::{info, warn};
 use par_stream::prelude::*;
-use serde_json::{json, Value};
+use serde_json::Value;
 use tokio::{pin, time::Instant};
 
-use crate::adapters::elasticsearch::initialize_template;
+use crate::adapters::elasticsearch::setup::initialize_template;
+use crate::adapters::elasticsearch::setup::IndexLayout;
+use crate::adapters::elasticsearch::BulkDocument;
 use crate::{
     adapters::elasticsearch::create_elasticsearch_client_from_url, formats::unified::OrlLog1_0,
 };
@@ -95,26 +96,8 @@ impl ElasticsearchBulkSink {
     }
 
     fn add_log_to_payload(&self, bulk_body: &mut Vec<JsonBody<Value>>, log: OrlLog1_0) {
-        let date_time = Utc.timestamp_millis_opt(log.key.timestamp).unwrap();
-        let timestamp_string = date_time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
-
-        // 2020-12-12 - 10 characters for ymd of an ISO date
-        let floor_ymd = timestamp_string[..7].to_string() + "-01";
-
-        let index_with_date = self.opts.index_base_name.to_string() + "-" + &floor_ymd;
-
-        let id = log.key.id;
-
-        bulk_body.push(json!({ "index": { "_index": &index_with_date , "_id": id}}).into());
-        bulk_body.push(
-            json!({
-                "channel": log.channel_name,
-                "username": log.username,
-                "text": log.text,
-                "ts": timestamp_string
-            })
-            .into(),
-        );
+        let layout = IndexLayout::monthly(&self.opts.index_base_name);
+        BulkDocument::from_log(&layout, log).push_to(bulk_body);
     }
 }
 
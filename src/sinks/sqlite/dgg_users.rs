@@ -0,0 +1,272 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::{Row, SqlitePool};
+
+use crate::scrapers::dgg::user::User;
+
+/// A dgg user seen on a site with the flairs they had at the time
+#[derive(Clone, Debug)]
+pub struct DggUserSighting {
+    pub site: String,
+    pub username: String,
+    /// The sub tier, like `tier1` or `twitch`
+    pub subscription: Option<String>,
+    /// Sorted flair names as dgg sends them, e.g. `flair3` or `moderator`
+    pub flairs: Vec<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl DggUserSighting {
+    pub fn new(site: &str, user: &User, timestamp: DateTime<Utc>) -> Self {
+        DggUserSighting {
+            site: site.to_string(),
+            username: user.username.clone(),
+            subscription: user.subscription.as_ref().map(|s| s.as_ref().to_string()),
+            flairs: user.flair_names(),
+            timestamp,
+        }
+    }
+}
+
+/// What a user's sub tier and flairs were at some point in time
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DggUserState {
+    pub username: String,
+    pub subscription: Option<String>,
+    pub flairs: Vec<String>,
+}
+
+/// Timestamps are unix seconds, like in the twitch `name_changes` and `renames` tables
+pub async fn init_dgg_user_tables(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+          CREATE TABLE IF NOT EXISTS dgg_users (
+            site TEXT NOT NULL,
+            username TEXT NOT NULL COLLATE NOCASE,
+            first_seen INTEGER NOT NULL,
+            last_seen INTEGER NOT NULL,
+            subscription TEXT,
+            flairs TEXT NOT NULL,
+            PRIMARY KEY(site, username)
+          );
+
+          -- A row for the first sighting of a user and for every change of tier or flairs after
+          CREATE TABLE IF NOT EXISTS dgg_user_changes (
+            site TEXT NOT NULL,
+            username TEXT NOT NULL COLLATE NOCASE,
+            timestamp INTEGER NOT NULL,
+            subscription TEXT,
+            flairs TEXT NOT NULL,
+            PRIMARY KEY(site, username, timestamp)
+          );
+          CREATE INDEX IF NOT EXISTS dgg_user_changes_timestamp
+            ON dgg_user_changes(site, timestamp);
+      "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Writes a batch of sightings, returns how many of them were new users or changed tiers or
+/// flairs.
+pub async fn submit_dgg_user_batch(
+    pool: &SqlitePool,
+    sightings: &[DggUserSighting],
+) -> Result<usize> {
+    let mut tx = pool.begin().await?;
+    let mut changes = 0;
+
+    for sighting in sightings {
+        let timestamp = sighting.timestamp.timestamp();
+        let flairs = sighting.flairs.join(",");
+        let current: Option<(i64, Option<String>, String)> = sqlx::query_as(
+            r#"
+              SELECT last_seen, subscription, flairs FROM dgg_users
+              WHERE site = ? AND username = ?;
+            "#,
+        )
+        .bind(&sighting.site)
+        .bind(&sighting.username)
+        .fetch_optional(&mut tx)
+        .await?;
+        // Older events from replays don't count, the user has been seen in a newer state since
+        let changed = match current {
+            None => true,
+            Some((last_seen, subscription, current_flairs)) => {
+                timestamp >= last_seen
+                    && (subscription != sighting.subscription || current_flairs != flairs)
+            }
+        };
+        if changed {
+            // The last change within the same second wins, that's the state the user ended up in
+            sqlx::query(
+                r#"
+                  INSERT OR REPLACE INTO dgg_user_changes(
+                      site,
+                      username,
+                      timestamp,
+                      subscription,
+                      flairs
+                  )
+
+                  VALUES (?, ?, ?, ?, ?);
+                "#,
+            )
+            .bind(&sighting.site)
+            .bind(&sighting.username)
+            .bind(timestamp)
+            .bind(&sighting.subscription)
+            .bind(&flairs)
+            .execute(&mut tx)
+            .await?;
+            changes += 1;
+        }
+
+        sqlx::query(
+            r#"
+              INSERT INTO dgg_users(site, username, first_seen, last_seen, subscription, flairs)
+              VALUES (?1, ?2, ?3, ?3, ?4, ?5)
+              ON CONFLICT(site, username) DO UPDATE SET
+                first_seen = min(first_seen, excluded.first_seen),
+                last_seen = max(last_seen, excluded.last_seen),
+                subscription = CASE WHEN excluded.last_seen >= last_seen
+                  THEN excluded.subscription ELSE subscription END,
+                flairs = CASE WHEN excluded.last_seen >= last_seen
+                  THEN excluded.flairs ELSE flairs END;
+            "#,
+        )
+        .bind(&sighting.site)
+        .bind(&sighting.username)
+        .bind(timestamp)
+        .bind(&sighting.subscription)
+        .bind(&flairs)
+        .execute(&mut tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(changes)
+}
+
+/// The tier and flairs every user of a site had at `at`, as far as they had been seen by then.
+/// Sorted by username.
+pub async fn query_dgg_users_at(
+    pool: &SqlitePool,
+    site: &str,
+    at: DateTime<Utc>,
+) -> Result<Vec<DggUserState>> {
+    let rows = sqlx::query(
+        r#"
+          SELECT username, subscription, flairs
+          FROM dgg_user_changes AS c
+          WHERE site = ?1
+            AND timestamp = (
+              SELECT max(timestamp) FROM dgg_user_changes
+              WHERE site = c.site AND username = c.username AND timestamp <= ?2
+            )
+          ORDER BY username;
+        "#,
+    )
+    .bind(site)
+    .bind(at.timestamp())
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            let flairs: String = row.try_get("flairs")?;
+            Ok(DggUserState {
+                username: row.try_get("username")?,
+                subscription: row.try_get("subscription")?,
+                flairs: flairs
+                    .split(',')
+                    .filter(|f| !f.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::sqlite_pool::create_sqlite;
+
+    fn sighting(username: &str, features: &[&str], seconds: i64) -> DggUserSighting {
+        let user: User = serde_json::from_value(serde_json::json!({
+            "nick": username,
+            "features": features,
+        }))
+        .unwrap();
+        DggUserSighting::new(
+            "Destinygg",
+            &user,
+            Utc.timestamp_opt(seconds, 0).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_tracks_tier_and_flair_changes() {
+        let path = std::env::temp_dir().join(format!("tl2-dgg-users-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pool = create_sqlite(path.to_str().unwrap()).await.unwrap();
+        init_dgg_user_tables(&pool).await.unwrap();
+
+        let changes = submit_dgg_user_batch(
+            &pool,
+            &[
+                sighting("bob", &["flair13"], 100),
+                sighting("alice", &[], 150),
+                sighting("bob", &["flair13"], 200),
+                sighting("Bob", &["moderator", "flair13"], 300),
+                // Same flairs in a different order aren't a change
+                sighting("bob", &["flair13", "moderator"], 400),
+                sighting("bob", &["moderator"], 500),
+                // A late event from before the sub ran out
+                sighting("bob", &["flair13", "moderator"], 450),
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(changes, 4);
+
+        let at = |seconds| Utc.timestamp_opt(seconds, 0).unwrap();
+        let users = query_dgg_users_at(&pool, "Destinygg", at(350))
+            .await
+            .unwrap();
+        assert_eq!(
+            users,
+            vec![
+                DggUserState {
+                    username: "alice".to_string(),
+                    subscription: None,
+                    flairs: vec![],
+                },
+                DggUserState {
+                    username: "Bob".to_string(),
+                    subscription: Some("tier1".to_string()),
+                    flairs: vec!["flair13".to_string(), "moderator".to_string()],
+                },
+            ]
+        );
+
+        let users = query_dgg_users_at(&pool, "Destinygg", at(600))
+            .await
+            .unwrap();
+        assert_eq!(users[1].subscription, None);
+        assert_eq!(users[1].flairs, vec!["moderator".to_string()]);
+
+        assert!(query_dgg_users_at(&pool, "Destinygg", at(50))
+            .await
+            .unwrap()
+            .is_empty());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1 +1,5 @@
+pub mod dgg_users;
 pub mod messages;
+pub mod moderation;
+pub mod presence;
+pub mod usernames;
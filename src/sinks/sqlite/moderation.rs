@@ -0,0 +1,196 @@
+use anyhow::{anyhow, Result};
+use chrono::{TimeZone, Utc};
+use sqlx::{sqlite::SqliteRow, Row, SqlitePool};
+
+use crate::formats::{moderation::ModerationLog, unified::ChannelType};
+
+pub async fn init_moderation_tables(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+          CREATE TABLE IF NOT EXISTS moderation_log (
+            platform TEXT NOT NULL,
+            channel TEXT NOT NULL,
+            channel_id TEXT,
+            action TEXT NOT NULL,
+            target TEXT,
+            target_id TEXT,
+            moderator TEXT,
+            duration_seconds INTEGER,
+            timestamp INTEGER NOT NULL
+          );
+      "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Replays and the write-ahead log may write an action again, it's only stored once
+    sqlx::query(
+        r#"
+          CREATE UNIQUE INDEX IF NOT EXISTS moderation_log_unique
+            ON moderation_log(platform, channel, action, ifnull(target, ''), timestamp);
+          CREATE INDEX IF NOT EXISTS moderation_log_target
+            ON moderation_log(target COLLATE NOCASE, timestamp);
+          CREATE INDEX IF NOT EXISTS moderation_log_channel
+            ON moderation_log(channel COLLATE NOCASE, timestamp);
+      "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn submit_moderation_batch(pool: &SqlitePool, logs: &[ModerationLog]) -> Result<()> {
+    let mut tx = pool.begin().await?;
+
+    for log in logs {
+        sqlx::query(
+            r#"
+              INSERT OR IGNORE INTO moderation_log(
+                  platform,
+                  channel,
+                  channel_id,
+                  action,
+                  target,
+                  target_id,
+                  moderator,
+                  duration_seconds,
+                  timestamp
+              )
+
+              VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?);
+            "#,
+        )
+        .bind(log.platform.as_ref())
+        .bind(&log.channel)
+        .bind(&log.channel_id)
+        .bind(log.action.as_ref())
+        .bind(&log.target)
+        .bind(&log.target_id)
+        .bind(&log.moderator)
+        .bind(log.duration_seconds.map(|d| d as i64))
+        .bind(log.timestamp.timestamp_millis())
+        .execute(&mut tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Which moderation log entries to look up, every filter that's set has to match. Names are
+/// compared case insensitively.
+#[derive(Clone, Debug, Default)]
+pub struct ModerationQuery {
+    pub target: Option<String>,
+    pub channel: Option<String>,
+    pub platform: Option<ChannelType>,
+    pub limit: u32,
+}
+
+/// The most recent entries matching the query, oldest first
+pub async fn query_moderation(
+    pool: &SqlitePool,
+    query: &ModerationQuery,
+) -> Result<Vec<ModerationLog>> {
+    let rows = sqlx::query(
+        r#"
+          SELECT * FROM (
+            SELECT platform, channel, channel_id, action, target, target_id, moderator,
+                   duration_seconds, timestamp
+            FROM moderation_log
+            WHERE (?1 IS NULL OR target = ?1 COLLATE NOCASE)
+              AND (?2 IS NULL OR channel = ?2 COLLATE NOCASE)
+              AND (?3 IS NULL OR platform = ?3)
+            ORDER BY timestamp DESC
+            LIMIT ?4
+          )
+          ORDER BY timestamp ASC;
+        "#,
+    )
+    .bind(&query.target)
+    .bind(&query.channel)
+    .bind(query.platform.map(|p| p.as_ref().to_string()))
+    .bind(query.limit)
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter().map(row_to_log).collect()
+}
+
+fn row_to_log(row: &SqliteRow) -> Result<ModerationLog> {
+    let platform: String = row.try_get("platform")?;
+    let action: String = row.try_get("action")?;
+    let timestamp: i64 = row.try_get("timestamp")?;
+    let duration_seconds: Option<i64> = row.try_get("duration_seconds")?;
+    Ok(ModerationLog {
+        platform: platform.parse()?,
+        channel: row.try_get("channel")?,
+        channel_id: row.try_get("channel_id")?,
+        action: action.parse()?,
+        target: row.try_get("target")?,
+        target_id: row.try_get("target_id")?,
+        moderator: row.try_get("moderator")?,
+        duration_seconds: duration_seconds.map(|d| d as u64),
+        timestamp: Utc
+            .timestamp_millis_opt(timestamp)
+            .single()
+            .ok_or_else(|| anyhow!("Invalid moderation log timestamp: {}", timestamp))?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{formats::moderation::ModerationAction, sqlite_pool::create_sqlite};
+
+    fn log(target: &str, channel: &str, millis: i64) -> ModerationLog {
+        ModerationLog {
+            platform: ChannelType::Twitch,
+            channel: channel.to_string(),
+            channel_id: Some("1".to_string()),
+            action: ModerationAction::Timeout,
+            target: Some(target.to_string()),
+            target_id: None,
+            moderator: None,
+            duration_seconds: Some(600),
+            timestamp: Utc.timestamp_millis_opt(millis).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queries_by_target_and_channel() {
+        let path = std::env::temp_dir().join(format!("tl2-moderation-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pool = create_sqlite(path.to_str().unwrap()).await.unwrap();
+        init_moderation_tables(&pool).await.unwrap();
+
+        let logs = vec![
+            log("spammer", "xqc", 1000),
+            log("spammer", "forsen", 2000),
+            log("other", "xqc", 3000),
+            log("spammer", "xqc", 4000),
+        ];
+        submit_moderation_batch(&pool, &logs).await.unwrap();
+        // Writing the same actions again doesn't duplicate them
+        submit_moderation_batch(&pool, &logs).await.unwrap();
+
+        let query = ModerationQuery {
+            target: Some("Spammer".to_string()),
+            limit: 2,
+            ..Default::default()
+        };
+        let found = query_moderation(&pool, &query).await.unwrap();
+        assert_eq!(found, vec![logs[1].clone(), logs[3].clone()]);
+
+        let query = ModerationQuery {
+            channel: Some("xqc".to_string()),
+            limit: 10,
+            ..Default::default()
+        };
+        assert_eq!(query_moderation(&pool, &query).await.unwrap().len(), 3);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
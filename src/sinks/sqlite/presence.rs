@@ -0,0 +1,154 @@
+use anyhow::Result;
+use sqlx::SqlitePool;
+
+use crate::userlist_scraper::PresenceSnapshot;
+
+pub async fn init_presence_tables(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+          CREATE TABLE IF NOT EXISTS presence (
+            platform TEXT NOT NULL,
+            channel TEXT NOT NULL,
+            timestamp INTEGER NOT NULL,
+            chatters INTEGER,
+            connection_count INTEGER,
+            joins INTEGER NOT NULL,
+            leaves INTEGER NOT NULL,
+            messages INTEGER NOT NULL,
+            viewers INTEGER,
+            PRIMARY KEY(platform, channel, timestamp)
+          );
+
+          -- The users of every chat as of its latest snapshot
+          CREATE TABLE IF NOT EXISTS presence_users (
+            platform TEXT NOT NULL,
+            channel TEXT NOT NULL,
+            username TEXT NOT NULL,
+            PRIMARY KEY(platform, channel, username)
+          );
+      "#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn submit_presence_snapshots(
+    pool: &SqlitePool,
+    snapshots: &[PresenceSnapshot],
+) -> Result<()> {
+    let mut tx = pool.begin().await?;
+
+    for snapshot in snapshots {
+        sqlx::query(
+            r#"
+              INSERT OR REPLACE INTO presence(
+                  platform,
+                  channel,
+                  timestamp,
+                  chatters,
+                  connection_count,
+                  joins,
+                  leaves,
+                  messages,
+                  viewers
+              )
+
+              VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?);
+            "#,
+        )
+        .bind(snapshot.platform.as_ref())
+        .bind(&snapshot.channel)
+        .bind(snapshot.timestamp.timestamp_millis())
+        .bind(snapshot.chatters.map(|c| c as i64))
+        .bind(snapshot.connection_count.map(|c| c as i64))
+        .bind(snapshot.joins as i64)
+        .bind(snapshot.leaves as i64)
+        .bind(snapshot.messages as i64)
+        .bind(snapshot.viewers.map(|v| v as i64))
+        .execute(&mut tx)
+        .await?;
+
+        // Chats without a userlist keep whatever they had
+        if snapshot.chatters.is_none() {
+            continue;
+        }
+        sqlx::query("DELETE FROM presence_users WHERE platform = ? AND channel = ?;")
+            .bind(snapshot.platform.as_ref())
+            .bind(&snapshot.channel)
+            .execute(&mut tx)
+            .await?;
+        for username in &snapshot.users {
+            sqlx::query(
+                r#"
+                  INSERT OR IGNORE INTO presence_users(platform, channel, username)
+                  VALUES (?, ?, ?);
+                "#,
+            )
+            .bind(snapshot.platform.as_ref())
+            .bind(&snapshot.channel)
+            .bind(username)
+            .execute(&mut tx)
+            .await?;
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::{formats::unified::ChannelType, sqlite_pool::create_sqlite};
+
+    fn snapshot(millis: i64, users: &[&str]) -> PresenceSnapshot {
+        PresenceSnapshot {
+            timestamp: Utc.timestamp_millis_opt(millis).unwrap(),
+            platform: ChannelType::Dgg,
+            channel: "Destinygg".to_string(),
+            chatters: Some(users.len() as u64),
+            connection_count: Some(30),
+            joins: 1,
+            leaves: 0,
+            messages: 5,
+            viewers: Some(1200),
+            users: users.iter().map(|u| u.to_string()).collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_writes_time_series_and_latest_users() {
+        let path = std::env::temp_dir().join(format!("tl2-presence-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pool = create_sqlite(path.to_str().unwrap()).await.unwrap();
+        init_presence_tables(&pool).await.unwrap();
+
+        submit_presence_snapshots(&pool, &[snapshot(1000, &["a", "b"])])
+            .await
+            .unwrap();
+        submit_presence_snapshots(&pool, &[snapshot(2000, &["b", "c", "d"])])
+            .await
+            .unwrap();
+
+        let chatters: Vec<(i64, i64, i64)> =
+            sqlx::query_as("SELECT timestamp, chatters, viewers FROM presence ORDER BY timestamp;")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(chatters, vec![(1000, 2, 1200), (2000, 3, 1200)]);
+        let users: Vec<(String,)> =
+            sqlx::query_as("SELECT username FROM presence_users ORDER BY username;")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        let users: Vec<String> = users.into_iter().map(|(u,)| u).collect();
+        assert_eq!(users, vec!["b", "c", "d"]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
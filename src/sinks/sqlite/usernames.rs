@@ -0,0 +1,307 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use sqlx::{sqlite::SqliteRow, Row, SqlitePool};
+
+/// A twitch login seen with its user id at some point in time
+#[derive(Clone, Debug)]
+pub struct UsernameUpdate {
+    pub id: String,
+    pub username: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A known twitch id showing up with a login that's newer than the one it was last seen with
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rename {
+    pub twitch_id: String,
+    pub old_username: String,
+    pub new_username: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl fmt::Display for Rename {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} renamed to {} (twitch id {})",
+            self.old_username, self.new_username, self.twitch_id
+        )
+    }
+}
+
+/// One login a twitch id has used, and when it was seen with it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UsernameHistory {
+    pub twitch_id: String,
+    pub username: String,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+impl fmt::Display for UsernameHistory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} - {} {}",
+            self.first_seen.format("%Y-%m-%d %H:%M:%S"),
+            self.last_seen.format("%Y-%m-%d %H:%M:%S"),
+            self.username
+        )
+    }
+}
+
+pub async fn init_username_tables(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+          CREATE TABLE IF NOT EXISTS name_changes (
+            username TEXT NOT NULL,
+            twitch_id TEXT NOT NULL,
+            first_seen INTEGER NOT NULL,
+            last_seen INTEGER NOT NULL,
+            PRIMARY KEY(username, twitch_id)
+          );
+          CREATE INDEX IF NOT EXISTS name_changes_twitch_id ON name_changes(twitch_id, last_seen);
+          CREATE INDEX IF NOT EXISTS name_changes_username
+            ON name_changes(username COLLATE NOCASE);
+
+          CREATE TABLE IF NOT EXISTS renames (
+            twitch_id TEXT NOT NULL,
+            old_username TEXT NOT NULL,
+            new_username TEXT NOT NULL,
+            timestamp INTEGER NOT NULL,
+            PRIMARY KEY(twitch_id, new_username, timestamp)
+          );
+      "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Tables from before names had a first sighting, those rows only know when they were last seen
+    let has_first_seen: Option<i64> = sqlx::query_scalar(
+        "SELECT 1 FROM pragma_table_info('name_changes') WHERE name = 'first_seen';",
+    )
+    .fetch_optional(pool)
+    .await?;
+    if has_first_seen.is_none() {
+        sqlx::query(
+            r#"
+              ALTER TABLE name_changes ADD COLUMN first_seen INTEGER NOT NULL DEFAULT 0;
+              UPDATE name_changes SET first_seen = last_seen;
+            "#,
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Writes a batch of sightings and returns the renames they revealed, in the order they happened
+pub async fn submit_username_batch(
+    pool: &SqlitePool,
+    updates: &[UsernameUpdate],
+) -> Result<Vec<Rename>> {
+    let mut tx = pool.begin().await?;
+    let mut renames = Vec::new();
+
+    for update in updates {
+        let timestamp = update.timestamp.timestamp();
+        let latest: Option<(String, i64)> = sqlx::query_as(
+            r#"
+              SELECT username, last_seen FROM name_changes
+              WHERE twitch_id = ?
+              ORDER BY last_seen DESC
+              LIMIT 1;
+            "#,
+        )
+        .bind(&update.id)
+        .fetch_optional(&mut tx)
+        .await?;
+        // Older events from replays don't count, the id has been seen with a newer name since
+        if let Some((old_username, last_seen)) = latest {
+            if old_username != update.username && timestamp >= last_seen {
+                let rename = Rename {
+                    twitch_id: update.id.clone(),
+                    old_username,
+                    new_username: update.username.clone(),
+                    timestamp: update.timestamp,
+                };
+                sqlx::query(
+                    r#"
+                      INSERT OR IGNORE INTO renames(
+                          twitch_id,
+                          old_username,
+                          new_username,
+                          timestamp
+                      )
+
+                      VALUES (?, ?, ?, ?);
+                    "#,
+                )
+                .bind(&rename.twitch_id)
+                .bind(&rename.old_username)
+                .bind(&rename.new_username)
+                .bind(timestamp)
+                .execute(&mut tx)
+                .await?;
+                renames.push(rename);
+            }
+        }
+
+        sqlx::query(
+            r#"
+              INSERT INTO name_changes(username, twitch_id, first_seen, last_seen)
+              VALUES (?1, ?2, ?3, ?3)
+              ON CONFLICT(username, twitch_id) DO UPDATE SET
+                first_seen = min(first_seen, excluded.first_seen),
+                last_seen = max(last_seen, excluded.last_seen);
+            "#,
+        )
+        .bind(&update.username)
+        .bind(&update.id)
+        .bind(timestamp)
+        .execute(&mut tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(renames)
+}
+
+/// Every login of every twitch id that has used `name_or_id` as its login, or has it as its id.
+/// Sorted by id, then by when a login was first seen.
+pub async fn query_user_history(
+    pool: &SqlitePool,
+    name_or_id: &str,
+) -> Result<Vec<UsernameHistory>> {
+    let rows = sqlx::query(
+        r#"
+          SELECT twitch_id, username, first_seen, last_seen
+          FROM name_changes
+          WHERE twitch_id = ?1
+             OR twitch_id IN (SELECT twitch_id FROM name_changes WHERE username = ?1 COLLATE NOCASE)
+          ORDER BY twitch_id, first_seen, last_seen;
+        "#,
+    )
+    .bind(name_or_id)
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter().map(row_to_history).collect()
+}
+
+fn row_to_history(row: &SqliteRow) -> Result<UsernameHistory> {
+    let seconds = |column: &str| -> Result<DateTime<Utc>> {
+        let seconds: i64 = row.try_get(column)?;
+        Utc.timestamp_opt(seconds, 0)
+            .single()
+            .ok_or_else(|| anyhow!("Invalid {} timestamp: {}", column, seconds))
+    };
+    Ok(UsernameHistory {
+        twitch_id: row.try_get("twitch_id")?,
+        username: row.try_get("username")?,
+        first_seen: seconds("first_seen")?,
+        last_seen: seconds("last_seen")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite_pool::create_sqlite;
+
+    fn update(id: &str, username: &str, seconds: i64) -> UsernameUpdate {
+        UsernameUpdate {
+            id: id.to_string(),
+            username: username.to_string(),
+            timestamp: Utc.timestamp_opt(seconds, 0).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tracks_renames_and_history() {
+        let path = std::env::temp_dir().join(format!("tl2-usernames-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pool = create_sqlite(path.to_str().unwrap()).await.unwrap();
+        init_username_tables(&pool).await.unwrap();
+
+        let renames = submit_username_batch(
+            &pool,
+            &[
+                update("1", "oldname", 100),
+                update("1", "oldname", 200),
+                update("2", "someone", 150),
+                update("1", "newname", 300),
+                // A late event with the old name isn't a rename back
+                update("1", "oldname", 250),
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(renames.len(), 1);
+        assert_eq!(
+            renames[0].to_string(),
+            "oldname renamed to newname (twitch id 1)"
+        );
+
+        let history = query_user_history(&pool, "OldName").await.unwrap();
+        assert_eq!(
+            history,
+            vec![
+                UsernameHistory {
+                    twitch_id: "1".to_string(),
+                    username: "oldname".to_string(),
+                    first_seen: Utc.timestamp_opt(100, 0).unwrap(),
+                    last_seen: Utc.timestamp_opt(250, 0).unwrap(),
+                },
+                UsernameHistory {
+                    twitch_id: "1".to_string(),
+                    username: "newname".to_string(),
+                    first_seen: Utc.timestamp_opt(300, 0).unwrap(),
+                    last_seen: Utc.timestamp_opt(300, 0).unwrap(),
+                },
+            ]
+        );
+        assert_eq!(query_user_history(&pool, "1").await.unwrap(), history);
+        assert_eq!(query_user_history(&pool, "nobody").await.unwrap(), vec![]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_migrates_tables_without_first_seen() {
+        let path =
+            std::env::temp_dir().join(format!("tl2-usernames-migrate-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let pool = create_sqlite(path.to_str().unwrap()).await.unwrap();
+        sqlx::query(
+            r#"
+              CREATE TABLE name_changes (
+                username TEXT NOT NULL,
+                twitch_id TEXT NOT NULL,
+                last_seen INTEGER NOT NULL,
+                PRIMARY KEY(username, twitch_id)
+              );
+              INSERT INTO name_changes VALUES ('bob', '1', 500);
+            "#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        init_username_tables(&pool).await.unwrap();
+        init_username_tables(&pool).await.unwrap();
+        submit_username_batch(&pool, &[update("1", "bob", 400)])
+            .await
+            .unwrap();
+
+        let history = query_user_history(&pool, "bob").await.unwrap();
+        assert_eq!(history[0].first_seen, Utc.timestamp_opt(400, 0).unwrap());
+        assert_eq!(history[0].last_seen, Utc.timestamp_opt(500, 0).unwrap());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,66 @@
+use anyhow::Context;
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::Stream;
+use futures::StreamExt;
+use log::info;
+use log::warn;
+use tokio::pin;
+
+use super::Sink;
+use crate::adapters::RoutedWriter;
+use crate::events::AllEvents;
+use crate::processing::Processor;
+
+/// Feeds a stream of events into live writers, so their output can be regenerated from archives.
+pub struct WritersSink {
+    writers: Vec<RoutedWriter>,
+    processor: Option<Processor>,
+}
+
+impl WritersSink {
+    pub fn new(writers: Vec<RoutedWriter>, processor: Option<Processor>) -> Self {
+        WritersSink { writers, processor }
+    }
+}
+
+#[async_trait(?Send)]
+impl Sink<Result<AllEvents>> for WritersSink {
+    async fn run(mut self, stream: impl Stream<Item = Result<AllEvents>>) -> Result<()> {
+        pin!(stream);
+
+        let mut count = 0;
+        let mut failed = 0;
+        while let Some(event) = stream.next().await {
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    failed += 1;
+                    warn!("Skipping event that couldn't be read: {:?}", e);
+                    continue;
+                }
+            };
+            let event = match &self.processor {
+                Some(processor) => match processor.process(event) {
+                    Some(event) => event,
+                    None => continue,
+                },
+                None => event,
+            };
+            for writer in &self.writers {
+                writer
+                    .write_waiting(&event)
+                    .await
+                    .with_context(|| format!("Writer '{}' failed", writer.name))?;
+            }
+            count += 1;
+        }
+        info!("Wrote {} events, skipped {} failed events", count, failed);
+
+        // Writers buffer in the background, wait until everything is written out
+        for writer in self.writers {
+            writer.shutdown().await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+use async_stream::stream;
+use async_trait::async_trait;
+use chrono::DateTime;
+use chrono::Utc;
+use futures::Stream;
+use tokio::fs::File;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::BufReader;
+use tokio::time::sleep_until;
+use tokio::time::Instant;
+
+use super::twitch_raw::parse_raw_line;
+use super::Source;
+use crate::events::AllEvents;
+use crate::events::RawEvent;
+use crate::formats::unified::ChannelType;
+use crate::scrapers::dgg::map_frame;
+use crate::sinks::Sink;
+
+/// Reads a capture written by `tl2 scrape --record`, re-parsing every record the same way the
+/// live scrapers do.
+pub struct CaptureSource {
+    path: PathBuf,
+    /// Playback speed relative to the original timing, `0` replays as fast as possible
+    speed: f64,
+}
+
+impl CaptureSource {
+    pub fn new(path: PathBuf, speed: f64) -> Self {
+        CaptureSource { path, speed }
+    }
+
+    pub async fn get_stream(&self) -> Result<impl Stream<Item = Result<AllEvents>>> {
+        let file = File::open(&self.path)
+            .await
+            .with_context(|| format!("Opening capture {:?} failed", self.path))?;
+        let mut lines = BufReader::new(file).lines();
+        let speed = self.speed;
+
+        Ok(stream! {
+            let mut clock: Option<(DateTime<Utc>, Instant)> = None;
+            loop {
+                let line = match lines.next_line().await {
+                    Ok(Some(line)) => line,
+                    Ok(None) => break,
+                    Err(e) => {
+                        yield Err(e.into());
+                        break;
+                    }
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record: Result<RawEvent> = serde_json::from_str(&line)
+                    .with_context(|| format!("Invalid capture record: {:?}", line));
+                let record = match record {
+                    Ok(record) => record,
+                    Err(e) => {
+                        yield Err(e);
+                        continue;
+                    }
+                };
+
+                if speed > 0.0 {
+                    let (first_received_at, started) =
+                        *clock.get_or_insert((record.received_at, Instant::now()));
+                    let offset = (record.received_at - first_received_at)
+                        .to_std()
+                        .unwrap_or_default();
+                    sleep_until(started + offset.div_f64(speed)).await;
+                }
+                yield parse_record(record);
+            }
+        })
+    }
+}
+
+pub fn parse_record(record: RawEvent) -> Result<AllEvents> {
+    match record.platform {
+        ChannelType::Twitch => parse_raw_line(&record.data, record.received_at),
+        ChannelType::Dgg => {
+            let channel = record
+                .channel
+                .context("Dgg capture record is missing its channel")?;
+            Ok(map_frame(record.data, &channel, record.received_at))
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Source<Result<AllEvents>> for CaptureSource {
+    async fn pipe(&mut self, sink: impl Sink<Result<AllEvents>>) -> anyhow::Result<()> {
+        let stream = self.get_stream().await?;
+        sink.run(stream).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::events::SimpleMessageGroup;
+
+    #[test]
+    fn test_parse_dgg_records() {
+        let record = RawEvent {
+            platform: ChannelType::Dgg,
+            channel: Some("Destinygg".into()),
+            received_at: Utc.timestamp_millis_opt(1628035200095).unwrap(),
+            data: r#"MSG {"nick":"Bob","features":[],"timestamp":1628035200095,"data":"hi"}"#
+                .into(),
+        };
+        let event = parse_record(record.clone()).unwrap();
+        let msgs = SimpleMessageGroup::from(event.clone());
+        assert_eq!(msgs.0.len(), 1);
+        assert_eq!(msgs.0[0].text, "hi");
+        assert_eq!(event.received_at(), record.received_at);
+        assert_eq!(event.to_raw().data, record.data);
+
+        let broken = RawEvent {
+            data: r#"MSG {"nick":"#.into(),
+            ..record
+        };
+        assert!(matches!(
+            parse_record(broken).unwrap(),
+            AllEvents::Raw(RawEvent { data, .. }) if data == r#"MSG {"nick":"#
+        ));
+    }
+}
@@ -186,10 +186,13 @@ impl JsonFileSource {
                         future::ready({
                             let s = p.to_str();
                             if let Some(s) = s {
+                                // Moderation logs share the extension but not the records
                                 return future::ready(
-                                    s.ends_with(".jsonl")
+                                    (s.ends_with(".jsonl")
                                         || s.ends_with(".jsonl.gz")
-                                        || s.ends_with(".jsonl.br"),
+                                        || s.ends_with(".jsonl.zst")
+                                        || s.ends_with(".jsonl.br"))
+                                        && !s.contains(".moderation.jsonl"),
                                 );
                             }
                             false
@@ -1,12 +1,14 @@
-use async_trait::async_trait;
 use anyhow::Result;
+use async_trait::async_trait;
 
 use crate::sinks::Sink;
 
-pub mod orl;
+pub mod capture;
 pub mod jsonl;
+pub mod orl;
+pub mod twitch_raw;
 
 #[async_trait(?Send)]
-pub trait Source<SourceItem> : Sized {
-    async fn pipe(&mut self, sink: impl Sink<SourceItem>) -> Result<()>; 
+pub trait Source<SourceItem>: Sized {
+    async fn pipe(&mut self, sink: impl Sink<SourceItem>) -> Result<()>;
 }
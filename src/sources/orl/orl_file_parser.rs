@@ -4,7 +4,9 @@ use std::path::PathBuf;
 
 use anyhow::Context;
 use anyhow::Result;
+use async_compression::tokio::bufread::BrotliDecoder;
 use async_compression::tokio::bufread::GzipDecoder;
+use async_compression::tokio::bufread::ZstdDecoder;
 use async_stream::try_stream;
 use futures::Stream;
 use futures::TryStreamExt;
@@ -34,15 +36,22 @@ pub async fn read_orl_file_to_string(path: &Path) -> Result<String> {
     let file = File::open(path).await?;
     let mut buf_reader = BufReader::new(file);
 
-    let gz_ext = OsStr::new("gz");
-    if path.extension() == Some(gz_ext) {
+    let mut decoded: Vec<u8> = vec![];
+    let ext = path.extension();
+    if ext == Some(OsStr::new("gz")) {
         let mut reader = GzipDecoder::new(buf_reader);
-        let mut decoded: Vec<u8> = vec![];
         reader.read_to_end(&mut decoded).await?;
-
-        let contents = String::from_utf8(decoded)?;
-
-        return Ok(contents);
+        return Ok(String::from_utf8(decoded)?);
+    }
+    if ext == Some(OsStr::new("zst")) {
+        let mut reader = ZstdDecoder::new(buf_reader);
+        reader.read_to_end(&mut decoded).await?;
+        return Ok(String::from_utf8(decoded)?);
+    }
+    if ext == Some(OsStr::new("br")) {
+        let mut reader = BrotliDecoder::new(buf_reader);
+        reader.read_to_end(&mut decoded).await?;
+        return Ok(String::from_utf8(decoded)?);
     }
 
     let mut contents = String::new();
@@ -95,7 +104,10 @@ pub async fn read_orl_structured_dir(dir_path: &Path) -> Result<Vec<OrlDirFile>>
                 .filter(|p| {
                     let s = p.to_str();
                     if let Some(s) = s {
-                        return s.ends_with(".txt") || s.ends_with(".txt.gz");
+                        return s.ends_with(".txt")
+                            || s.ends_with(".txt.gz")
+                            || s.ends_with(".txt.zst")
+                            || s.ends_with(".txt.br");
                     }
                     false
                 })
@@ -0,0 +1,172 @@
+use std::convert::TryFrom;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+use async_compression::tokio::bufread::GzipDecoder;
+use async_stream::stream;
+use async_trait::async_trait;
+use chrono::DateTime;
+use chrono::NaiveDate;
+use chrono::TimeZone;
+use chrono::Utc;
+use futures::Stream;
+use log::debug;
+use tokio::fs;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio::io::BufReader;
+use twitch_irc::message::IRCMessage;
+use twitch_irc::message::ServerMessage;
+
+use super::Source;
+use crate::events::AllEvents;
+use crate::scrapers::twitch::TwitchScraper;
+use crate::sinks::Sink;
+
+const RAW_FILE_SUFFIX: &str = ".irc.gz";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct TwitchRawFile {
+    path: PathBuf,
+    day: NaiveDate,
+}
+
+/// Reads the archives written by the `TwitchIrc` file writer, structured as
+/// `<root>/<channel>/<YYYY-MM-DD>.irc.gz`, and re-parses every line into events the same way the
+/// live scraper does.
+pub struct TwitchRawSource {
+    root_dir: PathBuf,
+}
+
+impl TwitchRawSource {
+    pub fn new(root_dir: PathBuf) -> Self {
+        TwitchRawSource { root_dir }
+    }
+
+    pub async fn get_stream(&self) -> Result<impl Stream<Item = Result<AllEvents>>> {
+        let files = read_raw_structured_dir(&self.root_dir).await?;
+        Ok(stream! {
+            for file in files {
+                debug!("Processing file: {:?}", file.path);
+                let contents = match read_raw_file_to_string(&file.path).await {
+                    Ok(contents) => contents,
+                    Err(e) => {
+                        yield Err(e);
+                        continue;
+                    }
+                };
+                // Only the day is known for messages that were archived without being mapped
+                let received_at = Utc.from_utc_datetime(&file.day.and_hms_opt(0, 0, 0).unwrap());
+                for line in contents.lines().filter(|line| !line.is_empty()) {
+                    yield parse_raw_line(line, received_at);
+                }
+            }
+        })
+    }
+}
+
+pub fn parse_raw_line(line: &str, received_at: DateTime<Utc>) -> Result<AllEvents> {
+    let irc_message =
+        IRCMessage::parse(line).with_context(|| format!("Invalid IRC line: {:?}", line))?;
+    let server_message = ServerMessage::try_from(irc_message)
+        .with_context(|| format!("Unparseable twitch message: {:?}", line))?;
+
+    Ok(TwitchScraper::to_event(server_message, received_at))
+}
+
+async fn read_raw_file_to_string(path: &Path) -> Result<String> {
+    let file = File::open(path).await?;
+    let mut reader = GzipDecoder::new(BufReader::new(file));
+    // Every flush of the writer appends a separate gzip member
+    reader.multiple_members(true);
+
+    let mut contents = String::new();
+    reader
+        .read_to_string(&mut contents)
+        .await
+        .with_context(|| format!("Reading raw file {:?} failed", path))?;
+    Ok(contents)
+}
+
+async fn read_raw_structured_dir(dir_path: &Path) -> Result<Vec<TwitchRawFile>> {
+    let mut res: Vec<TwitchRawFile> = Vec::new();
+    let mut dir = fs::read_dir(dir_path).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        if !entry.file_type().await?.is_dir() {
+            continue;
+        }
+        let mut sub_dir = fs::read_dir(entry.path()).await?;
+        while let Some(file) = sub_dir.next_entry().await? {
+            let path = file.path();
+            let day = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(RAW_FILE_SUFFIX))
+                .and_then(|day| NaiveDate::parse_from_str(day, "%Y-%m-%d").ok());
+            if let Some(day) = day {
+                res.push(TwitchRawFile { path, day });
+            }
+        }
+    }
+    // Replay in the order the messages were received, as far as the file layout allows
+    res.sort_by(|a, b| (a.day, &a.path).cmp(&(b.day, &b.path)));
+    Ok(res)
+}
+
+#[async_trait(?Send)]
+impl Source<Result<AllEvents>> for TwitchRawSource {
+    async fn pipe(&mut self, sink: impl Sink<Result<AllEvents>>) -> anyhow::Result<()> {
+        let stream = self.get_stream().await?;
+        sink.run(stream).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_compression::tokio::write::GzipEncoder;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    async fn gzip(contents: &str) -> Vec<u8> {
+        let mut encoder = GzipEncoder::new(Vec::new());
+        encoder.write_all(contents.as_bytes()).await.unwrap();
+        encoder.shutdown().await.unwrap();
+        encoder.into_inner()
+    }
+
+    #[tokio::test]
+    async fn test_reads_appended_gzip_members() {
+        let root = std::env::temp_dir().join(format!("tl2-twitch-raw-{}", std::process::id()));
+        let channel_dir = root.join("xqcow");
+        fs::create_dir_all(&channel_dir).await.unwrap();
+
+        let mut contents = gzip("PING :tmi.twitch.tv\n").await;
+        contents.extend(gzip("PONG :tmi.twitch.tv\n").await);
+        fs::write(channel_dir.join("2021-08-04.irc.gz"), &contents)
+            .await
+            .unwrap();
+        fs::write(channel_dir.join("2021-08-03.irc.gz"), gzip("").await)
+            .await
+            .unwrap();
+        fs::write(channel_dir.join("2021-08-03.txt"), "")
+            .await
+            .unwrap();
+
+        let files = read_raw_structured_dir(&root).await.unwrap();
+        assert_eq!(
+            files.iter().map(|f| f.day.to_string()).collect::<Vec<_>>(),
+            vec!["2021-08-03", "2021-08-04"]
+        );
+        assert_eq!(
+            read_raw_file_to_string(&files[1].path).await.unwrap(),
+            "PING :tmi.twitch.tv\nPONG :tmi.twitch.tv\n"
+        );
+
+        fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -0,0 +1,455 @@
+use std::{
+    collections::VecDeque,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use log::{error, info, warn};
+
+use self::spill::SpillFile;
+use crate::{
+    adapters::{Writer, WriterFull, Writers},
+    alerts::DiscordAlerting,
+    events::AllEvents,
+    routing::Route,
+    run_scrape_ingester::create_writer,
+    settings::{OverflowPolicy, RestartSettings, WriterSettings},
+};
+
+pub mod spill;
+
+/// How often a blocked ingester checks whether the writer caught up
+pub const BLOCK_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Debug, Default)]
+pub struct QueueMetrics {
+    /// Events waiting in memory
+    pub depth: usize,
+    /// Events waiting in the spill file
+    pub spilled: usize,
+    /// Events dropped since startup because the queue was full
+    pub dropped: u64,
+}
+
+/// Keeps a writer instance running, restarting it with backoff when it fails. Events wait in a
+/// bounded queue while the writer is behind or down, what happens when the queue is full depends
+/// on the writer's overflow policy.
+pub struct SupervisedWriter {
+    config: WriterSettings,
+    alerting: Arc<DiscordAlerting>,
+    route: Route,
+    writer: Option<Writers>,
+    queue: VecDeque<AllEvents>,
+    spill: Option<SpillFile>,
+    /// Failures since the writer last worked
+    failures: u32,
+    /// Events dropped since the writer last worked, reported on recovery
+    outage_dropped: u64,
+    dropped: u64,
+    retry_at: Instant,
+}
+
+impl SupervisedWriter {
+    /// Creates the writer, failing at startup is treated as a config error and isn't retried.
+    pub async fn start(
+        config: WriterSettings,
+        alerting: Arc<DiscordAlerting>,
+    ) -> Result<SupervisedWriter> {
+        let writer = create_writer(&config, &alerting).await?;
+        let spill = match config.queue.overflow {
+            OverflowPolicy::Spill => {
+                let path =
+                    Path::new(&config.queue.spill_directory).join(format!("{}.jsonl", config.name));
+                let spill = SpillFile::open(path).await?;
+                if !spill.is_empty() {
+                    info!(
+                        "Writer '{}' has {} spilled events from a previous run",
+                        config.name,
+                        spill.len()
+                    );
+                }
+                Some(spill)
+            }
+            _ => None,
+        };
+        Ok(SupervisedWriter {
+            route: Route::new(&config.routes),
+            config,
+            alerting,
+            writer: Some(writer),
+            queue: VecDeque::new(),
+            spill,
+            failures: 0,
+            outage_dropped: 0,
+            dropped: 0,
+            retry_at: Instant::now(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    pub fn metrics(&self) -> QueueMetrics {
+        QueueMetrics {
+            depth: self.queue.len(),
+            spilled: self.spill.as_ref().map_or(0, |spill| spill.len()),
+            dropped: self.dropped,
+        }
+    }
+
+    /// Whether ingestion has to wait for this writer, because its queue is full and its overflow
+    /// policy is to block
+    pub fn is_blocking(&self) -> bool {
+        self.config.queue.overflow == OverflowPolicy::Block
+            && self.queue.len() >= self.config.restart.max_buffered_events
+    }
+
+    pub async fn write(&mut self, event: &AllEvents) {
+        if !self.route.matches(event) {
+            return;
+        }
+        self.enqueue(event.clone()).await;
+        self.poll().await;
+    }
+
+    /// Restarts the writer if it's down and its backoff has passed, then writes as much of the
+    /// queue as it accepts.
+    pub async fn poll(&mut self) {
+        if self.writer.is_none() {
+            if Instant::now() < self.retry_at {
+                return;
+            }
+            info!(
+                "Restarting writer '{}', attempt {}",
+                self.name(),
+                self.failures
+            );
+            match create_writer(&self.config, &self.alerting).await {
+                Ok(writer) => self.writer = Some(writer),
+                Err(e) => {
+                    self.failures += 1;
+                    let delay = self.schedule_retry();
+                    warn!(
+                        "Restarting writer '{}' failed, retrying in {:?}: {:?}",
+                        self.name(),
+                        delay,
+                        e
+                    );
+                    return;
+                }
+            }
+        }
+
+        loop {
+            while let Some(event) = self.queue.front() {
+                let result = match &self.writer {
+                    Some(writer) => writer.write(event.clone()),
+                    None => return,
+                };
+                match result {
+                    Ok(()) => {
+                        self.queue.pop_front();
+                    }
+                    Err(e) if e.is::<WriterFull>() => return,
+                    Err(e) => {
+                        self.fail(e);
+                        return;
+                    }
+                }
+            }
+            if !self.refill_from_spill().await {
+                break;
+            }
+        }
+
+        if self.failures > 0 {
+            let message = format!(
+                "Writer '{}' recovered after {} failures, dropped {} events while it was down",
+                self.name(),
+                self.failures,
+                self.outage_dropped
+            );
+            info!("{}", message);
+            self.alerting.info(&message);
+            self.failures = 0;
+            self.outage_dropped = 0;
+        }
+    }
+
+    /// Writes out the queue, then flushes and stops the writer. Whatever isn't written by the
+    /// deadline is lost, apart from spilled events which are picked up on the next start.
+    pub async fn shutdown(&mut self, deadline: Instant) -> Result<()> {
+        loop {
+            self.poll().await;
+            let spilled = self.spill.as_ref().map_or(0, |spill| spill.len());
+            if self.writer.is_some() && self.queue.is_empty() && spilled == 0 {
+                break;
+            }
+            if Instant::now() >= deadline {
+                if let Some(spill) = &mut self.spill {
+                    spill.flush().await?;
+                }
+                bail!(
+                    "Timed out with {} events queued and {} spilled",
+                    self.queue.len(),
+                    spilled
+                );
+            }
+            tokio::time::sleep(BLOCK_RETRY_DELAY).await;
+        }
+
+        match self.writer.take() {
+            Some(writer) => tokio::time::timeout_at(deadline.into(), writer.shutdown())
+                .await
+                .context("Timed out flushing the writer")?,
+            None => Ok(()),
+        }
+    }
+
+    async fn enqueue(&mut self, event: AllEvents) {
+        let has_spilled = self.spill.as_ref().map_or(false, |spill| !spill.is_empty());
+        if self.queue.len() < self.config.restart.max_buffered_events && !has_spilled {
+            self.queue.push_back(event);
+            return;
+        }
+
+        match self.config.queue.overflow {
+            // The ingester stops taking events while `is_blocking`, so this only goes past the
+            // limit for events that were already taken, like the ones drained on shutdown
+            OverflowPolicy::Block => self.queue.push_back(event),
+            OverflowPolicy::DropOldest => {
+                self.queue.pop_front();
+                self.queue.push_back(event);
+                self.count_dropped(1);
+            }
+            OverflowPolicy::Spill => {
+                let result = match &mut self.spill {
+                    Some(spill) => spill.push(&event).await,
+                    None => Ok(()),
+                };
+                if let Err(e) = result {
+                    error!(
+                        "Spilling event for writer '{}' failed, dropping it: {:?}",
+                        self.name(),
+                        e
+                    );
+                    self.count_dropped(1);
+                }
+            }
+        }
+    }
+
+    /// Moves spilled events back into the queue while it has room, returns whether any were moved
+    async fn refill_from_spill(&mut self) -> bool {
+        let spill = match &mut self.spill {
+            Some(spill) => spill,
+            None => return false,
+        };
+        let mut moved = false;
+        while self.queue.len() < self.config.restart.max_buffered_events && !spill.is_empty() {
+            match spill.pop().await {
+                Ok(Some(event)) => {
+                    self.queue.push_back(event);
+                    moved = true;
+                }
+                Ok(None) => break,
+                Err(e) => error!(
+                    "Reading spilled event for writer '{}' failed, skipping it: {:?}",
+                    self.config.name, e
+                ),
+            }
+        }
+        if let Err(e) = spill.flush().await {
+            error!(
+                "Flushing spill file for writer '{}' failed: {:?}",
+                self.config.name, e
+            );
+        }
+        moved
+    }
+
+    fn count_dropped(&mut self, count: u64) {
+        if self.dropped == 0 {
+            warn!("Queue of writer '{}' is full, dropping events", self.name());
+        }
+        self.dropped += count;
+        self.outage_dropped += count;
+    }
+
+    fn fail(&mut self, e: anyhow::Error) {
+        self.writer = None;
+        self.failures += 1;
+        let delay = self.schedule_retry();
+        error!(
+            "Writer '{}' failed, restarting in {:?}: {:?}",
+            self.name(),
+            delay,
+            e
+        );
+        // Only alert once per outage, recovery is reported separately
+        if self.failures == 1 {
+            self.alerting.error(&format!(
+                "Writer '{}' failed, restarting it in {:?} and queueing its events: {:#}",
+                self.name(),
+                delay,
+                e
+            ));
+        }
+    }
+
+    fn schedule_retry(&mut self) -> Duration {
+        let delay = backoff_delay(&self.config.restart, self.failures);
+        self.retry_at = Instant::now() + delay;
+        delay
+    }
+}
+
+/// Exponential backoff starting at the base delay, capped at the max delay
+fn backoff_delay(settings: &RestartSettings, failures: u32) -> Duration {
+    let factor = 2u64.saturating_pow(failures.saturating_sub(1));
+    Duration::from_secs(
+        settings
+            .base_delay_seconds
+            .saturating_mul(factor)
+            .min(settings.max_delay_seconds),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        scrapers::dgg::test_dgg_message,
+        settings::{
+            DiscordAlertingSettings, QueueSettings, RouteSettings, WalSettings, WriterKindSettings,
+        },
+    };
+
+    /// A console writer with room for 2 events that's down and won't be restarted until
+    /// `retry_at` is reset
+    async fn down_writer(queue: QueueSettings) -> SupervisedWriter {
+        let alerting = DiscordAlerting::new(DiscordAlertingSettings {
+            enabled: false,
+            webhook_url: None,
+            owner: None,
+        });
+        let config = WriterSettings {
+            name: format!("console-{}", std::process::id()),
+            enabled: true,
+            routes: RouteSettings::default(),
+            restart: RestartSettings {
+                max_buffered_events: 2,
+                ..RestartSettings::default()
+            },
+            queue,
+            wal: WalSettings::default(),
+            kind: WriterKindSettings::Console,
+        };
+        let mut supervised = SupervisedWriter::start(config, alerting).await.unwrap();
+        supervised.writer = None;
+        supervised.failures = 1;
+        supervised.retry_at = Instant::now() + Duration::from_secs(60);
+        supervised
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let settings = RestartSettings {
+            base_delay_seconds: 5,
+            max_delay_seconds: 60,
+            ..RestartSettings::default()
+        };
+        let delays: Vec<u64> = [1, 2, 3, 4, 5, 100]
+            .iter()
+            .map(|failures| backoff_delay(&settings, *failures).as_secs())
+            .collect();
+        assert_eq!(delays, vec![5, 10, 20, 40, 60, 60]);
+    }
+
+    #[tokio::test]
+    async fn test_drops_oldest_while_down_and_recovers() {
+        let mut supervised = down_writer(QueueSettings::default()).await;
+
+        for text in ["one", "two", "three"] {
+            supervised.write(&test_dgg_message("bob", text)).await;
+        }
+        assert!(supervised.writer.is_none());
+        let metrics = supervised.metrics();
+        assert_eq!((metrics.depth, metrics.dropped), (2, 1));
+
+        supervised.retry_at = Instant::now();
+        supervised.poll().await;
+        assert!(supervised.writer.is_some());
+        assert_eq!(supervised.metrics().depth, 0);
+        assert_eq!(supervised.failures, 0);
+        assert_eq!(supervised.outage_dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn test_block_holds_up_ingestion_without_waiting() {
+        let mut supervised = down_writer(QueueSettings {
+            overflow: OverflowPolicy::Block,
+            ..QueueSettings::default()
+        })
+        .await;
+
+        supervised.write(&test_dgg_message("bob", "one")).await;
+        assert!(!supervised.is_blocking());
+        supervised.write(&test_dgg_message("bob", "two")).await;
+        assert!(supervised.is_blocking());
+        // Events that were already taken aren't lost
+        supervised.write(&test_dgg_message("bob", "three")).await;
+        let metrics = supervised.metrics();
+        assert_eq!((metrics.depth, metrics.dropped), (3, 0));
+
+        supervised.retry_at = Instant::now();
+        supervised.poll().await;
+        assert!(!supervised.is_blocking());
+    }
+
+    #[tokio::test]
+    async fn test_spills_while_down_and_recovers() {
+        let directory = std::env::temp_dir().join(format!("tl2-spill-dir-{}", std::process::id()));
+        let mut supervised = down_writer(QueueSettings {
+            overflow: OverflowPolicy::Spill,
+            spill_directory: directory.to_string_lossy().into(),
+        })
+        .await;
+
+        for text in ["one", "two", "three", "four"] {
+            supervised.write(&test_dgg_message("bob", text)).await;
+        }
+        let metrics = supervised.metrics();
+        assert_eq!((metrics.depth, metrics.spilled, metrics.dropped), (2, 2, 0));
+
+        supervised.retry_at = Instant::now();
+        supervised.poll().await;
+        let metrics = supervised.metrics();
+        assert_eq!((metrics.depth, metrics.spilled), (0, 0));
+        assert_eq!(supervised.failures, 0);
+
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_queue_before_deadline() {
+        let mut supervised = down_writer(QueueSettings::default()).await;
+        for text in ["one", "two"] {
+            supervised.write(&test_dgg_message("bob", text)).await;
+        }
+
+        // The writer isn't due for a restart before the deadline
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert!(supervised.shutdown(deadline).await.is_err());
+        assert_eq!(supervised.metrics().depth, 2);
+
+        supervised.retry_at = Instant::now();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        supervised.shutdown(deadline).await.unwrap();
+        assert_eq!(supervised.metrics().depth, 0);
+        assert!(supervised.writer.is_none());
+    }
+}
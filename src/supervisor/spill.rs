@@ -0,0 +1,165 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, Lines},
+};
+
+use crate::{
+    events::{AllEvents, RawEvent},
+    sources::capture::parse_record,
+};
+
+/// Events that didn't fit in a writer's queue. They're appended to a file in the capture format
+/// and read back in order once the writer catches up, the file is removed once it's drained.
+pub struct SpillFile {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+    reader: Option<Lines<BufReader<File>>>,
+    len: usize,
+}
+
+impl SpillFile {
+    /// Opens the spill file, picking up events left over from a previous run. Events that were
+    /// already read back before the process stopped are read again, so they may be duplicated.
+    pub async fn open(path: PathBuf) -> Result<SpillFile> {
+        let mut len = 0;
+        if let Ok(file) = File::open(&path).await {
+            let mut lines = BufReader::new(file).lines();
+            while lines.next_line().await?.is_some() {
+                len += 1;
+            }
+        }
+        Ok(SpillFile {
+            path,
+            writer: None,
+            reader: None,
+            len,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub async fn push(&mut self, event: &AllEvents) -> Result<()> {
+        if self.writer.is_none() {
+            if let Some(parent) = self.path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await
+                .with_context(|| format!("Opening spill file {:?} failed", self.path))?;
+            self.writer = Some(BufWriter::new(file));
+        }
+        if let Some(writer) = &mut self.writer {
+            let line = serde_json::to_string(&event.to_raw())? + "\n";
+            writer.write_all(line.as_bytes()).await?;
+            self.len += 1;
+        }
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Reads back the oldest spilled event. A record that can't be parsed is still removed, so
+    /// one bad line doesn't get the writer stuck.
+    pub async fn pop(&mut self) -> Result<Option<AllEvents>> {
+        if self.len == 0 {
+            return Ok(None);
+        }
+        self.flush().await?;
+        if self.reader.is_none() {
+            let file = File::open(&self.path)
+                .await
+                .with_context(|| format!("Opening spill file {:?} failed", self.path))?;
+            self.reader = Some(BufReader::new(file).lines());
+        }
+        let line = match &mut self.reader {
+            Some(reader) => reader.next_line().await?,
+            None => None,
+        };
+        let line = match line {
+            Some(line) => line,
+            None => {
+                self.reset().await?;
+                return Ok(None);
+            }
+        };
+        self.len -= 1;
+        if self.len == 0 {
+            self.reset().await?;
+        }
+
+        let record: RawEvent = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid spill record: {:?}", line))?;
+        parse_record(record).map(Some)
+    }
+
+    async fn reset(&mut self) -> Result<()> {
+        self.len = 0;
+        self.writer = None;
+        self.reader = None;
+        match tokio::fs::remove_file(&self.path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{events::SimpleMessageGroup, scrapers::dgg::test_dgg_message};
+
+    fn text_of(event: AllEvents) -> String {
+        SimpleMessageGroup::from(event).0[0].text.clone()
+    }
+
+    #[tokio::test]
+    async fn test_spills_and_reads_back_in_order() {
+        let path = std::env::temp_dir().join(format!("tl2-spill-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut spill = SpillFile::open(path.clone()).await.unwrap();
+        spill.push(&test_dgg_message("bob", "one")).await.unwrap();
+        spill.push(&test_dgg_message("bob", "two")).await.unwrap();
+        // Events keep when they were scraped, not when they were spilled
+        let one = spill.pop().await.unwrap().unwrap();
+        assert_eq!(
+            one.received_at(),
+            test_dgg_message("bob", "one").received_at()
+        );
+        assert_eq!(text_of(one), "one");
+
+        // Events pushed while reading are appended after the ones already spilled
+        spill.push(&test_dgg_message("bob", "three")).await.unwrap();
+        assert_eq!(spill.len(), 2);
+        spill.flush().await.unwrap();
+        drop(spill);
+
+        // Reopening starts over from the start of the file
+        let mut spill = SpillFile::open(path.clone()).await.unwrap();
+        assert_eq!(spill.len(), 3);
+        let mut texts = Vec::new();
+        while let Some(event) = spill.pop().await.unwrap() {
+            texts.push(text_of(event));
+        }
+        assert_eq!(texts, vec!["one", "two", "three"]);
+        assert!(spill.is_empty());
+        assert!(!path.exists());
+    }
+}
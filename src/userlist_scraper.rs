@@ -0,0 +1,268 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    events::{AllEvents, EventCategory},
+    formats::unified::ChannelType,
+};
+
+/// A change to who is in a chat, from dgg `NAMES`, `JOIN` and `QUIT` or twitch `353`/`366`
+/// names replies, `JOIN` and `PART`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PresenceChange<'a> {
+    /// Who's in chat when we connect. dgg sends everyone at once, twitch splits the list over
+    /// several replies and ends it with an empty one, only the last is `is_complete`.
+    Names {
+        users: Vec<&'a str>,
+        connection_count: Option<u32>,
+        is_complete: bool,
+    },
+    Join(&'a str),
+    Leave(&'a str),
+}
+
+/// A chat at one point in time, along with what happened in it since the previous snapshot
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PresenceSnapshot {
+    pub timestamp: DateTime<Utc>,
+    pub platform: ChannelType,
+    pub channel: String,
+    /// Users in chat, `None` until the platform told us who's there. Twitch only does that with
+    /// the membership capability.
+    pub chatters: Option<u64>,
+    /// Open dgg websocket connections, including ones that aren't logged in. dgg only sends it
+    /// with `NAMES`, so it's the count from when we last connected.
+    pub connection_count: Option<u64>,
+    pub joins: u64,
+    pub leaves: u64,
+    pub messages: u64,
+    /// Viewers of the stream, 0 while it's offline. `None` when they weren't polled, which is
+    /// only done for twitch with helix credentials.
+    pub viewers: Option<u64>,
+    /// Sorted logins of the users in chat
+    pub users: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct ChannelPresence {
+    /// `None` until the first complete names list
+    users: Option<HashSet<String>>,
+    /// A names list that's still coming in
+    names: Option<HashSet<String>>,
+    connection_count: Option<u64>,
+    joins: u64,
+    leaves: u64,
+    messages: u64,
+}
+
+/// Who is in which chat, kept up to date from the events of every platform. Message counts are
+/// kept alongside, so chatter counts can be charted next to message rates.
+#[derive(Debug, Default)]
+pub struct Userlists {
+    channels: HashMap<(ChannelType, String), ChannelPresence>,
+}
+
+impl Userlists {
+    pub fn apply(&mut self, event: &AllEvents) {
+        let change = match (event, event.category()) {
+            (_, EventCategory::Message) => None,
+            (AllEvents::Dgg(event), EventCategory::Presence) => event.presence(),
+            (AllEvents::Twitch(event), EventCategory::Presence) => event.presence(),
+            _ => return,
+        };
+        let channel = match event.channel() {
+            Some(channel) => channel.to_string(),
+            None => return,
+        };
+        let presence = self
+            .channels
+            .entry((event.platform(), channel))
+            .or_default();
+
+        match change {
+            None => presence.messages += 1,
+            Some(PresenceChange::Names {
+                users,
+                connection_count,
+                is_complete,
+            }) => {
+                let names = presence.names.get_or_insert_with(HashSet::new);
+                names.extend(users.into_iter().map(str::to_string));
+                if is_complete {
+                    presence.users = presence.names.take();
+                }
+                if let Some(connection_count) = connection_count {
+                    presence.connection_count = Some(connection_count as u64);
+                }
+            }
+            // Without a userlist there's no telling repeats apart, every join and leave counts
+            Some(PresenceChange::Join(user)) => {
+                let is_new = match &mut presence.users {
+                    Some(users) => users.insert(user.to_string()),
+                    None => true,
+                };
+                if is_new {
+                    presence.joins += 1;
+                }
+            }
+            Some(PresenceChange::Leave(user)) => {
+                let was_there = match &mut presence.users {
+                    Some(users) => users.remove(user),
+                    None => true,
+                };
+                if was_there {
+                    presence.leaves += 1;
+                }
+            }
+        }
+    }
+
+    /// A snapshot of every chat we've seen events from, sorted by platform and channel. Starts
+    /// counting joins, leaves and messages from zero again.
+    pub fn snapshot(&mut self, timestamp: DateTime<Utc>) -> Vec<PresenceSnapshot> {
+        let mut snapshots: Vec<PresenceSnapshot> = self
+            .channels
+            .iter_mut()
+            .map(|((platform, channel), presence)| {
+                let mut users: Vec<String> = presence
+                    .users
+                    .iter()
+                    .flat_map(|users| users.iter().cloned())
+                    .collect();
+                users.sort();
+                let snapshot = PresenceSnapshot {
+                    timestamp,
+                    platform: *platform,
+                    channel: channel.clone(),
+                    chatters: presence.users.as_ref().map(|users| users.len() as u64),
+                    connection_count: presence.connection_count,
+                    joins: presence.joins,
+                    leaves: presence.leaves,
+                    messages: presence.messages,
+                    viewers: None,
+                    users,
+                };
+                presence.joins = 0;
+                presence.leaves = 0;
+                presence.messages = 0;
+                snapshot
+            })
+            .collect();
+        snapshots.sort_by(|a, b| {
+            (a.platform.as_ref(), &a.channel).cmp(&(b.platform.as_ref(), &b.channel))
+        });
+        snapshots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use twitch_irc::message::{IRCMessage, IRCPrefix, IRCTags};
+
+    use super::*;
+    use crate::scrapers::{
+        dgg::parse_test_frame,
+        twitch::events::{NamesReply, TwitchEvent, TwitchMessage},
+    };
+
+    fn dgg(raw: &str) -> AllEvents {
+        parse_test_frame(raw).into()
+    }
+
+    fn twitch_names(command: &str, params: &[&str]) -> AllEvents {
+        let source = IRCMessage {
+            tags: IRCTags(HashMap::new()),
+            prefix: Some(IRCPrefix::HostOnly {
+                host: "justinfan123.tmi.twitch.tv".into(),
+            }),
+            command: command.into(),
+            params: params.iter().map(|p| p.to_string()).collect(),
+        };
+        let names = TwitchMessage::Names(NamesReply::parse(&source).unwrap());
+        TwitchEvent::new(names, Utc::now()).into()
+    }
+
+    #[test]
+    fn test_dgg_userlist() {
+        let mut userlists = Userlists::default();
+        userlists.apply(&dgg(concat!(
+            r#"NAMES {"connectioncount":30,"users":"#,
+            r#"[{"nick":"a","features":[]},{"nick":"b","features":[]}]}"#,
+        )));
+        userlists.apply(&dgg(
+            r#"JOIN {"nick":"c","features":[],"timestamp":1628035200095}"#,
+        ));
+        userlists.apply(&dgg(
+            r#"QUIT {"nick":"a","features":[],"timestamp":1628035200095}"#,
+        ));
+        // Quitting twice doesn't count twice
+        userlists.apply(&dgg(
+            r#"QUIT {"nick":"a","features":[],"timestamp":1628035200095}"#,
+        ));
+        userlists.apply(&dgg(
+            r#"MSG {"nick":"b","features":[],"timestamp":1628035200095,"data":"hi"}"#,
+        ));
+
+        let now = Utc.timestamp_millis_opt(1628035260000).unwrap();
+        let snapshots = userlists.snapshot(now);
+        assert_eq!(
+            snapshots,
+            vec![PresenceSnapshot {
+                timestamp: now,
+                platform: ChannelType::Dgg,
+                channel: "Destinygg".to_string(),
+                chatters: Some(2),
+                connection_count: Some(30),
+                joins: 1,
+                leaves: 1,
+                messages: 1,
+                viewers: None,
+                users: vec!["b".to_string(), "c".to_string()],
+            }]
+        );
+
+        // Counters start over, the userlist stays
+        let snapshot = &userlists.snapshot(now)[0];
+        assert_eq!(
+            (snapshot.joins, snapshot.leaves, snapshot.messages),
+            (0, 0, 0)
+        );
+        assert_eq!(snapshot.chatters, Some(2));
+    }
+
+    #[test]
+    fn test_twitch_names_and_joins_before_them() {
+        let mut userlists = Userlists::default();
+        let now = Utc.timestamp_millis_opt(1628035260000).unwrap();
+        userlists.apply(&twitch_names(
+            "353",
+            &["justinfan123", "=", "#xqcow", "a b justinfan123"],
+        ));
+        // The list isn't complete yet
+        assert_eq!(userlists.snapshot(now)[0].chatters, None);
+
+        userlists.apply(&twitch_names("353", &["justinfan123", "=", "#xqcow", "c"]));
+        userlists.apply(&twitch_names(
+            "366",
+            &["justinfan123", "#xqcow", "End of /NAMES list"],
+        ));
+        let snapshot = &userlists.snapshot(now)[0];
+        assert_eq!(
+            (snapshot.platform, snapshot.chatters),
+            (ChannelType::Twitch, Some(3))
+        );
+        assert_eq!(snapshot.users, vec!["a", "b", "c"]);
+        assert_eq!(snapshot.connection_count, None);
+
+        // Joins before the first names list are counted, but don't make up a userlist
+        let mut userlists = Userlists::default();
+        userlists.apply(&dgg(
+            r#"JOIN {"nick":"c","features":[],"timestamp":1628035200095}"#,
+        ));
+        let snapshot = &userlists.snapshot(now)[0];
+        assert_eq!((snapshot.chatters, snapshot.joins), (None, 1));
+        assert!(snapshot.users.is_empty());
+    }
+}
@@ -0,0 +1,620 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, Lines},
+    sync::{
+        mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender},
+        oneshot, watch, Mutex as AsyncMutex, OwnedMutexGuard,
+    },
+};
+
+use crate::{
+    adapters::{writer_channel, WorkerMessage},
+    events::{AllEvents, RawEvent},
+    sources::capture::parse_record,
+};
+
+const SEGMENT_EXTENSION: &str = "wal";
+const CHECKPOINT_FILE: &str = "checkpoint";
+
+/// Checkpoints are kept in memory between writes, a crash replays at most this much extra
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a restarted writer waits for the previous appender to finish with the log
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One lock per log directory, held by its appender. A writer that gets restarted opens the log
+/// again while the old appender may still be appending what it accepted, recovering the segment
+/// under it would cut off or interleave those records.
+static WAL_LOCKS: Mutex<BTreeMap<PathBuf, Arc<AsyncMutex<()>>>> = Mutex::new(BTreeMap::new());
+
+/// Waits for the previous appender of `dir` to be gone
+async fn lock_wal(dir: &Path) -> Result<OwnedMutexGuard<()>> {
+    let lock = WAL_LOCKS
+        .lock()
+        .unwrap()
+        .entry(dir.to_path_buf())
+        .or_default()
+        .clone();
+    match tokio::time::timeout(HANDOVER_TIMEOUT, lock.lock_owned()).await {
+        Ok(guard) => Ok(guard),
+        Err(_) => bail!("WAL {:?} is still in use by the previous appender", dir),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct WalRecord {
+    seq: u64,
+    event: RawEvent,
+}
+
+/// Where a network writer's worker reads its events from. With a write-ahead log events are on
+/// disk before the worker sees them, and a failed batch is read again from the last checkpoint.
+pub enum WorkerInput {
+    Channel(Receiver<WorkerMessage<AllEvents>>),
+    Wal(WalReader),
+}
+
+impl WorkerInput {
+    /// Creates the writer's channel, with a write-ahead log in `wal_directory` when it's set.
+    pub async fn new(
+        wal_directory: Option<PathBuf>,
+        segment_bytes: u64,
+    ) -> Result<(Sender<WorkerMessage<AllEvents>>, WorkerInput)> {
+        let (tx, rx) = writer_channel();
+        match wal_directory {
+            Some(directory) => {
+                let (appender, reader) = open_wal(directory, segment_bytes).await?;
+                appender.spawn(rx);
+                Ok((tx, WorkerInput::Wal(reader)))
+            }
+            None => Ok((tx, WorkerInput::Channel(rx))),
+        }
+    }
+
+    /// The next event and its sequence number, which is always 0 without a write-ahead log
+    pub async fn next(&mut self) -> Result<Option<WorkerMessage<(u64, AllEvents)>>> {
+        match self {
+            WorkerInput::Channel(rx) => Ok(rx.recv().await.map(|message| match message {
+                WorkerMessage::Item(event) => WorkerMessage::Item((0, event)),
+                WorkerMessage::Flush(done) => WorkerMessage::Flush(done),
+            })),
+            WorkerInput::Wal(reader) => reader.next().await,
+        }
+    }
+
+    /// Marks every event up to `seq` as committed downstream
+    pub async fn commit(&mut self, seq: u64) -> Result<()> {
+        match self {
+            WorkerInput::Channel(_) => Ok(()),
+            WorkerInput::Wal(reader) => reader.commit(seq).await,
+        }
+    }
+
+    /// Persists the last commit right away instead of waiting for the checkpoint interval, so a
+    /// flushed writer doesn't replay anything after a restart.
+    pub async fn checkpoint(&mut self) -> Result<()> {
+        match self {
+            WorkerInput::Channel(_) => Ok(()),
+            WorkerInput::Wal(reader) => reader.persist().await,
+        }
+    }
+
+    /// Goes back to the last commit after a failure, without a write-ahead log the uncommitted
+    /// events are lost.
+    pub fn rewind(&mut self) {
+        if let WorkerInput::Wal(reader) = self {
+            reader.rewind();
+        }
+    }
+}
+
+/// Opens the write-ahead log in `dir`, creating it if needed. The reader starts after the last
+/// checkpoint, so anything that wasn't committed before a crash or restart is read again. Waits
+/// for an appender of the same log that's still running to finish first.
+pub async fn open_wal(dir: PathBuf, segment_bytes: u64) -> Result<(WalAppender, WalReader)> {
+    let lock = lock_wal(&dir).await?;
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("Creating WAL directory {:?} failed", dir))?;
+    let checkpoint = read_checkpoint(&dir).await?;
+    let segments = list_segments(&dir).await?;
+
+    let mut next_seq = checkpoint + 1;
+    let mut segment = None;
+    if let Some(&start) = segments.last() {
+        let path = segment_path(&dir, start);
+        let last_seq = recover_segment(&path).await?;
+        next_seq = next_seq.max(last_seq.map_or(start, |seq| seq + 1));
+        let file = OpenOptions::new().append(true).open(&path).await?;
+        let size = file.metadata().await?.len();
+        segment = Some((BufWriter::new(file), size));
+    }
+    if next_seq - 1 > checkpoint {
+        info!(
+            "WAL {:?} has {} uncommitted events",
+            dir,
+            next_seq - 1 - checkpoint
+        );
+    }
+
+    let (flushed_tx, flushed_rx) = watch::channel(next_seq - 1);
+    let (flush_requests_tx, flush_requests_rx) = mpsc::unbounded_channel();
+    let appender = WalAppender {
+        dir: dir.clone(),
+        segment,
+        segment_bytes,
+        next_seq,
+        flushed: flushed_tx,
+        flush_requests: flush_requests_tx,
+        _lock: lock,
+    };
+    let reader = WalReader {
+        dir,
+        flushed: flushed_rx,
+        flush_requests: flush_requests_rx,
+        pending_flushes: VecDeque::new(),
+        position: checkpoint,
+        committed: checkpoint,
+        persisted: checkpoint,
+        persisted_at: Instant::now(),
+        lines: None,
+    };
+    Ok((appender, reader))
+}
+
+/// Appends events to the newest segment, starting a new segment once it's `segment_bytes` long.
+pub struct WalAppender {
+    dir: PathBuf,
+    segment: Option<(BufWriter<File>, u64)>,
+    segment_bytes: u64,
+    next_seq: u64,
+    /// The last sequence number that's synced to disk, readers don't read past it
+    flushed: watch::Sender<u64>,
+    /// Flushes are passed on to the reader, to reply once it read up to the sequence number
+    flush_requests: UnboundedSender<FlushRequest>,
+    /// Released once the appender is dropped, which lets the log be opened again
+    _lock: OwnedMutexGuard<()>,
+}
+
+impl WalAppender {
+    /// Appends everything sent to `rx`, until every sender or the reader is dropped or the disk
+    /// fails. Dropping `rx` makes the writer fail, so it gets restarted by its supervisor.
+    pub fn spawn(mut self, mut rx: Receiver<WorkerMessage<AllEvents>>) {
+        tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                let mut messages = vec![message];
+                while let Ok(message) = rx.try_recv() {
+                    messages.push(message);
+                }
+                match self.append_all(messages).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        // Keep what's already been accepted, it's replayed on restart
+                        rx.close();
+                        let mut messages = Vec::new();
+                        while let Some(message) = rx.recv().await {
+                            messages.push(message);
+                        }
+                        if let Err(e) = self.append_all(messages).await {
+                            error!("Writing to WAL {:?} failed: {:?}", self.dir, e);
+                        }
+                        return;
+                    }
+                    Err(e) => {
+                        error!("Writing to WAL {:?} failed: {:?}", self.dir, e);
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Appends and syncs the events, returns whether the reader is still around
+    pub async fn append_all(&mut self, messages: Vec<WorkerMessage<AllEvents>>) -> Result<bool> {
+        for message in messages {
+            match message {
+                WorkerMessage::Item(event) => {
+                    self.append(&event).await?;
+                }
+                WorkerMessage::Flush(done) => {
+                    self.flush().await?;
+                    // If the reader is gone the flush fails when `done` is dropped
+                    let _ = self.flush_requests.send((self.next_seq - 1, done));
+                }
+            }
+        }
+        self.flush().await
+    }
+
+    async fn append(&mut self, event: &AllEvents) -> Result<u64> {
+        let needs_segment = match &self.segment {
+            Some((_, size)) => *size >= self.segment_bytes,
+            None => true,
+        };
+        if needs_segment {
+            self.roll().await?;
+        }
+
+        let seq = self.next_seq;
+        let record = WalRecord {
+            seq,
+            event: event.to_raw(),
+        };
+        let line = serde_json::to_string(&record)? + "\n";
+        if let Some((writer, size)) = &mut self.segment {
+            writer.write_all(line.as_bytes()).await?;
+            *size += line.len() as u64;
+        }
+        self.next_seq += 1;
+        Ok(seq)
+    }
+
+    async fn flush(&mut self) -> Result<bool> {
+        if let Some((writer, _)) = &mut self.segment {
+            writer.flush().await?;
+            writer.get_ref().sync_data().await?;
+        }
+        Ok(self.flushed.send(self.next_seq - 1).is_ok())
+    }
+
+    async fn roll(&mut self) -> Result<()> {
+        if let Some((mut writer, _)) = self.segment.take() {
+            writer.flush().await?;
+            writer.get_ref().sync_all().await?;
+        }
+        let path = segment_path(&self.dir, self.next_seq);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .with_context(|| format!("Creating WAL segment {:?} failed", path))?;
+        self.segment = Some((BufWriter::new(file), 0));
+        Ok(())
+    }
+}
+
+/// Reads events in order from the checkpoint on, waiting for the appender at the end of the log.
+pub struct WalReader {
+    dir: PathBuf,
+    flushed: watch::Receiver<u64>,
+    flush_requests: UnboundedReceiver<FlushRequest>,
+    pending_flushes: VecDeque<FlushRequest>,
+    /// The last sequence number that was read
+    position: u64,
+    committed: u64,
+    /// The last checkpoint written to disk
+    persisted: u64,
+    persisted_at: Instant,
+    /// The start of the segment being read and its lines
+    lines: Option<(u64, Lines<BufReader<File>>)>,
+}
+
+impl WalReader {
+    /// The next event, or `None` once the appender is gone and everything was read
+    pub async fn next(&mut self) -> Result<Option<WorkerMessage<(u64, AllEvents)>>> {
+        loop {
+            while let Ok(request) = self.flush_requests.try_recv() {
+                self.pending_flushes.push_back(request);
+            }
+            if let Some((seq, _)) = self.pending_flushes.front() {
+                if *seq <= self.position {
+                    if let Some((_, done)) = self.pending_flushes.pop_front() {
+                        return Ok(Some(WorkerMessage::Flush(done)));
+                    }
+                }
+            }
+
+            let flushed = *self.flushed.borrow_and_update();
+            if self.position >= flushed {
+                if self.committed > self.persisted {
+                    self.persist().await?;
+                }
+                tokio::select! {
+                    changed = self.flushed.changed() => {
+                        if changed.is_err() {
+                            return Ok(None);
+                        }
+                    }
+                    Some(request) = self.flush_requests.recv() => {
+                        self.pending_flushes.push_back(request);
+                    }
+                }
+                continue;
+            }
+
+            let wanted = self.position + 1;
+            let (start, lines) = match &mut self.lines {
+                Some((start, lines)) => (*start, lines),
+                None => {
+                    let start = find_segment(&self.dir, wanted).await?;
+                    let path = segment_path(&self.dir, start);
+                    let file = File::open(&path)
+                        .await
+                        .with_context(|| format!("Opening WAL segment {:?} failed", path))?;
+                    let (_, lines) = self.lines.insert((start, BufReader::new(file).lines()));
+                    (start, lines)
+                }
+            };
+
+            let line = match lines.next_line().await? {
+                Some(line) => line,
+                None => {
+                    // The rest of the log is in the next segment
+                    if find_segment(&self.dir, wanted).await? == start {
+                        bail!("WAL {:?} is missing record {}", self.dir, wanted);
+                    }
+                    self.lines = None;
+                    continue;
+                }
+            };
+            let record: WalRecord = serde_json::from_str(&line).with_context(|| {
+                format!("Invalid record in WAL segment {} of {:?}", start, self.dir)
+            })?;
+            if record.seq < wanted {
+                continue;
+            }
+            self.position = record.seq;
+            match parse_record(record.event) {
+                Ok(event) => return Ok(Some(WorkerMessage::Item((record.seq, event)))),
+                Err(e) => warn!(
+                    "Skipping WAL record {} that can't be parsed: {:?}",
+                    record.seq, e
+                ),
+            }
+        }
+    }
+
+    pub async fn commit(&mut self, seq: u64) -> Result<()> {
+        if seq <= self.committed {
+            return Ok(());
+        }
+        self.committed = seq;
+        if self.persisted_at.elapsed() >= CHECKPOINT_INTERVAL {
+            self.persist().await?;
+        }
+        Ok(())
+    }
+
+    pub fn rewind(&mut self) {
+        self.position = self.committed;
+        self.lines = None;
+    }
+
+    /// Writes the checkpoint and removes the segments that are fully committed
+    async fn persist(&mut self) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", CHECKPOINT_FILE));
+        tokio::fs::write(&tmp, self.committed.to_string()).await?;
+        tokio::fs::rename(&tmp, self.dir.join(CHECKPOINT_FILE)).await?;
+        self.persisted = self.committed;
+        self.persisted_at = Instant::now();
+
+        let segments = list_segments(&self.dir).await?;
+        for pair in segments.windows(2) {
+            if pair[1] - 1 <= self.committed {
+                tokio::fs::remove_file(segment_path(&self.dir, pair[0])).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The sequence number to flush up to, and where to reply once it's done
+type FlushRequest = (u64, oneshot::Sender<()>);
+
+fn segment_path(dir: &Path, start: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", start, SEGMENT_EXTENSION))
+}
+
+/// Start sequence numbers of the segments in `dir`, oldest first
+async fn list_segments(dir: &Path) -> Result<Vec<u64>> {
+    let mut segments = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        if let Some(start) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse().ok())
+        {
+            segments.push(start);
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// The newest segment starting at or before `seq`
+async fn find_segment(dir: &Path, seq: u64) -> Result<u64> {
+    match list_segments(dir)
+        .await?
+        .into_iter()
+        .filter(|start| *start <= seq)
+        .last()
+    {
+        Some(start) => Ok(start),
+        None => bail!("WAL {:?} has no segment with record {}", dir, seq),
+    }
+}
+
+async fn read_checkpoint(dir: &Path) -> Result<u64> {
+    match tokio::fs::read_to_string(dir.join(CHECKPOINT_FILE)).await {
+        Ok(checkpoint) => checkpoint
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid WAL checkpoint in {:?}", dir)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Cuts off a record that was only partially written when the process died, returns the last
+/// sequence number in the segment.
+async fn recover_segment(path: &Path) -> Result<Option<u64>> {
+    let contents = tokio::fs::read(path).await?;
+    let mut valid_len = 0;
+    let mut last_seq = None;
+    for line in contents.split_inclusive(|b| *b == b'\n') {
+        if !line.ends_with(b"\n") {
+            break;
+        }
+        match serde_json::from_slice::<WalRecord>(line) {
+            Ok(record) => last_seq = Some(record.seq),
+            Err(_) => break,
+        }
+        valid_len += line.len();
+    }
+    if valid_len < contents.len() {
+        warn!(
+            "Truncating {} bytes of partially written records from WAL segment {:?}",
+            contents.len() - valid_len,
+            path
+        );
+        let file = OpenOptions::new().write(true).open(path).await?;
+        file.set_len(valid_len as u64).await?;
+        file.sync_all().await?;
+    }
+    Ok(last_seq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{events::SimpleMessageGroup, scrapers::dgg::test_dgg_message};
+
+    fn item(text: &str) -> WorkerMessage<AllEvents> {
+        WorkerMessage::Item(test_dgg_message("bob", text))
+    }
+
+    fn text_of(event: AllEvents) -> String {
+        SimpleMessageGroup::from(event).0[0].text.clone()
+    }
+
+    async fn read(reader: &mut WalReader, count: usize) -> Vec<(u64, String)> {
+        let mut read = Vec::new();
+        for _ in 0..count {
+            let (seq, event) = match reader.next().await.unwrap().unwrap() {
+                WorkerMessage::Item(item) => item,
+                WorkerMessage::Flush(_) => panic!("Expected an event, got a flush"),
+            };
+            read.push((seq, text_of(event)));
+        }
+        read
+    }
+
+    fn temp_wal(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tl2-wal-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_replays_uncommitted_events() {
+        let dir = temp_wal("replay");
+        // Tiny segments, so every record gets its own
+        let (mut appender, mut reader) = open_wal(dir.clone(), 1).await.unwrap();
+        let events = Vec::from(["one", "two", "three"].map(item));
+        assert!(appender.append_all(events).await.unwrap());
+
+        assert_eq!(
+            read(&mut reader, 2).await,
+            vec![(1, "one".into()), (2, "two".into())]
+        );
+        reader.commit(1).await.unwrap();
+        reader.persist().await.unwrap();
+        assert_eq!(list_segments(&dir).await.unwrap(), vec![2, 3]);
+
+        // A failed batch is read again from the last commit
+        reader.rewind();
+        assert_eq!(
+            read(&mut reader, 2).await,
+            vec![(2, "two".into()), (3, "three".into())]
+        );
+        drop((appender, reader));
+
+        // So is everything after the checkpoint when the process restarts
+        let (mut appender, mut reader) = open_wal(dir.clone(), 1).await.unwrap();
+        assert!(appender.append_all(vec![item("four")]).await.unwrap());
+        assert_eq!(
+            read(&mut reader, 3).await,
+            vec![(2, "two".into()), (3, "three".into()), (4, "four".into())]
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_flush_is_read_after_earlier_events() {
+        let dir = temp_wal("flush");
+        let (mut appender, mut reader) = open_wal(dir.clone(), 1 << 20).await.unwrap();
+        let (done_tx, mut done_rx) = oneshot::channel();
+        let messages = vec![item("one"), WorkerMessage::Flush(done_tx), item("two")];
+        assert!(appender.append_all(messages).await.unwrap());
+
+        assert_eq!(read(&mut reader, 1).await, vec![(1, "one".into())]);
+        match reader.next().await.unwrap().unwrap() {
+            WorkerMessage::Flush(done) => done.send(()).unwrap(),
+            WorkerMessage::Item(_) => panic!("Expected a flush, got an event"),
+        }
+        assert!(done_rx.try_recv().is_ok());
+        assert_eq!(read(&mut reader, 1).await, vec![(2, "two".into())]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_reopening_waits_for_previous_appender() {
+        let dir = temp_wal("handover");
+        let (appender, reader) = open_wal(dir.clone(), 1 << 20).await.unwrap();
+        let (tx, rx) = writer_channel();
+        appender.spawn(rx);
+        for text in ["one", "two", "three"] {
+            assert!(tx.send(item(text)).await.is_ok());
+        }
+        // The writer fails and is restarted while the old appender still has events to append
+        drop((tx, reader));
+
+        let (_appender, mut reader) = open_wal(dir.clone(), 1 << 20).await.unwrap();
+        assert_eq!(
+            read(&mut reader, 3).await,
+            vec![(1, "one".into()), (2, "two".into()), (3, "three".into())]
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_truncates_partial_record() {
+        let dir = temp_wal("partial");
+        let (mut appender, reader) = open_wal(dir.clone(), 1 << 20).await.unwrap();
+        assert!(appender.append_all(vec![item("one")]).await.unwrap());
+        drop((appender, reader));
+
+        let path = segment_path(&dir, 1);
+        let mut contents = std::fs::read(&path).unwrap();
+        contents.extend_from_slice(br#"{"seq":2,"event":{"plat"#);
+        std::fs::write(&path, contents).unwrap();
+
+        let (mut appender, mut reader) = open_wal(dir.clone(), 1 << 20).await.unwrap();
+        assert!(appender.append_all(vec![item("two")]).await.unwrap());
+        assert_eq!(
+            read(&mut reader, 2).await,
+            vec![(1, "one".into()), (2, "two".into())]
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}